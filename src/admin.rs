@@ -0,0 +1,168 @@
+//! Operator-only routes under `/admin`, guarded by the `admin.token` bearer
+//! token: shutdown and API key management.
+
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::auth::api_key::{self, IssuedApiKey, NewApiKey};
+use crate::auth::constant_time_eq;
+use crate::config::AdminConfig;
+use crate::error::{AppError, Challenge};
+use crate::models::ApiKey;
+use crate::openapi;
+use crate::shutdown::Shutdown;
+use crate::storage::UserRepository;
+
+/// Name of the `/admin/api-keys/{id}` resource.
+pub const API_KEY_RESOURCE: &str = "api_key";
+
+/// Response body of `GET /admin/api-keys`.
+#[derive(Serialize, ToSchema)]
+pub struct ApiKeyList {
+    pub items: Vec<ApiKey>,
+}
+
+fn authorize(req: &HttpRequest, admin: &AdminConfig) -> Result<(), AppError> {
+    if admin.token.is_empty() {
+        // Disabled routes look like missing ones.
+        return Err(AppError::NotFound(format!(
+            "No route for {} {}",
+            req.method(),
+            req.path()
+        )));
+    }
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match token {
+        Some(token) if constant_time_eq(token.as_bytes(), admin.token.as_bytes()) => Ok(()),
+        Some(_) => Err(AppError::Unauthorized(
+            Challenge::InvalidToken,
+            "Invalid admin token".to_string(),
+        )),
+        None => Err(AppError::Unauthorized(
+            Challenge::Bearer,
+            "Admin bearer token required".to_string(),
+        )),
+    }
+}
+
+/// `POST /admin/shutdown`: starts a graceful shutdown, as SIGTERM would.
+#[utoipa::path(
+    post,
+    path = "/admin/shutdown",
+    tag = "admin",
+    responses(
+        (status = 202, description = "Shutdown has started", body = Object, example = json!({ "status": "shutting down" })),
+        (status = 401, response = openapi::Unauthorized),
+        (status = 404, response = openapi::NotFound),
+    ),
+    security(("admin_token" = []))
+)]
+pub async fn shutdown(
+    req: HttpRequest,
+    admin: web::Data<AdminConfig>,
+    shutdown: web::Data<Shutdown>,
+) -> Result<HttpResponse, AppError> {
+    authorize(&req, &admin)?;
+    log::warn!("shutdown requested through /admin/shutdown");
+    shutdown.request();
+    Ok(HttpResponse::Accepted().json(serde_json::json!({ "status": "shutting down" })))
+}
+
+/// `POST /admin/api-keys`: issues a key. The response is the only place the
+/// secret ever appears.
+#[utoipa::path(
+    post,
+    path = "/admin/api-keys",
+    tag = "admin",
+    request_body = NewApiKey,
+    responses(
+        (
+            status = 201,
+            description = "The new key, with its secret",
+            body = IssuedApiKey,
+            headers(("Location" = String, description = "Path of the new key"))
+        ),
+        (status = 400, response = openapi::BadRequest),
+        (status = 401, response = openapi::Unauthorized),
+        (status = 404, response = openapi::NotFound),
+        (status = 422, response = openapi::ValidationFailed),
+    ),
+    security(("admin_token" = []))
+)]
+pub async fn create_api_key(
+    req: HttpRequest,
+    admin: web::Data<AdminConfig>,
+    store: web::Data<dyn UserRepository>,
+    body: web::Json<NewApiKey>,
+) -> Result<HttpResponse, AppError> {
+    authorize(&req, &admin)?;
+    let issued = api_key::issue(&**store, body.into_inner())?;
+    log::info!(
+        "issued API key {} ({})",
+        issued.api_key.id,
+        issued.api_key.name
+    );
+    let location = req
+        .url_for(API_KEY_RESOURCE, [&issued.api_key.id])
+        .map(|url| url.path().to_string())
+        .unwrap_or_else(|_| format!("/admin/api-keys/{}", issued.api_key.id));
+    Ok(HttpResponse::Created()
+        .insert_header((header::LOCATION, location))
+        .json(issued))
+}
+
+/// `GET /admin/api-keys`: every key, without secrets.
+#[utoipa::path(
+    get,
+    path = "/admin/api-keys",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Every key", body = ApiKeyList),
+        (status = 401, response = openapi::Unauthorized),
+        (status = 404, response = openapi::NotFound),
+    ),
+    security(("admin_token" = []))
+)]
+pub async fn list_api_keys(
+    req: HttpRequest,
+    admin: web::Data<AdminConfig>,
+    store: web::Data<dyn UserRepository>,
+) -> Result<HttpResponse, AppError> {
+    authorize(&req, &admin)?;
+    let items = store.list_api_keys()?;
+    Ok(HttpResponse::Ok().json(ApiKeyList { items }))
+}
+
+/// `DELETE /admin/api-keys/{id}`: revokes a key immediately.
+#[utoipa::path(
+    delete,
+    path = "/admin/api-keys/{id}",
+    tag = "admin",
+    params(("id" = String, Path, description = "Key id, e.g. `ak_1f2e3d4c5b6a`")),
+    responses(
+        (status = 204, description = "The key was revoked"),
+        (status = 401, response = openapi::Unauthorized),
+        (status = 404, response = openapi::NotFound),
+    ),
+    security(("admin_token" = []))
+)]
+pub async fn revoke_api_key(
+    req: HttpRequest,
+    admin: web::Data<AdminConfig>,
+    store: web::Data<dyn UserRepository>,
+    path: web::Path<(String,)>,
+) -> Result<HttpResponse, AppError> {
+    authorize(&req, &admin)?;
+    let id = path.into_inner().0;
+    if !store.delete_api_key(&id)? {
+        return Err(AppError::NotFound(format!("API key {} not found", id)));
+    }
+    log::info!("revoked API key {}", id);
+    Ok(HttpResponse::NoContent().finish())
+}
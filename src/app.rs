@@ -0,0 +1,260 @@
+//! Assembling the application: the shared [`AppState`], the app shell
+//! [`build_app`] returns, and the routes [`configure`] registers.
+//!
+//! The routes don't depend on where they are mounted, so they can be added at
+//! the root, as `main` does, or inside another server's scope:
+//!
+//! ```ignore
+//! build_app(&state)
+//!     .service(web::scope("/api/v1").configure(configure))
+//!     .route("/", web::get().to(home))
+//! ```
+
+use std::sync::Arc;
+
+use actix_session::config::PersistentSession;
+use actix_session::SessionMiddleware;
+use actix_web::body::MessageBody;
+use actix_web::cookie::time::Duration;
+use actix_web::cookie::{Key, SameSite};
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::{from_fn, ErrorHandlers};
+use actix_web::{web, App, HttpResponse};
+
+use crate::admin::{self, API_KEY_RESOURCE};
+use crate::auth::{self, JwtVerifier, MemorySessionStore};
+use crate::config::{AdminConfig, AuthConfig, Config, Limits};
+use crate::events::{self, Events};
+use crate::graphql::{self, UserSchema};
+use crate::handlers::{self, USER_RESOURCE};
+use crate::metrics::{self, Metrics};
+use crate::rate_limit::{MemoryStore, RateLimiter};
+use crate::shutdown::Shutdown;
+use crate::storage::{PublishingRepository, TracedRepository, UserRepository};
+use crate::{error, health, middleware, openapi};
+
+/// Everything the handlers and middleware share. Built once per server and
+/// cloned into each worker's app; clones share the same state.
+#[derive(Clone)]
+pub struct AppState {
+    pub store: web::Data<dyn UserRepository>,
+    pub shutdown: web::Data<Shutdown>,
+    pub metrics: web::Data<Metrics>,
+    pub rate_limiter: web::Data<RateLimiter>,
+    pub events: web::Data<Events>,
+    graphql: web::Data<UserSchema>,
+    verifier: web::Data<JwtVerifier>,
+    admin: web::Data<AdminConfig>,
+    auth: web::Data<AuthConfig>,
+    limits: Limits,
+    sessions: MemorySessionStore,
+    session_key: Key,
+}
+
+impl AppState {
+    /// State for `config`, storing users in `repository`, whose calls are
+    /// traced and whose changes are published from here on.
+    pub fn new(config: &Config, repository: Arc<dyn UserRepository>) -> Result<Self, String> {
+        let verifier = JwtVerifier::from_config(&config.auth.jwt)?;
+        if !verifier.is_configured() {
+            log::warn!("no auth.jwt keys configured; every /users request will be rejected");
+        }
+        let session_key = match config.auth.session.key_bytes() {
+            Ok(Some(bytes)) => Key::from(&bytes),
+            Ok(None) => {
+                log::warn!("no auth.session.key configured; sessions end when the server restarts");
+                Key::generate()
+            }
+            Err(msg) => return Err(format!("auth.session.key: {}", msg)),
+        };
+        let events = Arc::new(Events::new(&config.events));
+        let repository = PublishingRepository::new(repository, events.clone());
+        let store = web::Data::from(
+            Arc::new(TracedRepository(Arc::new(repository))) as Arc<dyn UserRepository>
+        );
+        let metrics = web::Data::new(Metrics::new());
+        let events = web::Data::from(events);
+        Ok(AppState {
+            graphql: web::Data::new(graphql::schema(
+                store.clone(),
+                metrics.clone(),
+                events.clone(),
+            )),
+            store,
+            shutdown: web::Data::new(Shutdown::default()),
+            metrics,
+            rate_limiter: web::Data::new(RateLimiter::new(
+                &config.rate_limit,
+                Arc::new(MemoryStore::default()),
+            )?),
+            events,
+            verifier: web::Data::new(verifier),
+            admin: web::Data::new(config.admin.clone()),
+            auth: web::Data::new(config.auth.clone()),
+            limits: config.limits.clone(),
+            sessions: MemorySessionStore::default(),
+            session_key,
+        })
+    }
+}
+
+/// An app with the state, extractor settings, error rendering and the
+/// middleware stack of [`crate::middleware`], but no routes yet; add them
+/// with [`configure`].
+pub fn build_app(
+    state: &AppState,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    let session = &state.auth.session;
+    App::new()
+        .app_data(state.store.clone())
+        .app_data(state.shutdown.clone())
+        .app_data(state.admin.clone())
+        .app_data(state.auth.clone())
+        .app_data(state.verifier.clone())
+        .app_data(state.metrics.clone())
+        .app_data(state.rate_limiter.clone())
+        .app_data(state.events.clone())
+        .app_data(state.graphql.clone())
+        .app_data(state.limits.clone())
+        .app_data(
+            web::JsonConfig::default()
+                .limit(state.limits.json)
+                .error_handler(error::json_error),
+        )
+        .app_data(web::PayloadConfig::default().limit(state.limits.payload))
+        .app_data(web::PathConfig::default().error_handler(error::path_error))
+        .app_data(web::QueryConfig::default().error_handler(error::query_error))
+        .wrap(ErrorHandlers::new().default_handler(error::render_problem))
+        // Inside the session middleware, so it can key buckets by user.
+        .wrap(from_fn(middleware::rate_limit))
+        .wrap(
+            SessionMiddleware::builder(state.sessions.clone(), state.session_key.clone())
+                .cookie_name("session".to_string())
+                .cookie_same_site(SameSite::Lax)
+                .cookie_secure(session.secure_cookie)
+                // `Config::validate` keeps the ttl to a year, so it fits.
+                .session_lifecycle(
+                    PersistentSession::default().session_ttl(Duration::seconds(session.ttl as i64)),
+                )
+                .build(),
+        )
+        .wrap(from_fn(middleware::metrics))
+        .wrap(from_fn(middleware::access_log))
+        .wrap(from_fn(middleware::trace))
+        .wrap(from_fn(middleware::request_id))
+        .wrap(from_fn(middleware::shutdown))
+        .default_service(web::to(error::not_found))
+}
+
+/// Registers every route of the API.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/", web::get().to(handlers::index))
+        .route("/healthz", web::get().to(health::healthz))
+        .route("/readyz", web::get().to(health::readyz))
+        .route("/health/details", web::get().to(health::details))
+        .route("/metrics", web::get().to(metrics::export))
+        .service(
+            web::resource("/users")
+                .route(
+                    web::get()
+                        .to(handlers::get_json_data)
+                        .wrap(auth::require(auth::USERS_READ)),
+                )
+                .route(
+                    web::post()
+                        .to(handlers::post_json)
+                        .wrap(auth::require(auth::USERS_WRITE)),
+                ),
+        )
+        // Before `/users/{id}`, which would match it too.
+        .route(
+            "/users/events",
+            web::get()
+                .to(events::sse::users)
+                .wrap(auth::require(auth::USERS_READ)),
+        )
+        .service(
+            web::resource("/users/{id}")
+                .name(USER_RESOURCE)
+                .route(
+                    web::get()
+                        .to(handlers::get_user)
+                        .wrap(auth::require(auth::USERS_READ)),
+                )
+                .route(
+                    web::put()
+                        .to(handlers::put_user)
+                        .wrap(auth::require(auth::USERS_WRITE)),
+                )
+                .route(
+                    web::patch()
+                        .to(handlers::patch_user)
+                        .wrap(auth::require(auth::USERS_WRITE)),
+                )
+                .route(
+                    web::delete()
+                        .to(handlers::delete_user)
+                        .wrap(auth::require(auth::USERS_WRITE)),
+                ),
+        )
+        .route(
+            "/ws/users",
+            web::get()
+                .to(events::ws::users)
+                .wrap(auth::require(auth::USERS_READ)),
+        )
+        .service(
+            web::resource("/graphql")
+                .route(
+                    web::post()
+                        .to(graphql::http::execute)
+                        .wrap(auth::authenticated()),
+                )
+                .route(
+                    web::get()
+                        .to(graphql::ws::subscriptions)
+                        .wrap(auth::authenticated()),
+                ),
+        )
+        .route("/graphiql", web::get().to(graphql::http::graphiql))
+        .route("/auth/signup", web::post().to(auth::account::signup))
+        .route("/auth/login", web::post().to(auth::account::login))
+        .route(
+            "/auth/logout",
+            web::post()
+                .to(auth::account::logout)
+                .wrap(auth::authenticated()),
+        )
+        .route("/auth/me", web::get().to(auth::account::me))
+        .route("/admin/shutdown", web::post().to(admin::shutdown))
+        .service(
+            web::resource("/admin/api-keys")
+                .route(web::post().to(admin::create_api_key))
+                .route(web::get().to(admin::list_api_keys)),
+        )
+        .service(
+            web::resource("/admin/api-keys/{id}")
+                .name(API_KEY_RESOURCE)
+                .route(web::delete().to(admin::revoke_api_key)),
+        )
+        .route("/openapi.json", web::get().to(openapi::spec))
+        .route(
+            "/docs",
+            // Relative, so it also works inside a scope.
+            web::get().to(|| async {
+                HttpResponse::PermanentRedirect()
+                    .insert_header((header::LOCATION, "docs/"))
+                    .finish()
+            }),
+        )
+        .service(openapi::swagger_ui());
+}
@@ -0,0 +1,209 @@
+//! Local accounts: `/auth/signup`, `/auth/login`, `/auth/logout` and
+//! `/auth/me`.
+//!
+//! An account is a user record with an Argon2 password hash. Signing up
+//! creates the user exactly as `POST /users` does and logs straight in.
+
+use actix_session::Session;
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use serde::{Deserialize, Serialize};
+use std::sync::LazyLock;
+use utoipa::ToSchema;
+use validator::Validate;
+
+use crate::error::{AppError, Challenge};
+use crate::handlers::user_path;
+use crate::metrics::Metrics;
+use crate::models::{Info, User, MAX_AGE, MAX_NAME_LEN};
+use crate::openapi;
+use crate::storage::{StorageError, UserRepository};
+
+use super::{session, Claims};
+
+const MIN_PASSWORD_LEN: u64 = 8;
+const MAX_PASSWORD_LEN: u64 = 128;
+
+/// Body of `POST /auth/signup`.
+#[derive(Deserialize, Validate, ToSchema)]
+pub struct Signup {
+    #[validate(length(min = 1, max = MAX_NAME_LEN, message = "must be 1 to 100 characters"))]
+    pub name: String,
+    #[validate(range(max = MAX_AGE, message = "must be between 0 and 150"))]
+    pub age: Option<u8>,
+    #[validate(email(message = "must be a valid email address"))]
+    pub email: String,
+    #[validate(length(
+        min = MIN_PASSWORD_LEN,
+        max = MAX_PASSWORD_LEN,
+        message = "must be 8 to 128 characters"
+    ))]
+    pub password: String,
+}
+
+/// Body of `POST /auth/login`.
+#[derive(Deserialize, ToSchema)]
+pub struct Login {
+    pub email: String,
+    pub password: String,
+}
+
+/// The logged-in account, and the token to send back in `X-CSRF-Token` on
+/// mutating requests.
+#[derive(Serialize, ToSchema)]
+pub struct Me {
+    pub user: User,
+    pub csrf_token: String,
+}
+
+fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Argon2 with default parameters accepts any password")
+        .to_string()
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+/// Checked against when the email is unknown, so that a failed login takes
+/// as long whether or not the account exists.
+static DUMMY_HASH: LazyLock<String> = LazyLock::new(|| hash_password("not a real password"));
+
+fn invalid_login() -> AppError {
+    AppError::Unauthorized(Challenge::None, "Invalid email or password".to_string())
+}
+
+/// Creates an account and logs in.
+#[utoipa::path(
+    post,
+    path = "/auth/signup",
+    tag = "auth",
+    request_body = Signup,
+    responses(
+        (status = 201, description = "The new account; the session cookie is set", body = Me),
+        (status = 400, response = openapi::BadRequest),
+        (status = 409, response = openapi::Conflict),
+        (status = 422, response = openapi::ValidationFailed),
+    )
+)]
+pub async fn signup(
+    store: web::Data<dyn UserRepository>,
+    req: HttpRequest,
+    metrics: web::Data<Metrics>,
+    session: Session,
+    body: web::Json<Signup>,
+) -> Result<HttpResponse, AppError> {
+    body.validate()?;
+    let Signup {
+        name,
+        age,
+        email,
+        password,
+    } = body.into_inner();
+    // Saves hashing for the common case; `create_account` is what makes
+    // sure two signups for one address can't both succeed.
+    if store.find_account(&email)?.is_some() {
+        return Err(StorageError::DuplicateEmail(email).into());
+    }
+
+    // Hashing is deliberately slow; keep it off the worker thread.
+    let hash = web::block(move || hash_password(&password))
+        .await
+        .map_err(|_| AppError::Unavailable("Password hashing was interrupted".to_string()))?;
+    let info = Info {
+        name,
+        age,
+        email: Some(email),
+        role: None,
+    };
+    let user = store.create_account(info, hash)?;
+    metrics.users_created.inc();
+
+    let csrf_token = session::log_in(&session, user.id);
+    Ok(HttpResponse::Created()
+        .insert_header((header::LOCATION, user_path(&req, user.id)))
+        .json(Me { user, csrf_token }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth/login",
+    tag = "auth",
+    request_body = Login,
+    responses(
+        (status = 200, description = "The account; the session cookie is set", body = Me),
+        (status = 400, response = openapi::BadRequest),
+        (status = 401, response = openapi::Unauthorized),
+    )
+)]
+pub async fn login(
+    store: web::Data<dyn UserRepository>,
+    session: Session,
+    body: web::Json<Login>,
+) -> Result<HttpResponse, AppError> {
+    let Login { email, password } = body.into_inner();
+    let account = store.find_account(&email)?;
+    let (user, hash) = match account {
+        Some((user, hash)) => (Some(user), hash),
+        None => (None, DUMMY_HASH.clone()),
+    };
+    let valid = web::block(move || verify_password(&password, &hash))
+        .await
+        .map_err(|_| AppError::Unavailable("Password check was interrupted".to_string()))?;
+    let user = user.filter(|_| valid).ok_or_else(invalid_login)?;
+
+    let csrf_token = session::log_in(&session, user.id);
+    Ok(HttpResponse::Ok().json(Me { user, csrf_token }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth/logout",
+    tag = "auth",
+    responses(
+        (status = 204, description = "The session was ended"),
+        (status = 401, response = openapi::Unauthorized),
+    ),
+    security(("session" = []))
+)]
+pub async fn logout(session: Session) -> HttpResponse {
+    session.purge();
+    HttpResponse::NoContent().finish()
+}
+
+#[utoipa::path(
+    get,
+    path = "/auth/me",
+    tag = "auth",
+    responses(
+        (status = 200, description = "The logged-in account", body = Me),
+        (status = 401, response = openapi::Unauthorized),
+    ),
+    security(("session" = []))
+)]
+pub async fn me(
+    store: web::Data<dyn UserRepository>,
+    claims: Claims,
+    session: Session,
+) -> Result<HttpResponse, AppError> {
+    let (Some(user_id), Some(csrf_token)) = (claims.user_id, session::csrf_token(&session)) else {
+        return Err(AppError::Unauthorized(
+            Challenge::None,
+            "This route requires a login session".to_string(),
+        ));
+    };
+    let user = store.get(user_id)?.ok_or_else(|| {
+        AppError::Unauthorized(Challenge::None, "The account no longer exists".to_string())
+    })?;
+    Ok(HttpResponse::Ok().json(Me { user, csrf_token }))
+}
@@ -0,0 +1,133 @@
+//! API keys for service callers that can't log in interactively.
+//!
+//! A key looks like `ak_1f2e3d4c5b6a.<secret>`. The part before the dot is
+//! the key's id, which is safe to show and log; only a SHA-256 hash of the
+//! whole key is stored. The secret is 32 random bytes, so a slow password
+//! hash would add latency to every request without making guessing harder.
+
+use actix_web::http::header::HeaderName;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+use validator::Validate;
+
+use crate::error::{AppError, Challenge};
+use crate::models::{ApiKey, Role, MAX_NAME_LEN};
+use crate::storage::UserRepository;
+
+use super::{constant_time_eq, AuthMethod, Claims};
+
+pub const API_KEY_HEADER: HeaderName = HeaderName::from_static("x-api-key");
+
+const ID_PREFIX: &str = "ak_";
+
+/// How stale `last_used_at` may get before a request refreshes it, so that
+/// busy keys don't cause a write on every request.
+const TOUCH_INTERVAL: TimeDelta = TimeDelta::seconds(60);
+
+/// Body of `POST /admin/api-keys`.
+#[derive(Deserialize, Validate, ToSchema)]
+pub struct NewApiKey {
+    #[validate(length(min = 1, max = MAX_NAME_LEN, message = "must be 1 to 100 characters"))]
+    pub name: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// Defaults to [`Role::Viewer`].
+    #[serde(default)]
+    pub role: Option<Role>,
+    /// Never expires if absent.
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// A freshly issued key. This is the only time the secret is shown.
+#[derive(Serialize, ToSchema)]
+pub struct IssuedApiKey {
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+    pub key: String,
+}
+
+fn hash(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+/// Creates and stores a key, returning it with its secret.
+pub fn issue(store: &dyn UserRepository, new: NewApiKey) -> Result<IssuedApiKey, AppError> {
+    new.validate()?;
+    let now = Utc::now();
+    if new.expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return Err(AppError::InvalidBody(
+            "expires_at must be in the future".to_string(),
+        ));
+    }
+    if let Some(scope) = new
+        .scopes
+        .iter()
+        .find(|scope| scope.is_empty() || scope.contains(char::is_whitespace))
+    {
+        return Err(AppError::InvalidBody(format!("Invalid scope {:?}", scope)));
+    }
+
+    let hex: String = random_bytes::<6>()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    let id = format!("{}{}", ID_PREFIX, hex);
+    let key = format!("{}.{}", id, URL_SAFE_NO_PAD.encode(random_bytes::<32>()));
+    let api_key = ApiKey {
+        id,
+        name: new.name,
+        scopes: new.scopes,
+        role: new.role.unwrap_or_default(),
+        created_at: now,
+        expires_at: new.expires_at,
+        last_used_at: None,
+    };
+    store.create_api_key(api_key.clone(), hash(&key))?;
+    Ok(IssuedApiKey { api_key, key })
+}
+
+/// Checks a presented key and records its use.
+pub(super) fn verify(store: &dyn UserRepository, key: &str) -> Result<Claims, AppError> {
+    let invalid = || AppError::Unauthorized(Challenge::ApiKey, "Invalid API key".to_string());
+    let (id, _) = key.split_once('.').ok_or_else(invalid)?;
+    let (api_key, stored_hash) = store.get_api_key(id)?.ok_or_else(invalid)?;
+    if !constant_time_eq(hash(key).as_bytes(), stored_hash.as_bytes()) {
+        return Err(invalid());
+    }
+    let now = Utc::now();
+    if api_key.is_expired(now) {
+        return Err(AppError::Unauthorized(
+            Challenge::ApiKey,
+            format!("API key {} has expired", api_key.id),
+        ));
+    }
+
+    let stale = api_key
+        .last_used_at
+        .is_none_or(|last_used_at| now - last_used_at >= TOUCH_INTERVAL);
+    if stale {
+        // Failing to record the use shouldn't fail the request.
+        if let Err(err) = store.touch_api_key(&api_key.id, now) {
+            log::warn!("cannot record use of API key {}: {}", api_key.id, err);
+        }
+    }
+    Ok(Claims {
+        sub: api_key.id,
+        scopes: api_key.scopes.into_iter().collect(),
+        method: AuthMethod::ApiKey,
+        user_id: None,
+        role: api_key.role,
+    })
+}
@@ -0,0 +1,172 @@
+//! Bearer token verification.
+
+use std::collections::BTreeSet;
+use std::fs;
+
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+
+use crate::config::JwtConfig;
+use crate::models::Role;
+
+use super::{AuthMethod, Claims};
+
+/// The claims we read from a token. Scopes may come as an OAuth-style
+/// space-separated `scope` string or as a `scp` array. Without a `role`
+/// claim the caller is a viewer.
+#[derive(Serialize, Deserialize)]
+struct TokenClaims {
+    sub: String,
+    exp: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+    #[serde(default, skip_serializing)]
+    scp: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    role: Option<Role>,
+}
+
+/// Checks `Authorization: Bearer` tokens against the configured keys.
+pub struct JwtVerifier {
+    hs256: Option<DecodingKey>,
+    rs256: Option<DecodingKey>,
+    /// RSA keys from the JWKS file, with their `kid`.
+    jwks: Vec<(Option<String>, DecodingKey)>,
+    validation: Validation,
+}
+
+impl JwtVerifier {
+    /// Loads the keys named in `config`, reading any key files.
+    pub fn from_config(config: &JwtConfig) -> Result<Self, String> {
+        let hs256 =
+            (!config.secret.is_empty()).then(|| DecodingKey::from_secret(config.secret.as_bytes()));
+        let rs256 = if config.public_key.is_empty() {
+            None
+        } else {
+            let pem = fs::read(&config.public_key)
+                .map_err(|err| format!("cannot read {}: {}", config.public_key, err))?;
+            Some(
+                DecodingKey::from_rsa_pem(&pem)
+                    .map_err(|err| format!("{}: {}", config.public_key, err))?,
+            )
+        };
+        let mut jwks = Vec::new();
+        if !config.jwks_file.is_empty() {
+            let text = fs::read_to_string(&config.jwks_file)
+                .map_err(|err| format!("cannot read {}: {}", config.jwks_file, err))?;
+            let set: JwkSet = serde_json::from_str(&text)
+                .map_err(|err| format!("{}: {}", config.jwks_file, err))?;
+            for jwk in set.keys.iter().filter(|jwk| jwk.is_supported()) {
+                let key = DecodingKey::from_jwk(jwk)
+                    .map_err(|err| format!("{}: {}", config.jwks_file, err))?;
+                jwks.push((jwk.common.key_id.clone(), key));
+            }
+        }
+
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.validate_nbf = true;
+        validation.leeway = config.leeway;
+        if !config.issuer.is_empty() {
+            validation.set_issuer(&[&config.issuer]);
+            validation.required_spec_claims.insert("iss".to_string());
+        }
+        if config.audience.is_empty() {
+            validation.validate_aud = false;
+        } else {
+            validation.set_audience(&[&config.audience]);
+            validation.required_spec_claims.insert("aud".to_string());
+        }
+
+        Ok(JwtVerifier {
+            hs256,
+            rs256,
+            jwks,
+            validation,
+        })
+    }
+
+    pub fn is_configured(&self) -> bool {
+        self.hs256.is_some() || self.rs256.is_some() || !self.jwks.is_empty()
+    }
+
+    /// Picks the key for the token's algorithm and `kid`.
+    fn key_for(&self, header: &Header) -> Result<&DecodingKey, String> {
+        match header.alg {
+            Algorithm::HS256 => self
+                .hs256
+                .as_ref()
+                .ok_or_else(|| "HS256 tokens are not accepted".to_string()),
+            Algorithm::RS256 => {
+                let from_jwks = match &header.kid {
+                    Some(kid) => self
+                        .jwks
+                        .iter()
+                        .find(|(id, _)| id.as_deref() == Some(kid.as_str())),
+                    None if self.jwks.len() == 1 => self.jwks.first(),
+                    None => None,
+                };
+                from_jwks
+                    .map(|(_, key)| key)
+                    .or(self.rs256.as_ref())
+                    .ok_or_else(|| "no key matches the token".to_string())
+            }
+            alg => Err(format!("{:?} tokens are not accepted", alg)),
+        }
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims, String> {
+        let header = jsonwebtoken::decode_header(token).map_err(|err| err.to_string())?;
+        let key = self.key_for(&header)?;
+        let mut validation = self.validation.clone();
+        validation.algorithms = vec![header.alg];
+        let claims = jsonwebtoken::decode::<TokenClaims>(token, key, &validation)
+            .map_err(|err| err.to_string())?
+            .claims;
+
+        let mut scopes: BTreeSet<String> = claims.scp.into_iter().collect();
+        if let Some(scope) = claims.scope {
+            scopes.extend(scope.split_whitespace().map(str::to_string));
+        }
+        Ok(Claims {
+            user_id: claims.sub.parse().ok(),
+            sub: claims.sub,
+            scopes,
+            method: AuthMethod::Bearer,
+            role: claims.role.unwrap_or_default(),
+        })
+    }
+}
+
+/// Mints an HS256 token, for development and tests.
+pub fn issue(
+    config: &JwtConfig,
+    sub: &str,
+    scopes: &[String],
+    role: Role,
+    ttl: u64,
+) -> Result<String, String> {
+    if config.secret.is_empty() {
+        return Err("auth.jwt.secret is not set".to_string());
+    }
+    let claims = TokenClaims {
+        sub: sub.to_string(),
+        exp: chrono::Utc::now().timestamp() + ttl as i64,
+        scope: (!scopes.is_empty()).then(|| scopes.join(" ")),
+        scp: Vec::new(),
+        role: Some(role),
+    };
+    let mut token = serde_json::to_value(&claims).unwrap();
+    if !config.issuer.is_empty() {
+        token["iss"] = config.issuer.clone().into();
+    }
+    if !config.audience.is_empty() {
+        token["aud"] = config.audience.clone().into();
+    }
+    jsonwebtoken::encode(
+        &Header::new(Algorithm::HS256),
+        &token,
+        &EncodingKey::from_secret(config.secret.as_bytes()),
+    )
+    .map_err(|err| err.to_string())
+}
@@ -0,0 +1,300 @@
+//! Authentication and scope checks.
+//!
+//! Callers authenticate with a bearer token, an API key (`X-Api-Key` or
+//! `Authorization: ApiKey`) or, for accounts created through `/auth/signup`,
+//! a session cookie. Credentials are only looked at by routes
+//! that ask for them: a route wrapped in [`require`] rejects the request
+//! unless its credentials are valid and grant the given scope, and handlers
+//! read the caller's identity through the [`Claims`] extractor. Routes
+//! without a requirement, such as the health probes, ignore credentials
+//! entirely.
+//!
+//! Cookies are sent by browsers on their own, so mutating requests
+//! authenticated by a session must also echo the session's CSRF token in
+//! `X-CSRF-Token`.
+
+pub mod account;
+pub mod api_key;
+mod jwt;
+pub mod policy;
+pub mod session;
+
+use std::collections::BTreeSet;
+use std::future::{ready, Ready};
+
+use actix_session::SessionExt;
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, HeaderName};
+use actix_web::middleware::{from_fn, Next};
+use actix_web::{web, Error, FromRequest, HttpMessage, HttpRequest};
+
+use crate::config::AuthConfig;
+use crate::error::{AppError, Challenge};
+use crate::models::Role;
+use crate::storage::UserRepository;
+
+pub use jwt::{issue, JwtVerifier};
+pub use session::MemorySessionStore;
+
+pub const USERS_READ: &str = "users:read";
+pub const USERS_WRITE: &str = "users:write";
+
+pub const CSRF_HEADER: HeaderName = HeaderName::from_static("x-csrf-token");
+
+/// Scopes of a logged-in account.
+const SESSION_SCOPES: [&str; 2] = [USERS_READ, USERS_WRITE];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthMethod {
+    Bearer,
+    ApiKey,
+    Session,
+}
+
+/// The authenticated caller.
+#[derive(Clone, Debug)]
+pub struct Claims {
+    /// Subject: who the credentials were issued to. For sessions, the user
+    /// id; for API keys, the key id.
+    pub sub: String,
+    pub scopes: BTreeSet<String>,
+    pub method: AuthMethod,
+    /// The caller's user record: a session's account, or a bearer token's
+    /// numeric `sub`.
+    pub user_id: Option<u32>,
+    pub role: Role,
+}
+
+impl Claims {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.contains(scope)
+    }
+
+    pub fn subject(&self) -> policy::Subject {
+        policy::Subject {
+            user_id: self.user_id,
+            role: self.role,
+        }
+    }
+}
+
+/// Compares in time independent of where the inputs first differ.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Verifies the request's credentials, caching the outcome in the request
+/// extensions. An `Authorization` header wins over `X-Api-Key`, which wins
+/// over a session cookie. `Ok(None)` means the request carries no
+/// credentials.
+fn authenticate(req: &HttpRequest) -> Result<Option<Claims>, AppError> {
+    if let Some(claims) = req.extensions().get::<Claims>() {
+        return Ok(Some(claims.clone()));
+    }
+    let headers = req.headers();
+    let claims = if let Some(value) = headers.get(header::AUTHORIZATION) {
+        from_authorization(req, value)?
+    } else if let Some(value) = headers.get(api_key::API_KEY_HEADER) {
+        let key = value.to_str().map_err(|_| {
+            AppError::Unauthorized(Challenge::ApiKey, "Invalid API key".to_string())
+        })?;
+        api_key::verify(store(req), key.trim())?
+    } else {
+        match from_session(req)? {
+            Some(claims) => claims,
+            None => return Ok(None),
+        }
+    };
+    req.extensions_mut().insert(claims.clone());
+    Ok(Some(claims))
+}
+
+/// The caller, if the request carries valid credentials. For code that
+/// runs before a route's own checks and treats bad credentials as none.
+pub(crate) fn caller(req: &HttpRequest) -> Option<Claims> {
+    authenticate(req).ok().flatten()
+}
+
+fn store(req: &HttpRequest) -> &dyn UserRepository {
+    req.app_data::<web::Data<dyn UserRepository>>()
+        .expect("UserRepository is registered as app data")
+        .get_ref()
+}
+
+fn from_authorization(req: &HttpRequest, value: &header::HeaderValue) -> Result<Claims, AppError> {
+    let value = value.to_str().unwrap_or_default();
+    if let Some(key) = value.strip_prefix("ApiKey ") {
+        return api_key::verify(store(req), key.trim());
+    }
+    let token = value
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+        .ok_or_else(|| {
+            AppError::Unauthorized(
+                Challenge::Any,
+                "Expected a bearer token or an API key".to_string(),
+            )
+        })?;
+
+    let verifier = req
+        .app_data::<web::Data<JwtVerifier>>()
+        .expect("JwtVerifier is registered as app data");
+    verifier.verify(token).map_err(|err| {
+        AppError::Unauthorized(
+            Challenge::InvalidToken,
+            format!("Invalid bearer token: {}", err),
+        )
+    })
+}
+
+/// Claims for a session cookie, if it belongs to an account that still
+/// exists. Sessions of deleted accounts are ended.
+fn from_session(req: &HttpRequest) -> Result<Option<Claims>, AppError> {
+    let session = req.get_session();
+    let Some(user_id) = session::user_id(&session) else {
+        return Ok(None);
+    };
+    let Some(user) = store(req).get(user_id)? else {
+        session.purge();
+        return Ok(None);
+    };
+    Ok(Some(Claims {
+        sub: user_id.to_string(),
+        scopes: SESSION_SCOPES
+            .iter()
+            .map(|scope| scope.to_string())
+            .collect(),
+        method: AuthMethod::Session,
+        user_id: Some(user_id),
+        role: user.role,
+    }))
+}
+
+fn missing_credentials() -> AppError {
+    AppError::Unauthorized(
+        Challenge::Any,
+        "This route requires a bearer token, an API key or a login session".to_string(),
+    )
+}
+
+/// Rejects session-authenticated WebSocket upgrades from pages of other
+/// origins. An upgrade is a GET, so [`check_csrf`] lets it through, and
+/// browsers send the cookie along from any same-site page; they always send
+/// `Origin` on upgrades, though. It must name the server's own host or one
+/// of `auth.session.allowed_origins`.
+pub fn check_origin(req: &HttpRequest, claims: &Claims) -> Result<(), AppError> {
+    if claims.method != AuthMethod::Session {
+        return Ok(());
+    }
+    let origin = req
+        .headers()
+        .get(header::ORIGIN)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let own_host = origin
+        .split_once("://")
+        .is_some_and(|(_, host)| host == req.connection_info().host().to_ascii_lowercase());
+    let config = req
+        .app_data::<web::Data<AuthConfig>>()
+        .expect("AuthConfig is registered as app data");
+    let allowed = (config.session.allowed_origins.iter())
+        .any(|allowed| allowed.eq_ignore_ascii_case(&origin));
+    if origin.is_empty() || !(own_host || allowed) {
+        return Err(AppError::Forbidden(format!(
+            "WebSockets authenticated by a session must be opened from an allowed origin, not {:?}",
+            origin
+        )));
+    }
+    Ok(())
+}
+
+/// Rejects session-authenticated requests that could have been forged by
+/// another site: anything but GET, HEAD and OPTIONS must carry the CSRF token.
+fn check_csrf(req: &HttpRequest, claims: &Claims) -> Result<(), AppError> {
+    if claims.method != AuthMethod::Session || req.method().is_safe() {
+        return Ok(());
+    }
+    let expected = session::csrf_token(&req.get_session()).unwrap_or_default();
+    let sent = req
+        .headers()
+        .get(CSRF_HEADER)
+        .map(|value| value.as_bytes())
+        .unwrap_or_default();
+    if expected.is_empty() || !constant_time_eq(sent, expected.as_bytes()) {
+        return Err(AppError::Forbidden(
+            "Missing or invalid X-CSRF-Token header".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+async fn enforce<B: MessageBody>(
+    scope: Option<&'static str>,
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    // Rejections are rendered here rather than returned as errors, so they
+    // pass back through the app middleware (request id, logs, metrics) like
+    // any other response.
+    if let Err(err) = check(scope, req.request()) {
+        return Ok(req.error_response(err).map_into_right_body());
+    }
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+fn check(scope: Option<&'static str>, req: &HttpRequest) -> Result<(), AppError> {
+    let claims = authenticate(req)?.ok_or_else(missing_credentials)?;
+    check_csrf(req, &claims)?;
+    if let Some(scope) = scope {
+        if !claims.has_scope(scope) {
+            return Err(AppError::Forbidden(format!("Missing scope {:?}", scope)));
+        }
+    }
+    Ok(())
+}
+
+/// Route middleware rejecting callers whose credentials don't grant `scope`:
+/// `401` without valid credentials, `403` without the scope.
+pub fn require<S, B>(
+    scope: &'static str,
+) -> impl Transform<
+    S,
+    ServiceRequest,
+    Response = ServiceResponse<EitherBody<B>>,
+    Error = Error,
+    InitError = (),
+>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    from_fn(move |req, next: Next<B>| enforce(Some(scope), req, next))
+}
+
+/// Route middleware rejecting anonymous callers, whatever their scopes.
+pub fn authenticated<S, B>() -> impl Transform<
+    S,
+    ServiceRequest,
+    Response = ServiceResponse<EitherBody<B>>,
+    Error = Error,
+    InitError = (),
+>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    from_fn(|req, next: Next<B>| enforce(None, req, next))
+}
+
+impl FromRequest for Claims {
+    type Error = AppError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(authenticate(req).and_then(|claims| claims.ok_or_else(missing_credentials)))
+    }
+}
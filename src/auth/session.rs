@@ -0,0 +1,114 @@
+//! Cookie sessions for logged-in accounts.
+//!
+//! The cookie only carries a random session key, encrypted and signed with
+//! the configured key; the session itself (user id and CSRF token) lives in
+//! a server-side [`SessionStore`].
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+use actix_session::storage::{
+    generate_session_key, LoadError, SaveError, SessionKey, SessionStore, UpdateError,
+};
+use actix_session::Session;
+use actix_web::cookie::time::Duration;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+
+const USER_ID: &str = "user_id";
+const CSRF_TOKEN: &str = "csrf_token";
+
+type State = HashMap<String, String>;
+
+/// Sessions kept in process memory; they are lost on restart and not shared
+/// between instances. Clones share the same sessions.
+#[derive(Clone, Default)]
+pub struct MemorySessionStore {
+    sessions: Arc<RwLock<HashMap<String, (State, Instant)>>>,
+}
+
+impl MemorySessionStore {
+    fn expiry(ttl: &Duration) -> Instant {
+        Instant::now() + std::time::Duration::try_from(*ttl).unwrap_or_default()
+    }
+
+    /// Drops expired sessions so abandoned ones don't pile up.
+    fn evict_expired(&self) {
+        let now = Instant::now();
+        self.sessions
+            .write()
+            .unwrap()
+            .retain(|_, (_, expires)| *expires > now);
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    async fn load(&self, key: &SessionKey) -> Result<Option<State>, LoadError> {
+        let sessions = self.sessions.read().unwrap();
+        Ok(sessions
+            .get(key.as_ref())
+            .filter(|(_, expires)| *expires > Instant::now())
+            .map(|(state, _)| state.clone()))
+    }
+
+    async fn save(&self, state: State, ttl: &Duration) -> Result<SessionKey, SaveError> {
+        self.evict_expired();
+        let key = generate_session_key();
+        self.sessions
+            .write()
+            .unwrap()
+            .insert(key.as_ref().to_string(), (state, Self::expiry(ttl)));
+        Ok(key)
+    }
+
+    async fn update(
+        &self,
+        key: SessionKey,
+        state: State,
+        ttl: &Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        if let Some(entry) = self.sessions.write().unwrap().get_mut(key.as_ref()) {
+            *entry = (state, Self::expiry(ttl));
+            return Ok(key);
+        }
+        // Expired and evicted in the meantime: start over under a new key.
+        self.save(state, ttl)
+            .await
+            .map_err(|err| UpdateError::Other(anyhow::anyhow!(err)))
+    }
+
+    async fn update_ttl(&self, key: &SessionKey, ttl: &Duration) -> Result<(), anyhow::Error> {
+        if let Some((_, expires)) = self.sessions.write().unwrap().get_mut(key.as_ref()) {
+            *expires = Self::expiry(ttl);
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, key: &SessionKey) -> Result<(), anyhow::Error> {
+        self.sessions.write().unwrap().remove(key.as_ref());
+        Ok(())
+    }
+}
+
+/// Starts a fresh session for `user_id` and returns its CSRF token. The
+/// session key is rotated so a key planted before login is useless after.
+pub fn log_in(session: &Session, user_id: u32) -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let csrf_token = URL_SAFE_NO_PAD.encode(bytes);
+
+    session.renew();
+    session.insert(USER_ID, user_id).unwrap();
+    session.insert(CSRF_TOKEN, &csrf_token).unwrap();
+    csrf_token
+}
+
+pub fn user_id(session: &Session) -> Option<u32> {
+    session.get(USER_ID).ok().flatten()
+}
+
+pub fn csrf_token(session: &Session) -> Option<String> {
+    session.get(CSRF_TOKEN).ok().flatten()
+}
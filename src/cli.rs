@@ -0,0 +1,127 @@
+//! Subcommands other than `serve`, for running from scripts.
+//!
+//! Each returns an error message on failure; `main` prints it and exits
+//! non-zero.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use fake::faker::internet::en::SafeEmail;
+use fake::faker::name::en::Name;
+use fake::Fake;
+use validator::Validate;
+
+use actix_web_app::auth;
+use actix_web_app::config::Config;
+use actix_web_app::models::{Info, Role, User, UserUpdate};
+use actix_web_app::storage;
+
+pub fn migrate(config: &Config) -> Result<(), String> {
+    let repository = storage::connect(&config.storage).map_err(|err| err.to_string())?;
+    let applied = repository.migrate().map_err(|err| err.to_string())?;
+    println!("applied {} migration(s) to {}", applied, config.storage);
+    Ok(())
+}
+
+/// Creates `count` random users through the same repository calls
+/// `POST /users` makes.
+pub fn seed(config: &Config, count: usize) -> Result<(), String> {
+    let repository = storage::open(&config.storage).map_err(|err| err.to_string())?;
+    for _ in 0..count {
+        let info = Info {
+            name: Name().fake(),
+            age: Some((18..90).fake()),
+            email: Some(SafeEmail().fake()),
+            role: None,
+        };
+        info.validate().map_err(|err| err.to_string())?;
+        repository.create(info).map_err(|err| err.to_string())?;
+    }
+    println!("created {} user(s) in {}", count, config.storage);
+    Ok(())
+}
+
+/// Writes every user as a JSON array to `output`, or stdout if `None`.
+pub fn export(config: &Config, output: Option<&Path>) -> Result<(), String> {
+    let repository = storage::open(&config.storage).map_err(|err| err.to_string())?;
+    let users = repository.list().map_err(|err| err.to_string())?;
+
+    let writer: Box<dyn Write> = match output {
+        Some(path) => {
+            Box::new(File::create(path).map_err(|err| format!("{}: {}", path.display(), err))?)
+        }
+        None => Box::new(io::stdout().lock()),
+    };
+    let mut writer = BufWriter::new(writer);
+    serde_json::to_writer_pretty(&mut writer, &users).map_err(|err| err.to_string())?;
+    writeln!(writer)
+        .and_then(|_| writer.flush())
+        .map_err(|err| err.to_string())?;
+
+    if let Some(path) = output {
+        eprintln!("exported {} user(s) to {}", users.len(), path.display());
+    }
+    Ok(())
+}
+
+/// Loads a file written by [`export`], keeping ids and timestamps. Every
+/// record is validated before anything is written.
+pub fn import(config: &Config, input: &Path) -> Result<(), String> {
+    let file = File::open(input).map_err(|err| format!("{}: {}", input.display(), err))?;
+    let users: Vec<User> = serde_json::from_reader(BufReader::new(file))
+        .map_err(|err| format!("{}: {}", input.display(), err))?;
+    for user in &users {
+        user.validate()
+            .map_err(|err| format!("user {}: {}", user.id, err))?;
+    }
+
+    let repository = storage::open(&config.storage).map_err(|err| err.to_string())?;
+    let count = users.len();
+    for user in users {
+        repository.import(user).map_err(|err| err.to_string())?;
+    }
+    println!("imported {} user(s) into {}", count, config.storage);
+    Ok(())
+}
+
+/// Gives user `id` a role, e.g. to make the first admin.
+pub fn set_role(config: &Config, id: u32, role: Role) -> Result<(), String> {
+    let repository = storage::open(&config.storage).map_err(|err| err.to_string())?;
+    let user = repository
+        .get(id)
+        .map_err(|err| err.to_string())?
+        .ok_or_else(|| format!("user {} not found", id))?;
+    let update = UserUpdate {
+        id: None,
+        name: user.name,
+        age: user.age,
+        email: user.email,
+        role: Some(role),
+    };
+    repository
+        .replace(id, update)
+        .map_err(|err| err.to_string())?;
+    println!("user {} is now {}", id, role);
+    Ok(())
+}
+
+/// Prints the resolved configuration as TOML, secrets masked.
+pub fn check_config(config: &Config) -> Result<(), String> {
+    let text = toml::to_string_pretty(&config.redacted()).map_err(|err| err.to_string())?;
+    print!("{}", text);
+    Ok(())
+}
+
+/// Prints an HS256 bearer token signed with `auth.jwt.secret`.
+pub fn token(
+    config: &Config,
+    sub: &str,
+    scopes: &[String],
+    role: Role,
+    ttl: u64,
+) -> Result<(), String> {
+    let token = auth::issue(&config.auth.jwt, sub, scopes, role, ttl)?;
+    println!("{}", token);
+    Ok(())
+}
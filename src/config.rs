@@ -0,0 +1,631 @@
+//! Layered configuration.
+//!
+//! Settings are resolved in this order, later layers winning:
+//!
+//! 1. built-in defaults ([`Config::default`]),
+//! 2. an optional TOML file given by `--config` or `APP_CONFIG`,
+//! 3. `APP_`-prefixed environment variables, with `__` separating nested
+//!    keys (`APP_PORT`, `APP_LIMITS__JSON`); variables that don't name a
+//!    setting, such as `APP_ENV`, are ignored,
+//! 4. command-line flags.
+//!
+//! The result is validated once at startup so a bad deployment fails
+//! immediately with a readable message instead of on the first request.
+
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use toml::{Table, Value};
+
+use crate::logging::LogFormat;
+use crate::rate_limit;
+use crate::storage::Backend;
+use crate::telemetry::TraceExporter;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    /// Number of worker threads; `0` starts one per physical CPU core.
+    pub workers: usize,
+    /// `env_logger` filter directives, e.g. `info` or `warn,actix_web=debug`.
+    pub log_level: String,
+    /// `json` (one object per line) or `text`.
+    pub log_format: LogFormat,
+    /// `memory`, `sqlite:<path>` or `json:<path>`.
+    pub storage: String,
+    pub limits: Limits,
+    pub shutdown: ShutdownConfig,
+    pub admin: AdminConfig,
+    pub tracing: TracingConfig,
+    pub auth: AuthConfig,
+    pub rate_limit: RateLimitConfig,
+    pub events: EventsConfig,
+}
+
+/// Request body size limits, in bytes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Largest body the `/users` routes will read, in any format.
+    pub json: usize,
+    /// Largest raw body any other extractor will read.
+    pub payload: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// Seconds in-flight requests get to finish after SIGTERM before they are
+    /// aborted.
+    pub drain_timeout: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Bearer token for the `/admin` routes. Empty disables them.
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TracingConfig {
+    /// Where finished spans go: `none`, `otlp` or `file`.
+    pub exporter: TraceExporter,
+    /// OTLP/HTTP traces endpoint of the collector.
+    pub endpoint: String,
+    /// JSON-lines file the `file` exporter appends to.
+    pub file: String,
+    /// `service.name` reported with every span.
+    pub service_name: String,
+}
+
+/// Most events `events.buffer` or `events.history` may name. The buffer is
+/// allocated at startup, and either can hold that many users in memory.
+pub const MAX_EVENTS: usize = 1_000_000;
+
+/// Streams of user change events, such as `/ws/users`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventsConfig {
+    /// Events a client may fall behind by before it loses its place, at
+    /// most [`MAX_EVENTS`].
+    pub buffer: usize,
+    /// Recent events kept so that `/users/events` clients can resume, at
+    /// most [`MAX_EVENTS`].
+    pub history: usize,
+    /// Seconds between the pings or keep-alive comments sent to each client.
+    pub heartbeat: u64,
+    /// Seconds without a frame from a client, or without being able to send
+    /// it one, after which it is disconnected.
+    pub client_timeout: u64,
+}
+
+/// Token-bucket rate limits. Requests that match no rule are not limited.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Proxies whose `X-Forwarded-For` header is believed, as IP addresses
+    /// or CIDR ranges.
+    pub trusted_proxies: Vec<String>,
+    /// Checked in order; the first matching rule applies.
+    pub rules: Vec<RateLimitRule>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitRule {
+    /// Route pattern as registered, e.g. `/users/{id}`, or `*` for every
+    /// request.
+    pub route: String,
+    /// HTTP method; empty matches any.
+    #[serde(default)]
+    pub method: String,
+    /// Requests a client may make in a burst.
+    pub capacity: u32,
+    /// Seconds for an empty bucket to refill completely.
+    pub period: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Whether `GET /` may be called without credentials.
+    pub anonymous_root: bool,
+    pub jwt: JwtConfig,
+    pub session: SessionConfig,
+}
+
+/// Longest `auth.session.ttl` accepted: a year, well within what cookie
+/// expiry dates can represent.
+pub const MAX_SESSION_TTL: u64 = 365 * 24 * 60 * 60;
+
+/// Cookie sessions for local accounts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// Base64 key of at least 64 bytes that signs and encrypts the cookie.
+    /// If empty, a random key is generated at startup, so sessions don't
+    /// survive a restart.
+    pub key: String,
+    /// Seconds a session stays valid, at most [`MAX_SESSION_TTL`].
+    pub ttl: u64,
+    /// Only send the cookie over HTTPS. Enable whenever the server is
+    /// reached through TLS.
+    pub secure_cookie: bool,
+    /// Origins, e.g. `https://app.example.com`, whose pages may open
+    /// WebSockets with the session cookie, besides the server's own host.
+    pub allowed_origins: Vec<String>,
+}
+
+/// Keys and claim checks for `Authorization: Bearer` tokens. Any mix of key
+/// sources may be configured; a token is checked against the one matching
+/// its `alg` (and `kid`, for JWKS keys).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JwtConfig {
+    /// Shared secret for HS256 tokens.
+    pub secret: String,
+    /// PEM file with the RSA public key for RS256 tokens.
+    pub public_key: String,
+    /// Local JWKS file with RS256 keys, selected by `kid`.
+    pub jwks_file: String,
+    /// Required `iss` claim, if set.
+    pub issuer: String,
+    /// Required `aud` claim, if set.
+    pub audience: String,
+    /// Seconds of clock skew tolerated when checking `exp` and `nbf`.
+    pub leeway: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            host: "127.0.0.1".to_string(),
+            port: 3000,
+            workers: 0,
+            log_level: "info".to_string(),
+            log_format: LogFormat::default(),
+            storage: "memory".to_string(),
+            limits: Limits::default(),
+            shutdown: ShutdownConfig::default(),
+            admin: AdminConfig::default(),
+            tracing: TracingConfig::default(),
+            auth: AuthConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            events: EventsConfig::default(),
+        }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            anonymous_root: true,
+            jwt: JwtConfig::default(),
+            session: SessionConfig::default(),
+        }
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            key: String::new(),
+            ttl: 86400,
+            secure_cookie: false,
+            allowed_origins: Vec::new(),
+        }
+    }
+}
+
+impl SessionConfig {
+    /// The decoded cookie key, `None` if none is configured.
+    pub fn key_bytes(&self) -> Result<Option<Vec<u8>>, String> {
+        if self.key.is_empty() {
+            return Ok(None);
+        }
+        let bytes = STANDARD
+            .decode(self.key.split_whitespace().collect::<String>())
+            .map_err(|err| format!("not valid base64: {}", err))?;
+        if bytes.len() < 64 {
+            return Err(format!(
+                "must decode to at least 64 bytes, got {}",
+                bytes.len()
+            ));
+        }
+        Ok(Some(bytes))
+    }
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        JwtConfig {
+            secret: String::new(),
+            public_key: String::new(),
+            jwks_file: String::new(),
+            issuer: String::new(),
+            audience: String::new(),
+            leeway: 30,
+        }
+    }
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        TracingConfig {
+            exporter: TraceExporter::default(),
+            // The OpenTelemetry Collector's default OTLP/HTTP receiver.
+            endpoint: "http://localhost:4318/v1/traces".to_string(),
+            file: "traces.jsonl".to_string(),
+            service_name: env!("CARGO_PKG_NAME").to_string(),
+        }
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        // actix-web's default.
+        ShutdownConfig { drain_timeout: 30 }
+    }
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        EventsConfig {
+            buffer: 256,
+            history: 1000,
+            heartbeat: 15,
+            client_timeout: 45,
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        // Same as actix-web's own defaults.
+        Limits {
+            json: 2 * 1024 * 1024,
+            payload: 256 * 1024,
+        }
+    }
+}
+
+/// Command-line flags; each one overrides the setting of the same name. They
+/// are accepted before or after any subcommand.
+#[derive(Args, Debug, Default)]
+pub struct ConfigArgs {
+    /// TOML file to read settings from
+    #[arg(long, short, global = true, env = "APP_CONFIG", value_name = "PATH")]
+    pub config: Option<PathBuf>,
+    /// Address to bind to
+    #[arg(long, global = true)]
+    pub host: Option<String>,
+    /// Port to listen on
+    #[arg(long, short, global = true)]
+    pub port: Option<u16>,
+    /// Worker threads (0 = one per CPU core)
+    #[arg(long, global = true)]
+    pub workers: Option<usize>,
+    /// Log filter, e.g. `info` or `warn,actix_web=debug`
+    #[arg(long, global = true, value_name = "FILTER")]
+    pub log_level: Option<String>,
+    /// Log line format: `json` or `text`
+    #[arg(long, global = true, value_name = "FORMAT")]
+    pub log_format: Option<LogFormat>,
+    /// Storage backend: `memory`, `sqlite:<path>` or `json:<path>`
+    #[arg(long, global = true, value_name = "SPEC")]
+    pub storage: Option<String>,
+    /// Maximum JSON, MessagePack, CBOR or YAML body size in bytes
+    #[arg(long, global = true, value_name = "BYTES")]
+    pub json_limit: Option<usize>,
+    /// Maximum raw body size in bytes
+    #[arg(long, global = true, value_name = "BYTES")]
+    pub payload_limit: Option<usize>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    /// A layer could not be parsed; the first field names the layer.
+    Parse(String, String),
+    /// The merged settings parsed but are not usable.
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, err) => {
+                write!(f, "cannot read config file {}: {}", path.display(), err)
+            }
+            ConfigError::Parse(source, msg) => write!(f, "{}: {}", source, msg),
+            ConfigError::Invalid(problems) => {
+                write!(f, "invalid configuration:")?;
+                for problem in problems {
+                    write!(f, "\n  - {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Resolves every layer against the process environment.
+    pub fn load(args: &ConfigArgs) -> Result<Config, ConfigError> {
+        Config::load_from(args, std::env::vars())
+    }
+
+    pub fn load_from(
+        args: &ConfigArgs,
+        env: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Config, ConfigError> {
+        let mut table = Table::try_from(Config::default()).unwrap();
+
+        if let Some(path) = &args.config {
+            let text =
+                fs::read_to_string(path).map_err(|err| ConfigError::Read(path.clone(), err))?;
+            let file: Table = toml::from_str(&text)
+                .map_err(|err| ConfigError::Parse(path.display().to_string(), err.to_string()))?;
+            merge(&mut table, file);
+        }
+
+        // Other software shares the prefix (`APP_ENV`, `APP_NAME`), so only
+        // variables naming one of our settings are read. Within a known
+        // section a misspelt key is still an error.
+        let mut env: Vec<_> = env
+            .into_iter()
+            .filter_map(|(key, raw)| {
+                let path: Vec<String> = key
+                    .strip_prefix("APP_")?
+                    .split("__")
+                    .map(str::to_ascii_lowercase)
+                    .collect();
+                table.contains_key(&path[0]).then_some((key, path, raw))
+            })
+            .collect();
+        env.sort();
+        for (key, path, raw) in env {
+            set(&mut table, &path, &raw).map_err(|msg| ConfigError::Parse(key, msg))?;
+        }
+
+        let source = match &args.config {
+            Some(path) => format!("{} and APP_* environment", path.display()),
+            None => "APP_* environment".to_string(),
+        };
+        let mut config: Config = table.try_into().map_err(|err: toml::de::Error| {
+            ConfigError::Parse(source, err.message().to_string())
+        })?;
+        args.apply(&mut config);
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// A copy that is safe to print, with secrets masked.
+    pub fn redacted(&self) -> Config {
+        let mut config = self.clone();
+        if !config.admin.token.is_empty() {
+            config.admin.token = "<redacted>".to_string();
+        }
+        if !config.auth.jwt.secret.is_empty() {
+            config.auth.jwt.secret = "<redacted>".to_string();
+        }
+        if !config.auth.session.key.is_empty() {
+            config.auth.session.key = "<redacted>".to_string();
+        }
+        config
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        if self.host.trim().is_empty() {
+            problems.push("host must not be empty".to_string());
+        }
+        if let Err(msg) = check_log_filter(&self.log_level) {
+            problems.push(format!("log_level: {}", msg));
+        }
+        if let Err(err) = self.storage.parse::<Backend>() {
+            problems.push(format!("storage: {}", err));
+        }
+        if self.limits.json == 0 {
+            problems.push("limits.json must be greater than 0".to_string());
+        }
+        if self.limits.payload == 0 {
+            problems.push("limits.payload must be greater than 0".to_string());
+        }
+        if !self.admin.token.is_empty() && self.admin.token.len() < 16 {
+            problems.push("admin.token must be at least 16 characters".to_string());
+        }
+        if !self.auth.jwt.secret.is_empty() && self.auth.jwt.secret.len() < 32 {
+            // RFC 7518 section 3.2: at least as many bits as the hash output.
+            problems.push("auth.jwt.secret must be at least 32 bytes".to_string());
+        }
+        if let Err(msg) = self.auth.session.key_bytes() {
+            problems.push(format!("auth.session.key: {}", msg));
+        }
+        for origin in &self.auth.session.allowed_origins {
+            if !is_origin(origin) {
+                problems.push(format!(
+                    "auth.session.allowed_origins: {:?} is not an origin like https://app.example.com",
+                    origin
+                ));
+            }
+        }
+        if self.auth.session.ttl == 0 {
+            problems.push("auth.session.ttl must be greater than 0".to_string());
+        }
+        if self.auth.session.ttl > MAX_SESSION_TTL {
+            problems.push(format!(
+                "auth.session.ttl must be at most {} seconds (a year)",
+                MAX_SESSION_TTL
+            ));
+        }
+        for proxy in &self.rate_limit.trusted_proxies {
+            if let Err(msg) = rate_limit::parse_proxy(proxy) {
+                problems.push(format!("rate_limit.trusted_proxies: {}", msg));
+            }
+        }
+        for (i, rule) in self.rate_limit.rules.iter().enumerate() {
+            if let Err(msg) = rate_limit::check_rule(rule) {
+                problems.push(format!("rate_limit.rules[{}]: {}", i, msg));
+            }
+        }
+        if self.events.buffer == 0 {
+            problems.push("events.buffer must be greater than 0".to_string());
+        }
+        if self.events.buffer > MAX_EVENTS {
+            problems.push(format!("events.buffer must be at most {}", MAX_EVENTS));
+        }
+        if self.events.history > MAX_EVENTS {
+            problems.push(format!("events.history must be at most {}", MAX_EVENTS));
+        }
+        if self.events.heartbeat == 0 {
+            problems.push("events.heartbeat must be greater than 0".to_string());
+        }
+        if self.events.client_timeout <= self.events.heartbeat {
+            problems.push("events.client_timeout must be longer than events.heartbeat".to_string());
+        }
+        if self.tracing.exporter == TraceExporter::Otlp
+            && !(self.tracing.endpoint.starts_with("http://")
+                || self.tracing.endpoint.starts_with("https://"))
+        {
+            problems.push("tracing.endpoint must be an http:// or https:// URL".to_string());
+        }
+        if self.tracing.exporter == TraceExporter::File && self.tracing.file.is_empty() {
+            problems.push("tracing.file must not be empty".to_string());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+}
+
+impl ConfigArgs {
+    fn apply(&self, config: &mut Config) {
+        if let Some(host) = &self.host {
+            config.host = host.clone();
+        }
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(workers) = self.workers {
+            config.workers = workers;
+        }
+        if let Some(log_level) = &self.log_level {
+            config.log_level = log_level.clone();
+        }
+        if let Some(log_format) = self.log_format {
+            config.log_format = log_format;
+        }
+        if let Some(storage) = &self.storage {
+            config.storage = storage.clone();
+        }
+        if let Some(json) = self.json_limit {
+            config.limits.json = json;
+        }
+        if let Some(payload) = self.payload_limit {
+            config.limits.payload = payload;
+        }
+    }
+}
+
+/// Recursively overlays `layer` onto `base`.
+fn merge(base: &mut Table, layer: Table) {
+    for (key, value) in layer {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(layer)) => merge(base, layer),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Sets the key at `path` from an environment variable. The string is
+/// converted to the type of the value it replaces, or guessed when the key
+/// has no default.
+fn set(table: &mut Table, path: &[String], raw: &str) -> Result<(), String> {
+    let (key, parents) = path.split_last().unwrap();
+    let mut table = table;
+    for parent in parents {
+        table = table
+            .entry(parent.clone())
+            .or_insert_with(|| Value::Table(Table::new()))
+            .as_table_mut()
+            .ok_or_else(|| format!("{} is not a table", parent))?;
+    }
+
+    let value = match table.get(key) {
+        Some(Value::Integer(_)) => raw
+            .parse()
+            .map(Value::Integer)
+            .map_err(|_| format!("expected an integer, got {:?}", raw))?,
+        Some(Value::Boolean(_)) => raw
+            .parse()
+            .map(Value::Boolean)
+            .map_err(|_| format!("expected true or false, got {:?}", raw))?,
+        Some(Value::String(_)) => Value::String(raw.to_string()),
+        _ => raw
+            .parse()
+            .map(Value::Integer)
+            .or_else(|_| raw.parse().map(Value::Boolean))
+            .unwrap_or_else(|_| Value::String(raw.to_string())),
+    };
+    table.insert(key.clone(), value);
+    Ok(())
+}
+
+/// `scheme://host[:port]`, as browsers send in `Origin`.
+fn is_origin(origin: &str) -> bool {
+    ["http://", "https://"].iter().any(|scheme| {
+        origin
+            .strip_prefix(scheme)
+            .is_some_and(|host| !host.is_empty() && !host.contains(['/', '?', '#']))
+    })
+}
+
+/// Accepts what `env_logger` accepts: comma-separated `level`, `target` or
+/// `target=level` entries, optionally followed by `/` and a message filter.
+/// `env_logger` itself only warns about the rest and carries on.
+fn check_log_filter(filter: &str) -> Result<(), String> {
+    let mut parts = filter.splitn(3, '/');
+    let directives = parts.next().unwrap_or_default();
+    parts.next();
+    if parts.next().is_some() {
+        return Err(format!("{:?} has more than one '/'", filter));
+    }
+    for directive in directives
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+    {
+        let Some((target, level)) = directive.split_once('=') else {
+            // A bare level, or a target to log at every level.
+            continue;
+        };
+        let level = level.trim();
+        if target.trim().is_empty() || level.contains('=') {
+            return Err(format!("{:?} is not a target=level directive", directive));
+        }
+        if !level.is_empty() && level.parse::<log::LevelFilter>().is_err() {
+            return Err(format!(
+                "{:?} is not one of off, error, warn, info, debug, trace",
+                level
+            ));
+        }
+    }
+    Ok(())
+}
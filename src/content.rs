@@ -0,0 +1,251 @@
+//! Content negotiation for the `/users` routes: JSON, MessagePack, CBOR or
+//! YAML, both ways.
+//!
+//! [`Body`] reads a request body in whichever format its `Content-Type`
+//! names, and fails with `415` for any other. The [`Format`] extractor picks
+//! the response format from `Accept`, honoring q-values, and fails with `406`
+//! when none is acceptable; it runs before the handler, so a request that
+//! can't be answered has no side effects. Problem documents are always JSON.
+
+use std::future::{ready, Future, Ready};
+use std::ops::Deref;
+use std::pin::Pin;
+
+use actix_web::dev::Payload;
+use actix_web::http::header::{self, Accept, Header, Quality};
+use actix_web::mime::{self, Mime};
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::config::Limits;
+use crate::error::AppError;
+
+/// A body format, in the order the server prefers them when `Accept`
+/// allows several equally.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    MessagePack,
+    Cbor,
+    Yaml,
+}
+
+impl Format {
+    pub const ALL: [Format; 4] = [
+        Format::Json,
+        Format::MessagePack,
+        Format::Cbor,
+        Format::Yaml,
+    ];
+
+    /// The `Content-Type` of responses in this format.
+    pub fn media_type(self) -> &'static str {
+        self.media_types()[0]
+    }
+
+    /// Every media type this format is known by, the canonical one first.
+    pub fn media_types(self) -> &'static [&'static str] {
+        match self {
+            Format::Json => &["application/json"],
+            Format::MessagePack => &[
+                "application/msgpack",
+                "application/vnd.msgpack",
+                "application/x-msgpack",
+            ],
+            Format::Cbor => &["application/cbor"],
+            Format::Yaml => &[
+                "application/yaml",
+                "application/x-yaml",
+                "text/yaml",
+                "text/x-yaml",
+            ],
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Format::Json => "JSON",
+            Format::MessagePack => "MessagePack",
+            Format::Cbor => "CBOR",
+            Format::Yaml => "YAML",
+        }
+    }
+
+    /// The format of a body sent with `Content-Type: mime`. Any `+json` type,
+    /// such as `application/merge-patch+json`, counts as JSON.
+    pub fn from_content_type(mime: &Mime) -> Option<Format> {
+        if mime.suffix() == Some(mime::JSON) {
+            return Some(Format::Json);
+        }
+        Format::ALL.into_iter().find(|format| {
+            format
+                .media_types()
+                .iter()
+                .any(|media_type| media_type == &mime.essence_str())
+        })
+    }
+
+    /// The format `accept` prefers, or `None` if it rules out all of them.
+    pub fn negotiate(accept: &Accept) -> Option<Format> {
+        let mut best = None;
+        let mut best_quality = Quality::ZERO;
+        for format in Format::ALL {
+            let quality = format.quality(accept);
+            // Strictly greater, so that ties go to the earlier format.
+            if quality > best_quality {
+                best = Some(format);
+                best_quality = quality;
+            }
+        }
+        best
+    }
+
+    /// The q-value `accept` gives this format: that of the most specific
+    /// range matching any of its media types, so `application/cbor;q=0`
+    /// overrides `*/*`.
+    fn quality(self, accept: &Accept) -> Quality {
+        let mut best: Option<(u8, Quality)> = None;
+        for range in accept.iter() {
+            for media_type in self.media_types() {
+                let (type_, subtype) = media_type.split_once('/').unwrap();
+                let specificity = if range.item.type_() == mime::STAR {
+                    0
+                } else if range.item.type_() != type_ {
+                    continue;
+                } else if range.item.subtype() == mime::STAR {
+                    1
+                } else if range.item.subtype() == subtype {
+                    2
+                } else {
+                    continue;
+                };
+                if best.is_none_or(|(most_specific, _)| specificity > most_specific) {
+                    best = Some((specificity, range.quality));
+                }
+            }
+        }
+        best.map_or(Quality::ZERO, |(_, quality)| quality)
+    }
+
+    /// Serializes `value`. Structs become maps in every format, so the
+    /// documents have the same shape as the JSON ones.
+    pub fn encode<T: Serialize>(self, value: &T) -> Vec<u8> {
+        const INFALLIBLE: &str = "API types serialize in every format";
+        match self {
+            Format::Json => serde_json::to_vec(value).expect(INFALLIBLE),
+            Format::MessagePack => rmp_serde::to_vec_named(value).expect(INFALLIBLE),
+            Format::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(value, &mut bytes).expect(INFALLIBLE);
+                bytes
+            }
+            Format::Yaml => serde_yaml::to_string(value).expect(INFALLIBLE).into_bytes(),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, AppError> {
+        let result = match self {
+            Format::Json => serde_json::from_slice(bytes).map_err(|err| err.to_string()),
+            Format::MessagePack => rmp_serde::from_slice(bytes).map_err(|err| err.to_string()),
+            Format::Cbor => ciborium::from_reader(bytes).map_err(|err| err.to_string()),
+            Format::Yaml => serde_yaml::from_slice(bytes).map_err(|err| err.to_string()),
+        };
+        result
+            .map_err(|err| AppError::InvalidBody(format!("Invalid {} body: {}", self.name(), err)))
+    }
+
+    /// Finishes `builder` with `value` in this format.
+    pub fn respond<T: Serialize>(
+        self,
+        mut builder: HttpResponseBuilder,
+        value: &T,
+    ) -> HttpResponse {
+        builder
+            .append_header((header::VARY, "Accept"))
+            .content_type(self.media_type())
+            .body(self.encode(value))
+    }
+}
+
+fn supported_types() -> String {
+    Format::ALL
+        .iter()
+        .map(|format| format.media_type())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The response format `Accept` asks for. A missing or unreadable header
+/// means JSON.
+impl FromRequest for Format {
+    type Error = AppError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let accept = match Accept::parse(req) {
+            Ok(accept) if !accept.is_empty() => accept,
+            _ => return ready(Ok(Format::Json)),
+        };
+        ready(Format::negotiate(&accept).ok_or_else(|| {
+            AppError::NotAcceptable(format!(
+                "None of the Accept types can be produced; this route serves {}",
+                supported_types()
+            ))
+        }))
+    }
+}
+
+/// A request body in any [`Format`], read up to `limits.json` bytes.
+pub struct Body<T>(pub T);
+
+impl<T> Body<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for Body<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for Body<T> {
+    type Error = AppError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let format = req
+            .mime_type()
+            .ok()
+            .flatten()
+            .and_then(|mime| Format::from_content_type(&mime));
+        let limit = req
+            .app_data::<Limits>()
+            .map_or_else(|| Limits::default().json, |limits| limits.json);
+        let body = web::Payload::from_request(req, payload).into_inner();
+        Box::pin(async move {
+            let format = format.ok_or_else(|| {
+                AppError::UnsupportedMediaType(format!(
+                    "Expected a body with Content-Type: {}",
+                    supported_types()
+                ))
+            })?;
+            let bytes = body
+                .map_err(|err| AppError::InvalidBody(err.to_string()))?
+                .to_bytes_limited(limit)
+                .await
+                .map_err(|_| {
+                    AppError::PayloadTooLarge(format!(
+                        "Body is larger than the {} byte limit",
+                        limit
+                    ))
+                })?
+                .map_err(|err| AppError::InvalidBody(err.to_string()))?;
+            format.decode(&bytes).map(Body)
+        })
+    }
+}
@@ -0,0 +1,342 @@
+//! The application error type and its RFC 7807 `application/problem+json`
+//! rendering.
+//!
+//! Handlers return [`AppError`]; extractor failures, unknown routes and any
+//! other error response produced by actix itself are funnelled through the
+//! handlers at the bottom of this module, so every failure reaches the client
+//! in the same shape.
+
+use std::fmt;
+
+use actix_web::body::{BoxBody, EitherBody};
+use actix_web::dev::ServiceResponse;
+use actix_web::error::{JsonPayloadError, PathError, QueryPayloadError};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::middleware::ErrorHandlerResponse;
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use utoipa::ToSchema;
+use validator::ValidationErrors;
+
+use crate::request_id::RequestId;
+use crate::storage::StorageError;
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// The `WWW-Authenticate` challenge a `401` answers with, picked from the
+/// credentials the request offered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Challenge {
+    /// No credentials were offered; lists every scheme the API accepts.
+    Any,
+    /// Only a bearer token will do, as on the admin routes.
+    Bearer,
+    /// The bearer token offered was rejected.
+    InvalidToken,
+    /// The API key offered was rejected.
+    ApiKey,
+    /// Not HTTP authentication, e.g. a login form or a session cookie, so
+    /// there is nothing to challenge with and no header is sent.
+    None,
+}
+
+impl Challenge {
+    fn header_value(self) -> Option<HeaderValue> {
+        let value = match self {
+            Challenge::Any => "Bearer, ApiKey",
+            Challenge::Bearer => "Bearer",
+            Challenge::InvalidToken => "Bearer error=\"invalid_token\"",
+            Challenge::ApiKey => "ApiKey",
+            Challenge::None => return None,
+        };
+        Some(HeaderValue::from_static(value))
+    }
+}
+
+#[derive(Debug)]
+pub enum AppError {
+    /// Missing or wrong credentials.
+    Unauthorized(Challenge, String),
+    /// Valid credentials that don't allow this request.
+    Forbidden(String),
+    NotFound(String),
+    Conflict(String),
+    /// The request body could not be read as the expected type.
+    InvalidBody(String),
+    /// A path segment could not be parsed, e.g. a non-numeric `{id}`.
+    InvalidPath(String),
+    InvalidQuery(String),
+    PayloadTooLarge(String),
+    UnsupportedMediaType(String),
+    /// No format the client accepts can represent the response.
+    NotAcceptable(String),
+    /// The client used up its rate limit.
+    TooManyRequests(String),
+    /// The instance can't take traffic right now, e.g. while draining.
+    Unavailable(String),
+    Validation(ValidationErrors),
+    Storage(StorageError),
+}
+
+impl AppError {
+    /// Short identifier used to build the problem `type` URI.
+    fn slug(&self) -> &'static str {
+        match self {
+            AppError::Unauthorized(..) => "unauthorized",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::NotFound(_) => "not-found",
+            AppError::Conflict(_) => "conflict",
+            AppError::InvalidBody(_) => "invalid-body",
+            AppError::InvalidPath(_) => "invalid-path",
+            AppError::InvalidQuery(_) => "invalid-query",
+            AppError::PayloadTooLarge(_) => "payload-too-large",
+            AppError::UnsupportedMediaType(_) => "unsupported-media-type",
+            AppError::NotAcceptable(_) => "not-acceptable",
+            AppError::TooManyRequests(_) => "rate-limited",
+            AppError::Unavailable(_) => "unavailable",
+            AppError::Validation(_) => "validation-error",
+            AppError::Storage(_) => "internal-error",
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            AppError::Unauthorized(..) => "Authentication required",
+            AppError::Forbidden(_) => "Permission denied",
+            AppError::NotFound(_) => "Resource not found",
+            AppError::Conflict(_) => "Conflicting request",
+            AppError::InvalidBody(_) => "Malformed request body",
+            AppError::InvalidPath(_) => "Invalid path parameter",
+            AppError::InvalidQuery(_) => "Invalid query string",
+            AppError::PayloadTooLarge(_) => "Request body too large",
+            AppError::UnsupportedMediaType(_) => "Unsupported media type",
+            AppError::NotAcceptable(_) => "Not acceptable",
+            AppError::TooManyRequests(_) => "Too many requests",
+            AppError::Unavailable(_) => "Service unavailable",
+            AppError::Validation(_) => "Validation failed",
+            AppError::Storage(_) => "Internal server error",
+        }
+    }
+
+    fn detail(&self) -> String {
+        match self {
+            AppError::Unauthorized(_, detail)
+            | AppError::Forbidden(detail)
+            | AppError::NotFound(detail)
+            | AppError::Conflict(detail)
+            | AppError::InvalidBody(detail)
+            | AppError::InvalidPath(detail)
+            | AppError::InvalidQuery(detail)
+            | AppError::PayloadTooLarge(detail)
+            | AppError::UnsupportedMediaType(detail)
+            | AppError::NotAcceptable(detail)
+            | AppError::TooManyRequests(detail)
+            | AppError::Unavailable(detail) => detail.clone(),
+            AppError::Validation(_) => "One or more fields are invalid".to_string(),
+            // Storage internals are logged, not shown to clients.
+            AppError::Storage(_) => "The server could not complete the request".to_string(),
+        }
+    }
+
+    fn problem(&self) -> Problem {
+        let mut problem = Problem::new(
+            format!("/problems/{}", self.slug()),
+            self.title(),
+            self.status_code(),
+            self.detail(),
+        );
+        if let AppError::Validation(errors) = self {
+            problem
+                .extensions
+                .insert("errors".to_string(), serde_json::to_value(errors).unwrap());
+        }
+        problem
+    }
+
+    /// The problem document as JSON, for errors reported inside another
+    /// protocol's envelope, such as a GraphQL response.
+    pub(crate) fn problem_json(&self) -> Value {
+        serde_json::to_value(self.problem()).unwrap()
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Storage(err) => write!(f, "{}", err),
+            AppError::Validation(errors) => write!(f, "validation failed: {}", errors),
+            _ => write!(f, "{}: {}", self.title(), self.detail()),
+        }
+    }
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::Unauthorized(..) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::InvalidBody(_) | AppError::InvalidPath(_) | AppError::InvalidQuery(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            AppError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AppError::NotAcceptable(_) => StatusCode::NOT_ACCEPTABLE,
+            AppError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        if let AppError::Storage(err) = self {
+            log::error!("{}", err);
+        }
+        let mut response = self.problem().into_response();
+        if let AppError::Unauthorized(challenge, _) = self {
+            if let Some(value) = challenge.header_value() {
+                response
+                    .headers_mut()
+                    .insert(header::WWW_AUTHENTICATE, value);
+            }
+        }
+        response
+    }
+}
+
+impl From<StorageError> for AppError {
+    fn from(err: StorageError) -> Self {
+        match err {
+            StorageError::DuplicateEmail(email) => {
+                AppError::Conflict(format!("An account for {} already exists", email))
+            }
+            err => AppError::Storage(err),
+        }
+    }
+}
+
+impl From<ValidationErrors> for AppError {
+    fn from(errors: ValidationErrors) -> Self {
+        AppError::Validation(errors)
+    }
+}
+
+/// An RFC 7807 problem details document. Validation failures add an
+/// `errors` member with the messages for each field.
+#[derive(Serialize, ToSchema)]
+#[schema(example = json!({
+    "type": "/problems/not-found",
+    "title": "Resource not found",
+    "status": 404,
+    "detail": "User 7 not found",
+    "request_id": "4f2c1a9e-5d3b-4c8a-9e7f-0b1d2c3e4f5a"
+}))]
+pub struct Problem {
+    #[serde(rename = "type")]
+    kind: String,
+    title: String,
+    status: u16,
+    detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    #[serde(flatten)]
+    #[schema(ignore)]
+    extensions: Map<String, Value>,
+}
+
+impl Problem {
+    fn new(kind: String, title: &str, status: StatusCode, detail: String) -> Self {
+        Problem {
+            kind,
+            title: title.to_string(),
+            status: status.as_u16(),
+            detail,
+            request_id: RequestId::current().map(|id| id.to_string()),
+            extensions: Map::new(),
+        }
+    }
+
+    /// A problem that says nothing beyond its status code; RFC 7807 uses
+    /// `about:blank` as the type for these.
+    fn for_status(status: StatusCode, detail: String) -> Self {
+        let title = status.canonical_reason().unwrap_or("Error");
+        Problem::new("about:blank".to_string(), title, status, detail)
+    }
+
+    fn into_response(self) -> HttpResponse {
+        let status = StatusCode::from_u16(self.status).unwrap();
+        HttpResponse::build(status)
+            .content_type(PROBLEM_JSON)
+            .json(self)
+    }
+}
+
+/// `JsonConfig` error handler.
+pub fn json_error(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    match err {
+        JsonPayloadError::ContentType => AppError::UnsupportedMediaType(
+            "Expected a body with Content-Type: application/json".to_string(),
+        ),
+        JsonPayloadError::Overflow { .. } | JsonPayloadError::OverflowKnownLength { .. } => {
+            AppError::PayloadTooLarge(err.to_string())
+        }
+        _ => AppError::InvalidBody(err.to_string()),
+    }
+    .into()
+}
+
+/// `PathConfig` error handler.
+pub fn path_error(err: PathError, req: &HttpRequest) -> actix_web::Error {
+    AppError::InvalidPath(format!("{}: {}", req.path(), err)).into()
+}
+
+/// `QueryConfig` error handler.
+pub fn query_error(err: QueryPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    AppError::InvalidQuery(err.to_string()).into()
+}
+
+/// `default_service` for requests that match no route.
+pub async fn not_found(req: HttpRequest) -> Result<HttpResponse, AppError> {
+    Err(AppError::NotFound(format!(
+        "No route for {} {}",
+        req.method(),
+        req.path()
+    )))
+}
+
+/// `ErrorHandlers` fallback that turns any error response that is not
+/// already a problem document (such as actix's bodyless `405`) into one.
+/// Headers such as `Allow` are kept. Server errors get a generic detail, as
+/// [`AppError::Storage`] does, and the error itself is logged.
+pub fn render_problem<B>(res: ServiceResponse<B>) -> actix_web::Result<ErrorHandlerResponse<B>> {
+    let is_problem = res.response().headers().get(header::CONTENT_TYPE)
+        == Some(&HeaderValue::from_static(PROBLEM_JSON));
+    if is_problem {
+        return Ok(ErrorHandlerResponse::Response(res.map_into_left_body()));
+    }
+
+    let status = res.status();
+    let detail = match res.response().error() {
+        Some(err) if status.is_server_error() => {
+            log::error!("{}", err);
+            "The server could not complete the request".to_string()
+        }
+        Some(err) => err.to_string(),
+        None => format!("{} {}", res.request().method(), res.request().path()),
+    };
+    let (req, original) = res.into_parts();
+    let mut response = Problem::for_status(status, detail).into_response();
+    for (name, value) in original.headers() {
+        if name != header::CONTENT_TYPE && name != header::CONTENT_LENGTH {
+            response.headers_mut().append(name.clone(), value.clone());
+        }
+    }
+    let response = response.map_body(|_, body| EitherBody::<B, BoxBody>::right(body));
+    Ok(ErrorHandlerResponse::Response(ServiceResponse::new(
+        req, response,
+    )))
+}
@@ -0,0 +1,190 @@
+//! User change events, published as the store is changed (see
+//! [`PublishingRepository`](crate::storage::PublishingRepository)) and
+//! streamed to clients over `/ws/users` and `/users/events`.
+//!
+//! [`Events`] fans each event out to every subscriber through a bounded
+//! broadcast channel. A subscriber that falls `events.buffer` events behind
+//! loses its place rather than slowing down the others or growing without
+//! limit. The last `events.history` events are also retained, so a client
+//! that lost its place or reconnects can pick up where it left off.
+
+pub mod sse;
+pub mod ws;
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use async_graphql::{Enum, SimpleObject};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::auth::policy::{self, Action};
+use crate::auth::Claims;
+use crate::config::EventsConfig;
+use crate::error::AppError;
+use crate::models::User;
+
+#[derive(Serialize, ToSchema, Enum, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+impl EventKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EventKind::Created => "created",
+            EventKind::Updated => "updated",
+            EventKind::Deleted => "deleted",
+        }
+    }
+}
+
+/// A change to one user.
+#[derive(Serialize, ToSchema, SimpleObject, Clone)]
+pub struct UserEvent {
+    /// Increases by one with every event this instance publishes.
+    pub id: u64,
+    #[serde(rename = "type")]
+    #[graphql(name = "type")]
+    pub kind: EventKind,
+    pub user_id: u32,
+    /// The user after the change; absent for `deleted`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<User>,
+    pub at: DateTime<Utc>,
+}
+
+/// Which events a client receives.
+#[derive(Deserialize, IntoParams, Clone, Copy, Default)]
+#[into_params(parameter_in = Query)]
+pub struct Filter {
+    /// Only events for this user. Viewers must name themselves.
+    pub user_id: Option<u32>,
+}
+
+impl Filter {
+    /// Checks that `claims` may see the events this filter lets through:
+    /// every user's needs the list permission, one user's the read
+    /// permission on that user.
+    pub fn authorize(&self, claims: &Claims) -> Result<(), AppError> {
+        match self.user_id {
+            Some(user_id) => policy::authorize(claims.subject(), Action::Read, Some(user_id)),
+            None => policy::authorize(claims.subject(), Action::List, None),
+        }
+    }
+
+    pub fn matches(&self, event: &UserEvent) -> bool {
+        self.user_id.is_none_or(|user_id| user_id == event.user_id)
+    }
+}
+
+/// A place in the event stream: the events since some id, followed by
+/// the live ones.
+pub struct Subscription {
+    /// Id of the last event before the first one `receiver` yields.
+    pub position: u64,
+    /// The retained events after the requested id; `None` if some of them
+    /// are no longer retained, or the id was never published.
+    pub missed: Option<Vec<Arc<UserEvent>>>,
+    pub receiver: broadcast::Receiver<Arc<UserEvent>>,
+}
+
+struct Log {
+    next_id: u64,
+    retained: VecDeque<Arc<UserEvent>>,
+}
+
+pub struct Events {
+    config: EventsConfig,
+    /// Locked while sending, so that ids reach subscribers in order and
+    /// [`Events::resume`] sees a consistent log.
+    log: Mutex<Log>,
+    sender: broadcast::Sender<Arc<UserEvent>>,
+}
+
+impl Events {
+    /// A hub whose subscribers may each fall `config.buffer` events behind.
+    pub fn new(config: &EventsConfig) -> Self {
+        Events {
+            config: config.clone(),
+            log: Mutex::new(Log {
+                next_id: 1,
+                // Grows as events arrive rather than reserving `history`
+                // slots up front.
+                retained: VecDeque::new(),
+            }),
+            sender: broadcast::channel(config.buffer).0,
+        }
+    }
+
+    pub fn config(&self) -> &EventsConfig {
+        &self.config
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<UserEvent>> {
+        self.sender.subscribe()
+    }
+
+    /// Subscribes after event `after`, or from now on if `None`.
+    pub fn resume(&self, after: Option<u64>) -> Subscription {
+        let log = self.log.lock().unwrap();
+        let position = log.next_id - 1;
+        let missed = match after {
+            None => Some(Vec::new()),
+            // From before a restart, or another instance.
+            Some(after) if after > position => None,
+            Some(after) => {
+                let oldest = log.retained.front().map_or(log.next_id, |event| event.id);
+                (oldest <= after + 1).then(|| {
+                    log.retained
+                        .iter()
+                        .filter(|event| event.id > after)
+                        .cloned()
+                        .collect()
+                })
+            }
+        };
+        Subscription {
+            position,
+            missed,
+            receiver: self.sender.subscribe(),
+        }
+    }
+
+    pub fn created(&self, user: &User) {
+        self.publish(EventKind::Created, user.id, Some(user.clone()));
+    }
+
+    pub fn updated(&self, user: &User) {
+        self.publish(EventKind::Updated, user.id, Some(user.clone()));
+    }
+
+    pub fn deleted(&self, user_id: u32) {
+        self.publish(EventKind::Deleted, user_id, None);
+    }
+
+    fn publish(&self, kind: EventKind, user_id: u32, user: Option<User>) {
+        let mut log = self.log.lock().unwrap();
+        let event = Arc::new(UserEvent {
+            id: log.next_id,
+            kind,
+            user_id,
+            user,
+            at: Utc::now(),
+        });
+        log.next_id += 1;
+        if self.config.history > 0 {
+            if log.retained.len() == self.config.history {
+                log.retained.pop_front();
+            }
+            log.retained.push_back(event.clone());
+        }
+        // Fails only when nobody is subscribed.
+        let _ = self.sender.send(event);
+    }
+}
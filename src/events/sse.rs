@@ -0,0 +1,219 @@
+//! `GET /users/events`: user change events as Server-Sent Events, for
+//! clients that can't use the WebSocket, e.g. behind proxies that don't pass
+//! upgrades.
+//!
+//! Authentication and the `user_id` filter work as on `/ws/users`. Each
+//! event is sent with its id, so a client that reconnects with
+//! `Last-Event-ID` is first sent what it missed, from the retained history.
+//! If that is no longer retained, it gets a `reset` event instead and should
+//! reload the users. A client that falls behind catches up the same way
+//! without reconnecting. Comments keep idle connections open, every
+//! `events.heartbeat` seconds.
+
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::rt::{self, time};
+use actix_web::web::{self, Bytes};
+use actix_web::{http::header, HttpRequest, HttpResponse};
+use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+
+use crate::auth::Claims;
+use crate::error::AppError;
+use crate::openapi;
+use crate::shutdown::Shutdown;
+
+use super::{Events, Filter, Subscription, UserEvent};
+
+/// How long browsers wait before reconnecting, in milliseconds.
+const RETRY_MS: u64 = 3000;
+
+/// Frames waiting to be written to one client.
+const QUEUE: usize = 16;
+
+/// Streams user change events; see the module docs.
+#[utoipa::path(
+    get,
+    path = "/users/events",
+    tag = "users",
+    params(
+        Filter,
+        (
+            "Last-Event-ID" = Option<u64>,
+            Header,
+            description = "Id of the last event received; the stream starts with the ones after it"
+        ),
+    ),
+    responses(
+        (
+            status = 200,
+            description = "Events as they happen: `created`, `updated` and `deleted`, each with a `UserEvent` as data, and `reset` when some can't be replayed",
+            content_type = "text/event-stream",
+            body = UserEvent
+        ),
+        (status = 400, response = openapi::BadRequest),
+        (status = 401, response = openapi::Unauthorized),
+        (status = 403, response = openapi::Forbidden),
+    ),
+    security(("bearer" = ["users:read"]), ("api_key" = ["users:read"]), ("session" = []))
+)]
+pub async fn users(
+    req: HttpRequest,
+    claims: Claims,
+    filter: web::Query<Filter>,
+    events: web::Data<Events>,
+    shutdown: web::Data<Shutdown>,
+) -> Result<HttpResponse, AppError> {
+    let filter = filter.into_inner();
+    filter.authorize(&claims)?;
+    let last_event_id = req.headers().get("Last-Event-ID").map(|value| {
+        value
+            .to_str()
+            .ok()
+            .and_then(|value| value.trim().parse().ok())
+            // Not one of ours, so nothing can be replayed.
+            .unwrap_or(u64::MAX)
+    });
+
+    let subscription = events.resume(last_event_id);
+    let (sender, receiver) = mpsc::channel(QUEUE);
+    let stream = Stream {
+        sender,
+        filter,
+        heartbeat: Duration::from_secs(events.config().heartbeat),
+        timeout: Duration::from_secs(events.config().client_timeout),
+        events: events.into_inner(),
+    };
+    rt::spawn(stream.run(subscription, shutdown));
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        // Asks nginx not to buffer the stream.
+        .insert_header(("X-Accel-Buffering", "no"))
+        .body(EventStream(receiver)))
+}
+
+/// The response body: frames from the stream's task, as they come.
+struct EventStream(mpsc::Receiver<Bytes>);
+
+impl MessageBody for EventStream {
+    type Error = Infallible;
+
+    fn size(&self) -> BodySize {
+        BodySize::Stream
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        self.0.poll_recv(cx).map(|frame| frame.map(Ok))
+    }
+}
+
+/// The client is gone, too slow, or the server is shutting down.
+struct End;
+
+struct Stream {
+    sender: mpsc::Sender<Bytes>,
+    filter: Filter,
+    heartbeat: Duration,
+    timeout: Duration,
+    events: Arc<Events>,
+}
+
+impl Stream {
+    async fn run(mut self, subscription: Subscription, shutdown: web::Data<Shutdown>) {
+        let Subscription {
+            mut position,
+            missed,
+            mut receiver,
+        } = subscription;
+        if self.send(format!("retry: {}\n\n", RETRY_MS)).await.is_err()
+            || self.replay(position, missed).await.is_err()
+        {
+            return;
+        }
+        let mut keep_alive =
+            time::interval_at(time::Instant::now() + self.heartbeat, self.heartbeat);
+
+        loop {
+            let step = tokio::select! {
+                event = receiver.recv() => match event {
+                    Ok(event) => {
+                        position = event.id;
+                        self.forward(&event).await
+                    }
+                    Err(RecvError::Lagged(_)) => {
+                        let resumed = self.events.resume(Some(position));
+                        receiver = resumed.receiver;
+                        position = resumed.position;
+                        self.replay(position, resumed.missed).await
+                    }
+                    Err(RecvError::Closed) => Err(End),
+                },
+                _ = keep_alive.tick() => self.send(": keep-alive\n\n".to_string()).await,
+                _ = self.sender.closed() => Err(End),
+                _ = shutdown.draining() => Err(End),
+            };
+            if step.is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Sends the events a subscription missed, or a `reset` event carrying
+    /// `position` if they are gone, so a later reconnect resumes from there.
+    async fn replay(
+        &mut self,
+        position: u64,
+        missed: Option<Vec<Arc<UserEvent>>>,
+    ) -> Result<(), End> {
+        match missed {
+            Some(missed) => {
+                for event in missed {
+                    self.forward(&event).await?;
+                }
+                Ok(())
+            }
+            None => {
+                let data = json!({
+                    "detail": "Some events are no longer available; reload the users",
+                });
+                self.send(format!(
+                    "id: {}\nevent: reset\ndata: {}\n\n",
+                    position, data
+                ))
+                .await
+            }
+        }
+    }
+
+    async fn forward(&mut self, event: &UserEvent) -> Result<(), End> {
+        if !self.filter.matches(event) {
+            return Ok(());
+        }
+        self.send(format!(
+            "id: {}\nevent: {}\ndata: {}\n\n",
+            event.id,
+            event.kind.as_str(),
+            serde_json::to_string(event).unwrap()
+        ))
+        .await
+    }
+
+    /// Queues a frame, giving up on a client that doesn't read them.
+    async fn send(&mut self, frame: String) -> Result<(), End> {
+        match time::timeout(self.timeout, self.sender.send(Bytes::from(frame))).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(_)) | Err(_) => Err(End),
+        }
+    }
+}
@@ -0,0 +1,271 @@
+//! `GET /ws/users`: user change events over a WebSocket.
+//!
+//! The upgrade request is authenticated like `GET /users`; with a session
+//! cookie it must also come from an allowed `Origin`. The optional
+//! `user_id` query parameter narrows the stream to one user. Each matching
+//! event arrives as a JSON text frame. The client can change its filter by
+//! sending `{"user_id": 7}`, or `{"user_id": null}` for every user; the
+//! server answers with a `subscribed` frame, or an `error` frame if the
+//! filter is not allowed.
+//!
+//! The server pings every `events.heartbeat` seconds and disconnects a client
+//! it has heard nothing from in `events.client_timeout` seconds, or that
+//! can't keep up: one whose frames can't be written within that time, or
+//! that falls `events.buffer` events behind.
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix_web::rt::{self, time};
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_ws::{CloseCode, CloseReason, Closed, Message, MessageStream, Session};
+use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+
+use crate::auth::{self, Claims};
+use crate::openapi;
+use crate::shutdown::Shutdown;
+
+use super::{Events, Filter, UserEvent};
+
+/// Largest frame a client may send; filters are tiny.
+const MAX_FRAME_SIZE: usize = 4 * 1024;
+
+/// Streams user change events; see the module docs.
+#[utoipa::path(
+    get,
+    path = "/ws/users",
+    tag = "users",
+    params(Filter),
+    responses(
+        (
+            status = 101,
+            description = "Switched to the WebSocket protocol; events follow as JSON text frames",
+            body = UserEvent
+        ),
+        (status = 400, response = openapi::BadRequest),
+        (status = 401, response = openapi::Unauthorized),
+        (status = 403, response = openapi::Forbidden),
+    ),
+    security(("bearer" = ["users:read"]), ("api_key" = ["users:read"]), ("session" = []))
+)]
+pub async fn users(
+    req: HttpRequest,
+    body: web::Payload,
+    claims: Claims,
+    filter: web::Query<Filter>,
+    events: web::Data<Events>,
+    shutdown: web::Data<Shutdown>,
+) -> Result<HttpResponse, actix_web::Error> {
+    auth::check_origin(&req, &claims)?;
+    let filter = filter.into_inner();
+    filter.authorize(&claims)?;
+    let (response, session, messages) = actix_ws::handle(&req, body)?;
+
+    // Subscribed before the upgrade completes, so no event is missed.
+    let connection = Connection {
+        events: events.subscribe(),
+        filter,
+        claims,
+    };
+    let socket = Socket::new(session, Duration::from_secs(events.config().client_timeout));
+    let heartbeat = Duration::from_secs(events.config().heartbeat);
+    rt::spawn(async move {
+        let sub = connection.claims.sub.clone();
+        log::debug!("/ws/users: {} subscribed", sub);
+        let messages = messages.max_frame_size(MAX_FRAME_SIZE);
+        match pump(socket, messages, connection, heartbeat, &shutdown).await {
+            End::Close(reason) => {
+                log::debug!("/ws/users: closed {}: {:?}", sub, reason.description)
+            }
+            End::Gone => log::debug!("/ws/users: {} disconnected", sub),
+        }
+    });
+    Ok(response)
+}
+
+struct Connection {
+    events: Receiver<Arc<UserEvent>>,
+    filter: Filter,
+    claims: Claims,
+}
+
+impl Handler for Connection {
+    type Output = Result<Arc<UserEvent>, RecvError>;
+
+    async fn next(&mut self) -> Self::Output {
+        self.events.recv().await
+    }
+
+    async fn output(&mut self, socket: &mut Socket, event: Self::Output) -> Result<(), End> {
+        match event {
+            Ok(event) if self.filter.matches(&event) => {
+                socket.text(serde_json::to_string(&*event).unwrap()).await
+            }
+            Ok(_) => Ok(()),
+            Err(RecvError::Lagged(missed)) => Err(End::Close(close(
+                CloseCode::Again,
+                &format!("Fell {} events behind; reconnect to resume", missed),
+            ))),
+            Err(RecvError::Closed) => Err(End::Close(close(
+                CloseCode::Away,
+                "Server is shutting down",
+            ))),
+        }
+    }
+
+    /// Applies a filter sent by the client.
+    async fn text(&mut self, socket: &mut Socket, text: &str) -> Result<(), End> {
+        let reply = match serde_json::from_str::<Filter>(text) {
+            Ok(filter) => match filter.authorize(&self.claims) {
+                Ok(()) => {
+                    self.filter = filter;
+                    json!({ "type": "subscribed", "user_id": filter.user_id })
+                }
+                Err(err) => json!({ "type": "error", "detail": err.to_string() }),
+            },
+            Err(err) => json!({
+                "type": "error",
+                "detail": format!("Expected {{\"user_id\": <id or null>}}: {}", err),
+            }),
+        };
+        socket.text(reply.to_string()).await
+    }
+}
+
+/// Why [`pump`] stopped: with a close frame to send, or because the
+/// connection is already gone.
+pub(crate) enum End {
+    Close(CloseReason),
+    Gone,
+}
+
+/// The sending half of a connection.
+pub(crate) struct Socket {
+    session: Session,
+    /// How long a client may take to read a frame, or to send one.
+    timeout: Duration,
+}
+
+impl Socket {
+    pub(crate) fn new(session: Session, timeout: Duration) -> Self {
+        Socket { session, timeout }
+    }
+
+    pub(crate) async fn text(&mut self, text: String) -> Result<(), End> {
+        deliver(self.timeout, self.session.text(text)).await
+    }
+
+    async fn ping(&mut self) -> Result<(), End> {
+        deliver(self.timeout, self.session.ping(b"")).await
+    }
+
+    async fn pong(&mut self, bytes: &[u8]) -> Result<(), End> {
+        deliver(self.timeout, self.session.pong(bytes)).await
+    }
+}
+
+/// What a connection does with the client's text frames and its own
+/// output; [`pump`] takes care of the rest.
+pub(crate) trait Handler {
+    type Output;
+
+    /// Waits for the next thing to send. Must be cancel-safe, as it races
+    /// the client's frames.
+    async fn next(&mut self) -> Self::Output;
+
+    async fn output(&mut self, socket: &mut Socket, output: Self::Output) -> Result<(), End>;
+
+    async fn text(&mut self, socket: &mut Socket, text: &str) -> Result<(), End>;
+}
+
+/// Runs a connection until either side ends it or the server drains:
+/// pings every `heartbeat`, answers pings, rejects binary frames and closes
+/// the connection on a client that stays silent or stops reading for the
+/// socket's timeout. Returns why it ended.
+pub(crate) async fn pump(
+    mut socket: Socket,
+    mut messages: MessageStream,
+    mut handler: impl Handler,
+    heartbeat: Duration,
+    shutdown: &Shutdown,
+) -> End {
+    let mut ticks = time::interval_at(time::Instant::now() + heartbeat, heartbeat);
+    let mut last_heard = Instant::now();
+
+    let end = loop {
+        let step = tokio::select! {
+            _ = ticks.tick() => {
+                if last_heard.elapsed() > socket.timeout {
+                    Err(End::Close(close(CloseCode::Policy, "No pong within the client timeout")))
+                } else {
+                    socket.ping().await
+                }
+            }
+            message = messages.recv() => {
+                last_heard = Instant::now();
+                match message {
+                    Some(Ok(message)) => receive(&mut socket, &mut handler, message).await,
+                    Some(Err(err)) => Err(End::Close(close(CloseCode::Protocol, &err.to_string()))),
+                    None => Err(End::Gone),
+                }
+            }
+            output = handler.next() => handler.output(&mut socket, output).await,
+            _ = shutdown.draining() => {
+                Err(End::Close(close(CloseCode::Away, "Server is shutting down")))
+            }
+        };
+        if let Err(end) = step {
+            break end;
+        }
+    };
+
+    if let End::Close(reason) = &end {
+        let _ = time::timeout(socket.timeout, socket.session.close(Some(reason.clone()))).await;
+    }
+    end
+}
+
+async fn receive(
+    socket: &mut Socket,
+    handler: &mut impl Handler,
+    message: Message,
+) -> Result<(), End> {
+    match message {
+        Message::Text(text) => handler.text(socket, &text).await,
+        Message::Ping(bytes) => socket.pong(&bytes).await,
+        Message::Binary(_) => Err(End::Close(close(
+            CloseCode::Unsupported,
+            "Only JSON text frames are accepted",
+        ))),
+        // The client is done; answer its close frame.
+        Message::Close(reason) => Err(End::Close(
+            reason.unwrap_or_else(|| CloseCode::Normal.into()),
+        )),
+        Message::Pong(_) | Message::Continuation(_) | Message::Nop => Ok(()),
+    }
+}
+
+/// Awaits a write, giving up on a client that doesn't read its frames.
+async fn deliver(
+    timeout: Duration,
+    send: impl Future<Output = Result<(), Closed>>,
+) -> Result<(), End> {
+    match time::timeout(timeout, send).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(Closed)) => Err(End::Gone),
+        Err(_) => Err(End::Close(close(
+            CloseCode::Policy,
+            "Could not deliver frames within the client timeout",
+        ))),
+    }
+}
+
+pub(crate) fn close(code: CloseCode, description: &str) -> CloseReason {
+    CloseReason {
+        code,
+        description: Some(description.to_string()),
+    }
+}
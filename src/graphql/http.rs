@@ -0,0 +1,71 @@
+//! `POST /graphql` and the explorer page at `GET /graphiql`.
+//!
+//! Requests are the usual JSON documents with `query`, `operationName` and
+//! `variables`, and need credentials like the REST routes; session callers
+//! also send `X-CSRF-Token`, since a request can mutate. Responses are
+//! `200` whenever the request could be executed, with any failures under
+//! `errors`.
+
+use actix_web::{web, HttpResponse};
+
+use crate::auth::Claims;
+use crate::{openapi, telemetry};
+
+use super::UserSchema;
+
+/// A GraphiQL-style page for writing and running queries against
+/// `/graphql`, with the schema's fields listed alongside. It has no external
+/// scripts or styles, so it works offline.
+///
+/// This is not GraphiQL itself. Unlike Swagger UI, no crate ships its
+/// built files (async-graphql's `GraphiQLSource` loads them from a CDN), so
+/// there is nothing to vendor without adding a JavaScript build. To switch,
+/// put GraphiQL's `graphiql.min.js`, `graphiql.min.css` and React bundles
+/// next to this file, serve them under `/graphiql/` and point the page at
+/// them.
+const GRAPHIQL: &str = include_str!("graphiql.html");
+
+/// Runs a GraphQL query or mutation.
+#[utoipa::path(
+    post,
+    path = "/graphql",
+    tag = "graphql",
+    request_body(
+        content = Object,
+        description = "`{\"query\": ..., \"operationName\": ..., \"variables\": {...}}`"
+    ),
+    responses(
+        (
+            status = 200,
+            description = "`data` and any `errors`; each error's `extensions` hold the problem document",
+            body = Object
+        ),
+        (status = 400, response = openapi::BadRequest),
+        (status = 401, response = openapi::Unauthorized),
+        (status = 403, response = openapi::Forbidden),
+        (status = 415, response = openapi::UnsupportedMediaType),
+    ),
+    security(
+        ("bearer" = ["users:read", "users:write"]),
+        ("api_key" = ["users:read", "users:write"]),
+        ("session" = [])
+    )
+)]
+pub async fn execute(
+    schema: web::Data<UserSchema>,
+    claims: Claims,
+    request: web::Json<async_graphql::Request>,
+) -> HttpResponse {
+    telemetry::in_span("graphql", async move {
+        let response = schema.execute(request.into_inner().data(claims)).await;
+        HttpResponse::Ok().json(response)
+    })
+    .await
+}
+
+/// `GET /graphiql`.
+pub async fn graphiql() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(GRAPHIQL)
+}
@@ -0,0 +1,281 @@
+//! The GraphQL API at `/graphql`, for clients that want to pick the fields
+//! they get.
+//!
+//! It is a second way into the same users: the resolvers use the
+//! repository, policy, validation and metrics of the REST handlers, and the
+//! mutations go through the same `*_user` functions in
+//! [`crate::handlers`]. Queries need the `users:read` scope and mutations
+//! `users:write`. Failures are reported as GraphQL errors whose
+//! `extensions` hold the problem document the REST routes would have
+//! returned, so clients can tell e.g. a `403` from a `422`.
+//!
+//! [`http`] serves queries and mutations and the explorer page, and [`ws`]
+//! serves subscriptions.
+
+pub mod http;
+pub mod ws;
+
+use std::sync::Arc;
+
+use actix_web::web;
+use async_graphql::{
+    Context, Error, ErrorExtensions, InputObject, MaybeUndefined, Object, Result, Schema,
+    SimpleObject, Subscription, Value,
+};
+use futures_util::stream::{self, Stream};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::auth::policy::{self, Action};
+use crate::auth::{Claims, USERS_READ, USERS_WRITE};
+use crate::error::{AppError, Challenge};
+use crate::events::{Events, Filter, UserEvent};
+use crate::handlers::{self, user_not_found};
+use crate::metrics::Metrics;
+use crate::models::{Info, ListParams, Role, User, UserUpdate};
+use crate::storage::UserRepository;
+
+/// Deepest selection a query may make. The schema is shallow, so anything
+/// deeper is a mistake or an attempt to make the server do needless work.
+const MAX_DEPTH: usize = 8;
+
+pub type UserSchema = Schema<Query, Mutation, Subscription>;
+
+/// The schema, resolving against the given state. The caller's [`Claims`]
+/// are added to each request.
+pub fn schema(
+    store: web::Data<dyn UserRepository>,
+    metrics: web::Data<Metrics>,
+    events: web::Data<Events>,
+) -> UserSchema {
+    Schema::build(Query, Mutation, Subscription)
+        .data(store)
+        .data(metrics)
+        .data(events)
+        .limit_depth(MAX_DEPTH)
+        .extension(ws::SubscriptionsOnly)
+        .finish()
+}
+
+/// The schema in GraphQL SDL.
+pub fn sdl() -> String {
+    Schema::build(Query, Mutation, Subscription).finish().sdl()
+}
+
+/// Reports an [`AppError`] as a GraphQL error: the problem's `detail` is the
+/// message and the rest of the problem goes in `extensions`.
+fn to_error(err: AppError) -> Error {
+    if let AppError::Storage(err) = &err {
+        log::error!("{}", err);
+    }
+    let mut problem = err.problem_json();
+    let detail = problem["detail"].as_str().unwrap_or_default().to_string();
+    let members = problem.as_object_mut().unwrap();
+    members.remove("detail");
+    Error::new(detail).extend_with(|_, extensions| {
+        for (name, value) in members.iter() {
+            extensions.set(name.as_str(), Value::from_json(value.clone()).unwrap());
+        }
+    })
+}
+
+/// Runs a resolver's body, reporting its failure with [`to_error`].
+fn resolve<T>(body: impl FnOnce() -> Result<T, AppError>) -> Result<T> {
+    body().map_err(to_error)
+}
+
+/// The caller, who must have `scope`.
+fn caller<'a>(ctx: &Context<'a>, scope: &str) -> Result<&'a Claims, AppError> {
+    let claims = ctx
+        .data::<Claims>()
+        .map_err(|_| AppError::Unauthorized(Challenge::Any, "Missing credentials".to_string()))?;
+    if !claims.has_scope(scope) {
+        return Err(AppError::Forbidden(format!("Missing scope {:?}", scope)));
+    }
+    Ok(claims)
+}
+
+fn store<'a>(ctx: &Context<'a>) -> &'a dyn UserRepository {
+    &***ctx.data_unchecked::<web::Data<dyn UserRepository>>()
+}
+
+fn metrics<'a>(ctx: &Context<'a>) -> &'a Metrics {
+    ctx.data_unchecked::<web::Data<Metrics>>()
+}
+
+/// Filters of the `users` query; see `GET /users`.
+#[derive(InputObject, Default)]
+pub struct UserFilterInput {
+    /// Case-insensitive (ASCII) substring of the name.
+    pub name_contains: Option<String>,
+    /// Case-insensitive (ASCII) substring of the email address.
+    pub email_contains: Option<String>,
+    pub min_age: Option<u8>,
+    pub max_age: Option<u8>,
+}
+
+/// One page of the `users` query.
+#[derive(SimpleObject)]
+#[graphql(name = "UserPage")]
+pub struct Page {
+    pub items: Vec<User>,
+    /// Number of users matching the filters across all pages.
+    pub total: u64,
+    /// Cursor to pass as `after` for the next page; null on the last one.
+    pub next: Option<String>,
+}
+
+/// Input of the `updateUser` mutation. Omitted fields are left as they are;
+/// `null` clears `age` or `email`.
+#[derive(InputObject)]
+pub struct UpdateUserInput {
+    pub name: Option<String>,
+    pub age: MaybeUndefined<u8>,
+    pub email: MaybeUndefined<String>,
+    pub role: Option<Role>,
+}
+
+impl UpdateUserInput {
+    /// `user` with these changes, as a [`UserUpdate`] to validate and store.
+    fn apply(self, user: User) -> UserUpdate {
+        let mut age = user.age;
+        self.age.update_to(&mut age);
+        let mut email = user.email;
+        self.email.update_to(&mut email);
+        UserUpdate {
+            id: None,
+            name: self.name.unwrap_or(user.name),
+            age,
+            email,
+            role: self.role,
+        }
+    }
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    /// The user with this id, or null if there is none.
+    async fn user(&self, ctx: &Context<'_>, id: u32) -> Result<Option<User>> {
+        resolve(|| {
+            let claims = caller(ctx, USERS_READ)?;
+            policy::authorize(claims.subject(), Action::Read, Some(id))?;
+            Ok(store(ctx).get(id)?)
+        })
+    }
+
+    /// Users a page at a time, as `GET /users` lists them.
+    async fn users(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(desc = "Page size, from 1 to 100; 20 if absent")] limit: Option<usize>,
+        offset: Option<usize>,
+        #[graphql(desc = "The `next` cursor of the previous page")] after: Option<String>,
+        #[graphql(
+            desc = "Comma-separated fields to order by, each descending if prefixed with `-`"
+        )]
+        sort: Option<String>,
+        filter: Option<UserFilterInput>,
+    ) -> Result<Page> {
+        resolve(|| {
+            let claims = caller(ctx, USERS_READ)?;
+            policy::authorize(claims.subject(), Action::List, None)?;
+            let filter = filter.unwrap_or_default();
+            let params = ListParams {
+                limit,
+                offset,
+                after,
+                sort,
+                name_contains: filter.name_contains,
+                email_contains: filter.email_contains,
+                min_age: filter.min_age,
+                max_age: filter.max_age,
+            };
+            let query = params.to_query().map_err(AppError::InvalidQuery)?;
+            let page = store(ctx).search(&query)?;
+            Ok(Page {
+                items: page.items,
+                total: page.total,
+                next: page.next.map(|cursor| cursor.encode(&query.sort)),
+            })
+        })
+    }
+}
+
+pub struct Mutation;
+
+#[Object]
+impl Mutation {
+    /// Creates a user, as `POST /users` does.
+    async fn create_user(&self, ctx: &Context<'_>, input: Info) -> Result<User> {
+        resolve(|| {
+            let claims = caller(ctx, USERS_WRITE)?;
+            handlers::create_user(store(ctx), metrics(ctx), claims, input)
+        })
+    }
+
+    /// Changes some of a user's fields, as `PATCH /users/{id}` does.
+    async fn update_user(
+        &self,
+        ctx: &Context<'_>,
+        id: u32,
+        input: UpdateUserInput,
+    ) -> Result<User> {
+        resolve(|| {
+            let claims = caller(ctx, USERS_WRITE)?;
+            policy::authorize(claims.subject(), Action::Update, Some(id))?;
+            let user = store(ctx).get(id)?.ok_or_else(|| user_not_found(id))?;
+            handlers::replace_user(store(ctx), metrics(ctx), claims, id, input.apply(user))
+        })
+    }
+
+    /// Deletes a user, as `DELETE /users/{id}` does, and returns its id.
+    async fn delete_user(&self, ctx: &Context<'_>, id: u32) -> Result<u32> {
+        resolve(|| {
+            let claims = caller(ctx, USERS_WRITE)?;
+            handlers::remove_user(store(ctx), metrics(ctx), claims, id)?;
+            Ok(id)
+        })
+    }
+}
+
+pub struct Subscription;
+
+#[Subscription]
+impl Subscription {
+    /// Changes to users as they happen, as `/ws/users` streams them. Viewers
+    /// must pass their own `userId`. A subscriber that falls
+    /// `events.buffer` events behind gets an error and should subscribe
+    /// again.
+    async fn user_changed(
+        &self,
+        ctx: &Context<'_>,
+        user_id: Option<u32>,
+    ) -> Result<impl Stream<Item = Result<Arc<UserEvent>>>> {
+        let filter = Filter { user_id };
+        let receiver = resolve(|| {
+            filter.authorize(caller(ctx, USERS_READ)?)?;
+            Ok(ctx.data_unchecked::<web::Data<Events>>().subscribe())
+        })?;
+
+        Ok(stream::unfold(Some(receiver), move |receiver| async move {
+            let mut receiver = receiver?;
+            loop {
+                match receiver.recv().await {
+                    Ok(event) if filter.matches(&event) => {
+                        return Some((Ok(event), Some(receiver)))
+                    }
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(missed)) => {
+                        let err = Error::new(format!(
+                            "Fell {} events behind; subscribe again to resume",
+                            missed
+                        ));
+                        return Some((Err(err), None));
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        }))
+    }
+}
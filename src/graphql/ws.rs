@@ -0,0 +1,187 @@
+//! `GET /graphql` upgraded to a WebSocket: GraphQL subscriptions, over the
+//! `graphql-transport-ws` protocol or the older `graphql-ws` one, whichever
+//! the client offers.
+//!
+//! The upgrade request is authenticated, and the caller's credentials apply
+//! to every operation on the connection. An upgrade is a GET, so it needs no
+//! CSRF token; instead, session upgrades must come from an allowed `Origin`
+//! and only subscriptions may be sent, so a connection can't change users.
+//! Queries and mutations go to `POST /graphql`. Pings, timeouts and shutdown work
+//! as on `/ws/users`, whose `pump` runs both kinds of connection.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::rt;
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_ws::CloseCode;
+use async_graphql::extensions::{
+    Extension, ExtensionContext, ExtensionFactory, NextPrepareRequest,
+};
+use async_graphql::http::{WebSocket, WebSocketProtocols, WsMessage};
+use async_graphql::parser::types::{DocumentOperations, OperationType};
+use async_graphql::{Data, Request, ServerError, ServerResult};
+use futures_util::{stream, Stream, StreamExt};
+use tokio::sync::mpsc;
+
+use crate::auth::{self, Claims};
+use crate::events::ws::{close, pump, End, Handler, Socket};
+use crate::events::Events;
+use crate::openapi;
+use crate::shutdown::Shutdown;
+
+use super::UserSchema;
+
+/// Largest frame a client may send.
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// Client messages waiting to be handled; a client that sends more without
+/// waiting for answers is disconnected.
+const QUEUE: usize = 16;
+
+/// Opens a connection for GraphQL subscriptions; see the module docs.
+#[utoipa::path(
+    get,
+    path = "/graphql",
+    tag = "graphql",
+    params((
+        "Sec-WebSocket-Protocol" = String,
+        Header,
+        description = "`graphql-transport-ws` or `graphql-ws`"
+    )),
+    responses(
+        (status = 101, description = "Switched to the WebSocket protocol"),
+        (status = 400, response = openapi::BadRequest),
+        (status = 401, response = openapi::Unauthorized),
+    ),
+    security(("bearer" = ["users:read"]), ("api_key" = ["users:read"]), ("session" = []))
+)]
+pub async fn subscriptions(
+    req: HttpRequest,
+    body: web::Payload,
+    claims: Claims,
+    schema: web::Data<UserSchema>,
+    events: web::Data<Events>,
+    shutdown: web::Data<Shutdown>,
+) -> Result<HttpResponse, actix_web::Error> {
+    auth::check_origin(&req, &claims)?;
+    // Clients list the protocols they speak; the first we know wins.
+    let protocol = req
+        .headers()
+        .get(header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| {
+            value
+                .split(',')
+                .find_map(|protocol| protocol.trim().parse::<WebSocketProtocols>().ok())
+        })
+        .unwrap_or(WebSocketProtocols::GraphQLWS);
+    let (mut response, session, messages) = actix_ws::handle(&req, body)?;
+    response.headers_mut().insert(
+        header::SEC_WEBSOCKET_PROTOCOL,
+        HeaderValue::from_static(protocol.sec_websocket_protocol()),
+    );
+
+    let mut data = Data::default();
+    data.insert(claims);
+    data.insert(OverWebSocket);
+    let (sender, receiver) = mpsc::channel(QUEUE);
+    let graphql = WebSocket::new(
+        UserSchema::clone(&schema),
+        stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|text: String| (text, receiver))
+        }),
+        protocol,
+    )
+    .connection_data(data);
+
+    let connection = Connection {
+        sender,
+        graphql: Box::pin(graphql),
+    };
+    let socket = Socket::new(session, Duration::from_secs(events.config().client_timeout));
+    let heartbeat = Duration::from_secs(events.config().heartbeat);
+    rt::spawn(async move {
+        let messages = messages.max_frame_size(MAX_FRAME_SIZE);
+        pump(socket, messages, connection, heartbeat, &shutdown).await;
+    });
+    Ok(response)
+}
+
+struct Connection<S> {
+    /// Client messages for the GraphQL side.
+    sender: mpsc::Sender<String>,
+    /// Its replies.
+    graphql: S,
+}
+
+impl<S: Stream<Item = WsMessage> + Unpin> Handler for Connection<S> {
+    type Output = Option<WsMessage>;
+
+    async fn next(&mut self) -> Self::Output {
+        self.graphql.next().await
+    }
+
+    async fn output(&mut self, socket: &mut Socket, reply: Self::Output) -> Result<(), End> {
+        match reply {
+            Some(WsMessage::Text(text)) => socket.text(text).await,
+            Some(WsMessage::Close(code, reason)) => Err(End::Close(close(code.into(), &reason))),
+            None => Err(End::Close(CloseCode::Normal.into())),
+        }
+    }
+
+    async fn text(&mut self, _: &mut Socket, text: &str) -> Result<(), End> {
+        self.sender
+            .try_send(text.to_string())
+            .map_err(|_| End::Close(close(CloseCode::Policy, "Too many messages in flight")))
+    }
+}
+
+/// Connection data marking operations as sent over a WebSocket.
+struct OverWebSocket;
+
+/// Refuses queries and mutations sent over a WebSocket; see the module docs.
+pub(super) struct SubscriptionsOnly;
+
+impl ExtensionFactory for SubscriptionsOnly {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(SubscriptionsOnly)
+    }
+}
+
+#[async_graphql::async_trait::async_trait]
+impl Extension for SubscriptionsOnly {
+    async fn prepare_request(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        request: Request,
+        next: NextPrepareRequest<'_>,
+    ) -> ServerResult<Request> {
+        if ctx.data_opt::<OverWebSocket>().is_some() && !is_subscription(&request) {
+            return Err(ServerError::new(
+                "Only subscriptions may be sent over a WebSocket; send queries and mutations to POST /graphql",
+                None,
+            ));
+        }
+        next.run(ctx, request).await
+    }
+}
+
+/// Whether the operation `request` runs is a subscription. Documents that
+/// don't parse or don't name one operation count as subscriptions here and
+/// fail with the usual errors later.
+fn is_subscription(request: &Request) -> bool {
+    let Ok(document) = async_graphql::parser::parse_query(&request.query) else {
+        return true;
+    };
+    let operation = match (&document.operations, &request.operation_name) {
+        (DocumentOperations::Single(operation), _) => Some(operation),
+        (DocumentOperations::Multiple(operations), Some(name)) => operations.get(name.as_str()),
+        (DocumentOperations::Multiple(operations), None) if operations.len() == 1 => {
+            operations.values().next()
+        }
+        (DocumentOperations::Multiple(_), None) => None,
+    };
+    operation.is_none_or(|operation| operation.node.ty == OperationType::Subscription)
+}
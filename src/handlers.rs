@@ -0,0 +1,388 @@
+//! Handlers for `/` and the `/users` routes. Accounts, API keys and the
+//! operational routes have their handlers next to the rest of their code.
+//!
+//! The checks and side effects of each change live in the `*_user`
+//! functions, which the GraphQL mutations call too.
+
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde_json::Value;
+use validator::Validate;
+
+use crate::auth::policy::{self, Action};
+use crate::auth::Claims;
+use crate::config::AuthConfig;
+use crate::content::{Body, Format};
+use crate::error::AppError;
+use crate::metrics::Metrics;
+use crate::models::{Info, ListParams, Role, User, UserList, UserUpdate};
+use crate::storage::UserRepository;
+use crate::{openapi, telemetry};
+
+/// Applies a JSON Merge Patch (RFC 7396) to `target` in place.
+fn merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Default::default());
+    }
+    let target = target.as_object_mut().unwrap();
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+type Store = web::Data<dyn UserRepository>;
+
+/// Name of the `/users/{id}` resource, for building links to users.
+pub const USER_RESOURCE: &str = "user";
+
+/// Path of user `id`, including whatever scope the routes are mounted in.
+pub fn user_path(req: &HttpRequest, id: u32) -> String {
+    req.url_for(USER_RESOURCE, [id.to_string()])
+        .map(|url| url.path().to_string())
+        .unwrap_or_else(|_| format!("/users/{}", id))
+}
+
+pub(crate) fn user_not_found(user_id: u32) -> AppError {
+    AppError::NotFound(format!("User {} not found", user_id))
+}
+
+/// Checks that the caller may give `role` to a user who has `current`,
+/// which is always allowed if it changes nothing.
+fn authorize_role(claims: &Claims, role: Option<Role>, current: Role) -> Result<(), AppError> {
+    match role {
+        Some(role) if role != current => {
+            policy::authorize(claims.subject(), Action::AssignRole, None)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Creates a user for `claims`, as `POST /users` does.
+pub(crate) fn create_user(
+    store: &dyn UserRepository,
+    metrics: &Metrics,
+    claims: &Claims,
+    info: Info,
+) -> Result<User, AppError> {
+    policy::authorize(claims.subject(), Action::Create, None)?;
+    authorize_role(claims, info.role, Role::default())?;
+    info.validate()?;
+    let user = store.create(info)?;
+    metrics.users_created.inc();
+    Ok(user)
+}
+
+/// Replaces a user's fields. The caller must already be allowed to update
+/// the user; changing its role is checked here.
+pub(crate) fn replace_user(
+    store: &dyn UserRepository,
+    metrics: &Metrics,
+    claims: &Claims,
+    user_id: u32,
+    update: UserUpdate,
+) -> Result<User, AppError> {
+    if update.id.is_some_and(|id| id != user_id) {
+        return Err(AppError::Conflict(format!(
+            "Body id does not match user {}",
+            user_id
+        )));
+    }
+    update.validate()?;
+    if update.role.is_some() {
+        let current = store.get(user_id)?.ok_or_else(|| user_not_found(user_id))?;
+        authorize_role(claims, update.role, current.role)?;
+    }
+    let user = store
+        .replace(user_id, update)?
+        .ok_or_else(|| user_not_found(user_id))?;
+    metrics.users_updated.inc();
+    Ok(user)
+}
+
+/// Deletes a user for `claims`, as `DELETE /users/{id}` does.
+pub(crate) fn remove_user(
+    store: &dyn UserRepository,
+    metrics: &Metrics,
+    claims: &Claims,
+    user_id: u32,
+) -> Result<(), AppError> {
+    policy::authorize(claims.subject(), Action::Delete, Some(user_id))?;
+    if !store.delete(user_id)? {
+        return Err(user_not_found(user_id));
+    }
+    metrics.users_deleted.inc();
+    Ok(())
+}
+
+/// Lists users a page at a time.
+#[utoipa::path(
+    get,
+    path = "/users",
+    tag = "users",
+    params(ListParams),
+    responses(
+        (
+            status = 200,
+            description = "One page of users",
+            body = UserList,
+            headers(("Link" = String, description = "`first` and `next` page links (RFC 8288)"))
+        ),
+        (status = 400, response = openapi::BadRequest),
+        (status = 401, response = openapi::Unauthorized),
+        (status = 403, response = openapi::Forbidden),
+        (status = 406, response = openapi::NotAcceptable),
+    ),
+    security(("bearer" = ["users:read"]), ("api_key" = ["users:read"]), ("session" = []))
+)]
+pub async fn get_json_data(
+    store: Store,
+    req: HttpRequest,
+    claims: Claims,
+    format: Format,
+    params: web::Query<ListParams>,
+) -> Result<HttpResponse, AppError> {
+    telemetry::in_span("get_json_data", async move {
+        policy::authorize(claims.subject(), Action::List, None)?;
+        let query = params.to_query().map_err(AppError::InvalidQuery)?;
+        let page = store.search(&query)?;
+
+        let link = |params: ListParams| {
+            let query_string = serde_urlencoded::to_string(params).unwrap();
+            if query_string.is_empty() {
+                req.path().to_string()
+            } else {
+                format!("{}?{}", req.path(), query_string)
+            }
+        };
+        let next = page
+            .next
+            .map(|cursor| link(params.after(cursor.encode(&query.sort))));
+
+        // RFC 8288 web links, as used by e.g. the GitHub API.
+        let mut links = vec![format!("<{}>; rel=\"first\"", link(params.first()))];
+        if let Some(next) = &next {
+            links.push(format!("<{}>; rel=\"next\"", next));
+        }
+
+        let mut response = HttpResponse::Ok();
+        response.insert_header((header::LINK, links.join(", ")));
+        Ok(format.respond(
+            response,
+            &UserList {
+                items: page.items,
+                total: page.total,
+                next,
+            },
+        ))
+    })
+    .await
+}
+
+/// Creates a user.
+#[utoipa::path(
+    post,
+    path = "/users",
+    tag = "users",
+    request_body = Info,
+    responses(
+        (
+            status = 201,
+            description = "The new user",
+            body = User,
+            headers(("Location" = String, description = "Path of the new user"))
+        ),
+        (status = 400, response = openapi::BadRequest),
+        (status = 401, response = openapi::Unauthorized),
+        (status = 403, response = openapi::Forbidden),
+        (status = 406, response = openapi::NotAcceptable),
+        (status = 415, response = openapi::UnsupportedMediaType),
+        (status = 422, response = openapi::ValidationFailed),
+    ),
+    security(("bearer" = ["users:write"]), ("api_key" = ["users:write"]), ("session" = []))
+)]
+pub async fn post_json(
+    store: Store,
+    req: HttpRequest,
+    metrics: web::Data<Metrics>,
+    claims: Claims,
+    format: Format,
+    info: Body<Info>,
+) -> Result<HttpResponse, AppError> {
+    telemetry::in_span("post_json", async move {
+        let user = create_user(&**store, &metrics, &claims, info.into_inner())?;
+        let mut response = HttpResponse::Created();
+        response.insert_header((header::LOCATION, user_path(&req, user.id)));
+        Ok(format.respond(response, &user))
+    })
+    .await
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/{id}",
+    tag = "users",
+    params(("id" = u32, Path, description = "User id")),
+    responses(
+        (status = 200, description = "The user", body = User),
+        (status = 400, response = openapi::BadRequest),
+        (status = 401, response = openapi::Unauthorized),
+        (status = 403, response = openapi::Forbidden),
+        (status = 404, response = openapi::NotFound),
+        (status = 406, response = openapi::NotAcceptable),
+    ),
+    security(("bearer" = ["users:read"]), ("api_key" = ["users:read"]), ("session" = []))
+)]
+pub async fn get_user(
+    store: Store,
+    claims: Claims,
+    format: Format,
+    path: web::Path<(u32,)>,
+) -> Result<HttpResponse, AppError> {
+    telemetry::in_span("get_user", async move {
+        let user_id = path.into_inner().0;
+        policy::authorize(claims.subject(), Action::Read, Some(user_id))?;
+        let user = store.get(user_id)?.ok_or_else(|| user_not_found(user_id))?;
+        Ok(format.respond(HttpResponse::Ok(), &user))
+    })
+    .await
+}
+
+/// Replaces a user's fields.
+#[utoipa::path(
+    put,
+    path = "/users/{id}",
+    tag = "users",
+    params(("id" = u32, Path, description = "User id")),
+    request_body = UserUpdate,
+    responses(
+        (status = 200, description = "The updated user", body = User),
+        (status = 400, response = openapi::BadRequest),
+        (status = 401, response = openapi::Unauthorized),
+        (status = 403, response = openapi::Forbidden),
+        (status = 404, response = openapi::NotFound),
+        (status = 409, response = openapi::Conflict),
+        (status = 406, response = openapi::NotAcceptable),
+        (status = 415, response = openapi::UnsupportedMediaType),
+        (status = 422, response = openapi::ValidationFailed),
+    ),
+    security(("bearer" = ["users:write"]), ("api_key" = ["users:write"]), ("session" = []))
+)]
+pub async fn put_user(
+    store: Store,
+    metrics: web::Data<Metrics>,
+    claims: Claims,
+    format: Format,
+    path: web::Path<(u32,)>,
+    update: Body<UserUpdate>,
+) -> Result<HttpResponse, AppError> {
+    telemetry::in_span("put_user", async move {
+        let user_id = path.into_inner().0;
+        policy::authorize(claims.subject(), Action::Update, Some(user_id))?;
+        let user = replace_user(&**store, &metrics, &claims, user_id, update.into_inner())?;
+        Ok(format.respond(HttpResponse::Ok(), &user))
+    })
+    .await
+}
+
+/// Applies a JSON Merge Patch (RFC 7396) to a user.
+#[utoipa::path(
+    patch,
+    path = "/users/{id}",
+    tag = "users",
+    params(("id" = u32, Path, description = "User id")),
+    request_body(
+        content = Object,
+        content_type = "application/merge-patch+json",
+        description = "Fields to change; `null` clears a field"
+    ),
+    responses(
+        (status = 200, description = "The updated user", body = User),
+        (status = 400, response = openapi::BadRequest),
+        (status = 401, response = openapi::Unauthorized),
+        (status = 403, response = openapi::Forbidden),
+        (status = 404, response = openapi::NotFound),
+        (status = 409, response = openapi::Conflict),
+        (status = 406, response = openapi::NotAcceptable),
+        (status = 415, response = openapi::UnsupportedMediaType),
+        (status = 422, response = openapi::ValidationFailed),
+    ),
+    security(("bearer" = ["users:write"]), ("api_key" = ["users:write"]), ("session" = []))
+)]
+pub async fn patch_user(
+    store: Store,
+    metrics: web::Data<Metrics>,
+    claims: Claims,
+    format: Format,
+    path: web::Path<(u32,)>,
+    patch: Body<Value>,
+) -> Result<HttpResponse, AppError> {
+    telemetry::in_span("patch_user", async move {
+        let user_id = path.into_inner().0;
+        policy::authorize(claims.subject(), Action::Update, Some(user_id))?;
+        let user = store.get(user_id)?.ok_or_else(|| user_not_found(user_id))?;
+
+        let mut document = serde_json::to_value(user).unwrap();
+        merge_patch(&mut document, &patch);
+        let update = serde_json::from_value(document)
+            .map_err(|err| AppError::InvalidBody(format!("Patched user is invalid: {}", err)))?;
+        let user = replace_user(&**store, &metrics, &claims, user_id, update)?;
+        Ok(format.respond(HttpResponse::Ok(), &user))
+    })
+    .await
+}
+
+#[utoipa::path(
+    delete,
+    path = "/users/{id}",
+    tag = "users",
+    params(("id" = u32, Path, description = "User id")),
+    responses(
+        (status = 204, description = "The user was deleted"),
+        (status = 400, response = openapi::BadRequest),
+        (status = 401, response = openapi::Unauthorized),
+        (status = 403, response = openapi::Forbidden),
+        (status = 404, response = openapi::NotFound),
+    ),
+    security(("bearer" = ["users:write"]), ("api_key" = ["users:write"]), ("session" = []))
+)]
+pub async fn delete_user(
+    store: Store,
+    metrics: web::Data<Metrics>,
+    claims: Claims,
+    path: web::Path<(u32,)>,
+) -> Result<HttpResponse, AppError> {
+    telemetry::in_span("delete_user", async move {
+        remove_user(&**store, &metrics, &claims, path.into_inner().0)?;
+        Ok(HttpResponse::NoContent().finish())
+    })
+    .await
+}
+
+/// A greeting; needs credentials unless `auth.anonymous_root` is set.
+#[utoipa::path(
+    get,
+    path = "/",
+    tag = "operations",
+    responses(
+        (status = 200, description = "Always `Hello World!`", body = String),
+        (status = 401, response = openapi::Unauthorized),
+    ),
+    security((), ("bearer" = []), ("api_key" = []), ("session" = []))
+)]
+pub async fn index(
+    auth: web::Data<AuthConfig>,
+    claims: Result<Claims, AppError>,
+) -> Result<HttpResponse, AppError> {
+    if !auth.anonymous_root {
+        claims?;
+    }
+    Ok(HttpResponse::Ok().body("Hello World!"))
+}
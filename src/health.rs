@@ -0,0 +1,156 @@
+//! Probes for load balancers and orchestrators.
+//!
+//! - `GET /healthz`: the process is up and serving HTTP.
+//! - `GET /readyz`: the instance should receive traffic: storage answers,
+//!   migrations are applied and no shutdown is in progress. `503` otherwise.
+//! - `GET /health/details`: every check with its latency, plus build info.
+
+use std::sync::LazyLock;
+use std::time::Instant;
+
+use actix_web::{web, HttpResponse};
+use chrono::DateTime;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::error::AppError;
+use crate::openapi;
+use crate::shutdown::Shutdown;
+use crate::storage::UserRepository;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_HASH: &str = env!("GIT_HASH");
+const BUILD_TIMESTAMP: &str = env!("BUILD_TIMESTAMP");
+
+static STARTED: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+/// Starts the uptime clock; call once at startup.
+pub fn mark_started() {
+    LazyLock::force(&STARTED);
+}
+
+#[derive(Serialize, ToSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pass,
+    Fail,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Check {
+    /// Times `probe`, which returns a failure reason or `None` if healthy.
+    fn run(name: &'static str, probe: impl FnOnce() -> Option<String>) -> Self {
+        let started = Instant::now();
+        let detail = probe();
+        Check {
+            name,
+            status: if detail.is_none() {
+                Status::Pass
+            } else {
+                Status::Fail
+            },
+            latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+            detail,
+        }
+    }
+}
+
+fn readiness_checks(store: &dyn UserRepository, shutdown: &Shutdown) -> Vec<Check> {
+    vec![
+        Check::run("storage", || store.ping().err().map(|err| err.to_string())),
+        Check::run("migrations", || match store.pending_migrations() {
+            Ok(0) => None,
+            Ok(pending) => Some(format!("{} migration(s) pending", pending)),
+            Err(err) => Some(err.to_string()),
+        }),
+        Check::run("shutdown", || {
+            shutdown
+                .is_draining()
+                .then(|| "draining before shutdown".to_string())
+        }),
+    ]
+}
+
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "operations",
+    responses((status = 200, description = "The process is up", body = Object, example = json!({ "status": "pass" })))
+)]
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "pass" }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "operations",
+    responses(
+        (status = 200, description = "Every check passed", body = Object, example = json!({ "status": "pass" })),
+        (status = 503, response = openapi::Unavailable),
+    )
+)]
+pub async fn readyz(
+    store: web::Data<dyn UserRepository>,
+    shutdown: web::Data<Shutdown>,
+) -> Result<HttpResponse, AppError> {
+    let failures: Vec<String> = readiness_checks(&**store, &shutdown)
+        .into_iter()
+        .filter_map(|check| Some(format!("{}: {}", check.name, check.detail?)))
+        .collect();
+    if !failures.is_empty() {
+        return Err(AppError::Unavailable(failures.join("; ")));
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "pass" })))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Details {
+    pub status: Status,
+    pub version: &'static str,
+    pub git_hash: &'static str,
+    pub build_time: String,
+    pub uptime_seconds: u64,
+    pub checks: Vec<Check>,
+}
+
+/// Always `200`; the overall `status` says whether every check passed.
+#[utoipa::path(
+    get,
+    path = "/health/details",
+    tag = "operations",
+    responses((status = 200, description = "Every check, with build info", body = Details))
+)]
+pub async fn details(
+    store: web::Data<dyn UserRepository>,
+    shutdown: web::Data<Shutdown>,
+) -> HttpResponse {
+    let checks = readiness_checks(&**store, &shutdown);
+    let status = if checks.iter().all(|check| check.status == Status::Pass) {
+        Status::Pass
+    } else {
+        Status::Fail
+    };
+    let build_time = BUILD_TIMESTAMP
+        .parse()
+        .ok()
+        .and_then(|secs| DateTime::from_timestamp(secs, 0))
+        .map_or_else(|| "unknown".to_string(), |time| time.to_rfc3339());
+
+    HttpResponse::Ok().json(Details {
+        status,
+        version: VERSION,
+        git_hash: GIT_HASH,
+        build_time,
+        uptime_seconds: STARTED.elapsed().as_secs(),
+        checks,
+    })
+}
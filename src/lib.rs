@@ -0,0 +1,31 @@
+//! A users API on actix-web: CRUD over pluggable storage, also served as
+//! GraphQL, with JWT, API key and session authentication, roles, rate
+//! limiting, metrics and tracing.
+//!
+//! [`build_app`] returns an app with the [`AppState`] and middleware in place
+//! and [`configure`] adds the routes, so the API can be served on its own or
+//! mounted in a scope of a larger server. The `actix-web-app` binary does the
+//! former.
+
+pub mod admin;
+mod app;
+pub mod auth;
+pub mod config;
+pub mod content;
+pub mod error;
+pub mod events;
+pub mod graphql;
+pub mod handlers;
+pub mod health;
+pub mod logging;
+pub mod metrics;
+pub mod middleware;
+pub mod models;
+pub mod openapi;
+pub mod rate_limit;
+pub mod request_id;
+pub mod shutdown;
+pub mod storage;
+pub mod telemetry;
+
+pub use app::{build_app, configure, AppState};
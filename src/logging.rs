@@ -0,0 +1,167 @@
+//! Logging setup and the access log.
+//!
+//! With the default `json` format every log line is one JSON object carrying
+//! `timestamp`, `level`, `target` and `message`, any structured fields the
+//! call site attached (`log::info!(status = 200; "...")`), and, while a
+//! request is being handled, its `request_id`, `trace_id`, `method` and
+//! `route`. The `text` format is meant for reading logs in a terminal.
+
+use std::io::Write;
+use std::time::Instant;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpMessage};
+use chrono::{SecondsFormat, Utc};
+use log::kv::{self, Key, VisitSource};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::auth::Claims;
+use crate::request_id::RequestContext;
+use crate::telemetry;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Json,
+    Text,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(LogFormat::Json),
+            "text" => Ok(LogFormat::Text),
+            _ => Err(format!("{:?} is not one of json, text", s)),
+        }
+    }
+}
+
+/// Installs the global logger. Call once, before anything logs.
+pub fn init(filter: &str, format: LogFormat) {
+    let mut builder = env_logger::Builder::new();
+    builder.parse_filters(filter);
+    match format {
+        LogFormat::Json => builder.format(|buf, record| {
+            let line = json_line(record);
+            writeln!(buf, "{}", Value::Object(line))
+        }),
+        LogFormat::Text => builder.format(|buf, record| {
+            let mut fields = Fields::default();
+            record.key_values().visit(&mut fields).ok();
+            write!(
+                buf,
+                "{} {:<5} {}",
+                Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+                record.level(),
+                record.target()
+            )?;
+            if let Some(context) = RequestContext::current() {
+                write!(buf, " [{}]", context.id)?;
+            }
+            write!(buf, " {}", record.args())?;
+            for (key, value) in fields.0 {
+                write!(buf, " {}={}", key, value)?;
+            }
+            writeln!(buf)
+        }),
+    };
+    builder.init();
+}
+
+fn json_line(record: &log::Record) -> Map<String, Value> {
+    let mut line = Map::new();
+    line.insert(
+        "timestamp".into(),
+        Utc::now()
+            .to_rfc3339_opts(SecondsFormat::Millis, true)
+            .into(),
+    );
+    line.insert("level".into(), record.level().as_str().into());
+    line.insert("target".into(), record.target().into());
+    line.insert("message".into(), record.args().to_string().into());
+    if let Some(context) = RequestContext::current() {
+        line.insert("request_id".into(), context.id.as_str().into());
+        line.insert("method".into(), (*context.method).into());
+        if let Some(route) = context.route {
+            line.insert("route".into(), (*route).into());
+        }
+    }
+    if let Some(trace_id) = telemetry::current_trace_id() {
+        line.insert("trace_id".into(), trace_id.to_string().into());
+    }
+    let mut fields = Fields::default();
+    record.key_values().visit(&mut fields).ok();
+    line.extend(fields.0);
+    line
+}
+
+/// Structured fields of one record, in the order they were given.
+#[derive(Default)]
+struct Fields(Vec<(String, Value)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(n) = value.to_u64() {
+            n.into()
+        } else if let Some(n) = value.to_i64() {
+            n.into()
+        } else if let Some(b) = value.to_bool() {
+            b.into()
+        } else if let Some(n) = value.to_f64() {
+            n.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.push((key.as_str().to_string(), value));
+        Ok(())
+    }
+}
+
+/// Middleware writing one access log line per request. Must run inside
+/// [`request_id::assign`](crate::request_id::assign) so the line carries the
+/// request's id.
+pub async fn access_log(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let method = req.method().clone();
+    let path = req.path().to_string();
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("-")
+        .to_string();
+    let started = Instant::now();
+    let res = next.call(req).await;
+
+    let status = match &res {
+        Ok(res) => res.status(),
+        Err(err) => err.as_response_error().status_code(),
+    };
+    let sub = res
+        .as_ref()
+        .ok()
+        .and_then(|res| Some(res.request().extensions().get::<Claims>()?.sub.clone()));
+    let latency_ms = (started.elapsed().as_secs_f64() * 1e6).round() / 1000.0;
+    log::info!(
+        target: "access",
+        path = path.as_str(),
+        status = status.as_u16(),
+        latency_ms = latency_ms,
+        user_agent = user_agent.as_str(),
+        sub = sub.as_deref().unwrap_or("-");
+        "{} {} {}",
+        method,
+        path,
+        status.as_u16()
+    );
+    res
+}
@@ -1,48 +1,145 @@
-use actix_web::{web, App, HttpResponse, HttpServer};
-use actix_web::middleware::Logger;
-use serde::{Deserialize, Serialize};
+use actix_web::HttpServer;
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
 
-#[derive(Deserialize)]
-struct Info {
-    name: String,
+use actix_web_app::config::{Config, ConfigArgs};
+use actix_web_app::models::Role;
+use actix_web_app::{build_app, configure, health, logging, openapi, storage, telemetry, AppState};
+
+mod cli;
+
+/// Users API server.
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(flatten)]
+    config: ConfigArgs,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[derive(Serialize)]
-struct Person {
-    name: String,
-    age: String,
+#[derive(Subcommand)]
+enum Command {
+    /// Start the HTTP server (the default when no subcommand is given)
+    Serve,
+    /// Apply pending storage migrations and exit
+    Migrate,
+    /// Create randomly generated users
+    Seed {
+        /// How many users to create
+        #[arg(long, short = 'n', default_value_t = 10)]
+        count: usize,
+    },
+    /// Write every user as a JSON array
+    Export {
+        /// File to write to instead of stdout
+        #[arg(long, short, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+    /// Load users from a JSON export, keeping their ids and timestamps
+    Import {
+        /// File written by `export`
+        #[arg(value_name = "PATH")]
+        input: PathBuf,
+    },
+    /// Print the resolved configuration; exits non-zero if it is invalid
+    CheckConfig,
+    /// Print a signed bearer token for trying out the API
+    Token {
+        /// Subject (`sub` claim)
+        #[arg(long, default_value = "dev")]
+        sub: String,
+        /// Scope to grant; repeat for several
+        #[arg(long = "scope", value_name = "SCOPE", default_values_t = [String::from("users:read"), String::from("users:write")])]
+        scopes: Vec<String>,
+        /// Role (`role` claim)
+        #[arg(long, default_value = "admin")]
+        role: Role,
+        /// Lifetime in seconds
+        #[arg(long, default_value_t = 3600)]
+        ttl: u64,
+    },
+    /// Print the OpenAPI spec served at /openapi.json
+    Openapi,
+    /// Give a user a role, e.g. to make the first admin
+    SetRole {
+        /// User id
+        id: u32,
+        /// admin, editor or viewer
+        role: Role,
+    },
 }
 
-async fn get_json_data() -> HttpResponse {
-    let response = Person {
-        name: "Good!".to_string(),
-        age: "21".to_string(),
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let cli = Cli::parse();
+    let config = match Config::load(&cli.config) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            std::process::exit(2);
+        }
     };
+    logging::init(&config.log_level, config.log_format);
 
-    HttpResponse::Ok().json(response)
+    let result = match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => return serve(config).await,
+        Command::Migrate => cli::migrate(&config),
+        Command::Seed { count } => cli::seed(&config, count),
+        Command::Export { output } => cli::export(&config, output.as_deref()),
+        Command::Import { input } => cli::import(&config, &input),
+        Command::CheckConfig => cli::check_config(&config),
+        Command::Token {
+            sub,
+            scopes,
+            role,
+            ttl,
+        } => cli::token(&config, &sub, &scopes, role, ttl),
+        Command::SetRole { id, role } => cli::set_role(&config, id, role),
+        Command::Openapi => {
+            println!("{}", openapi::to_json());
+            Ok(())
+        }
+    };
+    if let Err(err) = result {
+        eprintln!("error: {}", err);
+        std::process::exit(1);
+    }
+    Ok(())
 }
 
-async fn post_json(info: web::Json<Info>) -> HttpResponse {
-    HttpResponse::Ok().json(format!("Received name: {}", info.name))
-}
+async fn serve(config: Config) -> std::io::Result<()> {
+    health::mark_started();
+    let repository =
+        storage::open(&config.storage).map_err(|err| std::io::Error::other(err.to_string()))?;
+    let tracer_provider = telemetry::init(&config.tracing).map_err(std::io::Error::other)?;
+    let state = AppState::new(&config, repository.clone()).map_err(std::io::Error::other)?;
+    let shutdown = state.shutdown.clone();
+    shutdown.on_shutdown("flush storage", move || {
+        repository.flush().map_err(|err| err.to_string())
+    });
+    shutdown.on_shutdown("export traces", move || {
+        tracer_provider.shutdown().map_err(|err| err.to_string())
+    });
+    shutdown.on_shutdown("flush logs", || {
+        log::logger().flush();
+        Ok(())
+    });
 
-async fn get_user(path: web::Path<(u32,)>) -> HttpResponse {
-    let user_id = path.into_inner();
-    HttpResponse::Ok().body(format!("User ID is {}", user_id.0))
-}
+    log::info!("starting server at http://{}:{}", config.host, config.port);
+    let mut server = HttpServer::new(move || build_app(&state).configure(configure))
+        .disable_signals()
+        .shutdown_timeout(config.shutdown.drain_timeout);
+    if config.workers > 0 {
+        server = server.workers(config.workers);
+    }
+    let server = server.bind((config.host.as_str(), config.port))?.run();
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    println!("Starting server at: http://localhost:3000");
-    HttpServer::new(|| {
-        App::new()
-            .wrap(Logger::default())  
-            .route("/", web::get().to(|| async { HttpResponse::Ok().body("Hello World!") }))
-            .route("/users", web::get().to(get_json_data))
-            .route("/users/{id}", web::get().to(get_user))
-            .route("/users", web::post().to(post_json))
-    })
-    .bind("127.0.0.1:3000")?
-    .run()
-    .await
+    let handle = server.handle();
+    let watcher = shutdown.clone();
+    actix_web::rt::spawn(async move { watcher.watch(handle).await });
+
+    server.await?;
+    shutdown.finish();
+    Ok(())
 }
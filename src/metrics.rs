@@ -0,0 +1,153 @@
+//! Prometheus metrics, served at `GET /metrics` in the text exposition
+//! format.
+//!
+//! HTTP metrics are labeled by route pattern (`/users/{id}`), never by raw
+//! path, so label cardinality stays bounded no matter what clients request.
+
+use std::time::Instant;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+/// Route label for requests that matched no route.
+const UNMATCHED: &str = "unmatched";
+
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    duration: HistogramVec,
+    in_flight: IntGaugeVec,
+    pub users_created: IntCounter,
+    pub users_updated: IntCounter,
+    pub users_deleted: IntCounter,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests handled"),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time from receiving a request to producing its response",
+            ),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let in_flight = IntGaugeVec::new(
+            Opts::new("http_requests_in_flight", "HTTP requests being handled"),
+            &["method", "route"],
+        )
+        .unwrap();
+        let users_created =
+            IntCounter::new("users_created_total", "Users created through the API").unwrap();
+        let users_updated = IntCounter::new(
+            "users_updated_total",
+            "Users replaced or patched through the API",
+        )
+        .unwrap();
+        let users_deleted =
+            IntCounter::new("users_deleted_total", "Users deleted through the API").unwrap();
+
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(duration.clone())).unwrap();
+        registry.register(Box::new(in_flight.clone())).unwrap();
+        registry.register(Box::new(users_created.clone())).unwrap();
+        registry.register(Box::new(users_updated.clone())).unwrap();
+        registry.register(Box::new(users_deleted.clone())).unwrap();
+
+        Metrics {
+            registry,
+            requests,
+            duration,
+            in_flight,
+            users_created,
+            users_updated,
+            users_deleted,
+        }
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+/// Keeps the in-flight gauge right even if the request future is dropped.
+struct InFlight(IntGauge);
+
+impl InFlight {
+    fn start(gauge: IntGauge) -> Self {
+        gauge.inc();
+        InFlight(gauge)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// Middleware recording the HTTP metrics for every request.
+pub async fn track(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let metrics = req
+        .app_data::<web::Data<Metrics>>()
+        .expect("Metrics is registered as app data")
+        .clone();
+    let method = req.method().to_string();
+    let route = req.match_pattern().unwrap_or_else(|| UNMATCHED.to_string());
+
+    let in_flight = InFlight::start(metrics.in_flight.with_label_values(&[&method, &route]));
+    let started = Instant::now();
+    let res = next.call(req).await;
+    drop(in_flight);
+
+    let status = match &res {
+        Ok(res) => res.status(),
+        Err(err) => err.as_response_error().status_code(),
+    };
+    let labels = [method.as_str(), route.as_str(), status.as_str()];
+    metrics.requests.with_label_values(&labels).inc();
+    metrics
+        .duration
+        .with_label_values(&labels)
+        .observe(started.elapsed().as_secs_f64());
+    res
+}
+
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "operations",
+    responses((
+        status = 200,
+        description = "Metrics in the Prometheus text format",
+        body = String,
+        content_type = "text/plain; version=0.0.4"
+    ))
+)]
+pub async fn export(metrics: web::Data<Metrics>) -> HttpResponse {
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    encoder
+        .encode(&metrics.registry.gather(), &mut body)
+        .unwrap();
+    HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(body)
+}
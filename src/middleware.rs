@@ -0,0 +1,22 @@
+//! The middleware [`build_app`](crate::build_app) wraps the app in, for
+//! servers that assemble their own.
+//!
+//! From the outside in: [`shutdown`] counts in-flight requests, [`request_id`]
+//! assigns `X-Request-Id`, [`trace`] opens the server span, [`access_log`]
+//! and [`metrics`] record the response, the session middleware loads the
+//! cookie session and [`rate_limit`] applies the `[rate_limit]` rules. Each
+//! reads its state from app data, so the [`AppState`](crate::AppState) must
+//! be registered on the same app.
+//!
+//! [`shutdown`] only counts, for the drain report; it still serves requests
+//! while draining, and `/readyz` is what takes the instance out of rotation.
+//!
+//! [`require`] and [`authenticated`] guard single routes.
+
+pub use crate::auth::{authenticated, require};
+pub use crate::logging::access_log;
+pub use crate::metrics::track as metrics;
+pub use crate::rate_limit::limit as rate_limit;
+pub use crate::request_id::assign as request_id;
+pub use crate::shutdown::track as shutdown;
+pub use crate::telemetry::trace;
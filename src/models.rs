@@ -0,0 +1,238 @@
+//! Stored records, and request and response bodies for the API.
+
+use std::fmt;
+use std::str::FromStr;
+
+use async_graphql::{Enum, InputObject, SimpleObject};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use validator::Validate;
+
+use crate::storage::{Cursor, Sort, UserFilter, UserQuery};
+
+pub const MAX_NAME_LEN: u64 = 100;
+pub const MAX_AGE: u8 = 150;
+pub const DEFAULT_PAGE_SIZE: usize = 20;
+pub const MAX_PAGE_SIZE: usize = 100;
+
+/// What a user may do; see [`crate::auth::policy`].
+#[derive(Serialize, Deserialize, ToSchema, Enum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Admin,
+    Editor,
+    #[default]
+    Viewer,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Editor => "editor",
+            Role::Viewer => "viewer",
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "admin" => Ok(Role::Admin),
+            "editor" => Ok(Role::Editor),
+            "viewer" => Ok(Role::Viewer),
+            other => Err(format!(
+                "unknown role {:?}; expected admin, editor or viewer",
+                other
+            )),
+        }
+    }
+}
+
+/// Body of `POST /users`, and the input of the `createUser` mutation.
+#[derive(Deserialize, Validate, ToSchema, InputObject)]
+#[graphql(name = "CreateUserInput")]
+pub struct Info {
+    #[validate(length(min = 1, max = MAX_NAME_LEN, message = "must be 1 to 100 characters"))]
+    pub name: String,
+    #[validate(range(max = MAX_AGE, message = "must be between 0 and 150"))]
+    pub age: Option<u8>,
+    #[validate(email(message = "must be a valid email address"))]
+    pub email: Option<String>,
+    /// Defaults to [`Role::Viewer`].
+    #[serde(default)]
+    pub role: Option<Role>,
+}
+
+/// Body of `PUT /users/{id}`, and the shape a merge-patched user must still
+/// have after `PATCH /users/{id}`. `id` may be sent back but must match the path.
+#[derive(Deserialize, Validate, ToSchema, Clone)]
+pub struct UserUpdate {
+    pub id: Option<u32>,
+    #[validate(length(min = 1, max = MAX_NAME_LEN, message = "must be 1 to 100 characters"))]
+    pub name: String,
+    #[validate(range(max = MAX_AGE, message = "must be between 0 and 150"))]
+    pub age: Option<u8>,
+    #[validate(email(message = "must be a valid email address"))]
+    pub email: Option<String>,
+    /// Left unchanged if omitted.
+    #[serde(default)]
+    pub role: Option<Role>,
+}
+
+/// A stored user. Validated on its own only when it comes from outside the
+/// API, e.g. an `import` file.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Validate, ToSchema, SimpleObject)]
+pub struct User {
+    pub id: u32,
+    #[validate(length(min = 1, max = MAX_NAME_LEN, message = "must be 1 to 100 characters"))]
+    pub name: String,
+    #[validate(range(max = MAX_AGE, message = "must be between 0 and 150"))]
+    pub age: Option<u8>,
+    #[validate(email(message = "must be a valid email address"))]
+    pub email: Option<String>,
+    /// Missing from records written before roles existed.
+    #[serde(default)]
+    pub role: Role,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl User {
+    /// Builds a freshly created user; both timestamps are set to now.
+    pub fn new(id: u32, info: Info) -> Self {
+        let now = Utc::now();
+        User {
+            id,
+            name: info.name,
+            age: info.age,
+            email: info.email,
+            role: info.role.unwrap_or_default(),
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    /// Overwrites the mutable fields and bumps `updated_at`.
+    pub fn apply(&mut self, update: UserUpdate) {
+        self.name = update.name;
+        self.age = update.age;
+        self.email = update.email;
+        if let Some(role) = update.role {
+            self.role = role;
+        }
+        self.updated_at = Utc::now();
+    }
+}
+
+/// An API key for service callers, without its secret. The `id` is the
+/// part of the key before the `.`, so it can be shown to identify the key.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct ApiKey {
+    pub id: String,
+    /// What the key is for, e.g. the name of the job using it.
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub role: Role,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl ApiKey {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// Query string of `GET /users`, e.g. `?sort=name,-age&min_age=18&limit=50`.
+/// Serialized back into the query string of pagination links.
+#[derive(Serialize, Deserialize, Default, Clone, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ListParams {
+    /// Page size, from 1 to 100; 20 if absent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<usize>,
+    /// Opaque cursor taken from a previous page's `next` link.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub after: Option<String>,
+    /// Comma-separated fields to order by, each descending if prefixed
+    /// with `-`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sort: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name_contains: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email_contains: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_age: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_age: Option<u8>,
+}
+
+impl ListParams {
+    pub fn to_query(&self) -> Result<UserQuery, String> {
+        let limit = self.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+        if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+            return Err(format!("limit must be between 1 and {}", MAX_PAGE_SIZE));
+        }
+        if self.after.is_some() && self.offset.is_some() {
+            return Err("after and offset cannot be combined".to_string());
+        }
+        let sort: Sort = self.sort.as_deref().unwrap_or("").parse()?;
+        let after = match &self.after {
+            Some(token) => Some(Cursor::decode(token, &sort)?),
+            None => None,
+        };
+        Ok(UserQuery {
+            filter: UserFilter {
+                name_contains: self.name_contains.clone(),
+                email_contains: self.email_contains.clone(),
+                min_age: self.min_age,
+                max_age: self.max_age,
+            },
+            sort,
+            after,
+            offset: self.offset.unwrap_or(0),
+            limit,
+        })
+    }
+
+    /// The same listing starting from the first page.
+    pub fn first(&self) -> ListParams {
+        ListParams {
+            offset: None,
+            after: None,
+            ..self.clone()
+        }
+    }
+
+    /// The same listing continuing after `cursor`.
+    pub fn after(&self, cursor: String) -> ListParams {
+        ListParams {
+            after: Some(cursor),
+            ..self.first()
+        }
+    }
+}
+
+/// Response body of `GET /users`.
+#[derive(Serialize, ToSchema)]
+pub struct UserList {
+    pub items: Vec<User>,
+    /// Number of users matching the filters across all pages.
+    pub total: u64,
+    /// Link to the next page, absent on the last one.
+    pub next: Option<String>,
+}
@@ -0,0 +1,246 @@
+//! The OpenAPI 3.1 description of the API, generated from the handlers'
+//! `#[utoipa::path]` attributes and the models' schemas.
+//!
+//! It is served at `GET /openapi.json`, with Swagger UI at `/docs`; the UI's
+//! files are compiled into the binary, so the page works offline. The `openapi`
+//! subcommand prints the same document, and `tests/openapi.rs` compares it
+//! with the committed snapshot so that API changes show up in review.
+//!
+//! The `/users` routes are annotated with their JSON bodies only; the
+//! [`MediaTypes`] modifier lists the other [`Format`]s next to them.
+
+use std::sync::LazyLock;
+
+use actix_web::HttpResponse;
+use utoipa::openapi::path::Operation;
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, Http, HttpAuthScheme, SecurityScheme};
+use utoipa::openapi::RefOr;
+use utoipa::{Modify, OpenApi};
+use utoipa_swagger_ui::{Config, SwaggerUi};
+
+use crate::content::Format;
+use crate::{admin, auth, events, graphql, handlers, health, metrics};
+
+pub use responses::*;
+
+/// Reusable error responses for `#[utoipa::path]`. The types only describe
+/// responses and are never built.
+#[allow(dead_code)]
+mod responses {
+    use utoipa::ToResponse;
+
+    use crate::error::Problem;
+
+    /// The request lacks valid credentials.
+    #[derive(ToResponse)]
+    #[response(content_type = "application/problem+json")]
+    pub struct Unauthorized(Problem);
+
+    /// The caller may not do this.
+    #[derive(ToResponse)]
+    #[response(content_type = "application/problem+json")]
+    pub struct Forbidden(Problem);
+
+    /// The record does not exist.
+    #[derive(ToResponse)]
+    #[response(content_type = "application/problem+json")]
+    pub struct NotFound(Problem);
+
+    /// The body, path or query string could not be read.
+    #[derive(ToResponse)]
+    #[response(content_type = "application/problem+json")]
+    pub struct BadRequest(Problem);
+
+    /// The body was read but some fields are invalid; see `errors`.
+    #[derive(ToResponse)]
+    #[response(content_type = "application/problem+json")]
+    pub struct ValidationFailed(Problem);
+
+    /// The request conflicts with existing data.
+    #[derive(ToResponse)]
+    #[response(content_type = "application/problem+json")]
+    pub struct Conflict(Problem);
+
+    /// The body's `Content-Type` is not one the route reads.
+    #[derive(ToResponse)]
+    #[response(content_type = "application/problem+json")]
+    pub struct UnsupportedMediaType(Problem);
+
+    /// None of the `Accept` types can be produced.
+    #[derive(ToResponse)]
+    #[response(content_type = "application/problem+json")]
+    pub struct NotAcceptable(Problem);
+
+    /// The instance can't take traffic right now.
+    #[derive(ToResponse)]
+    #[response(content_type = "application/problem+json")]
+    pub struct Unavailable(Problem);
+}
+
+/// Registers the ways a caller can authenticate.
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        let mut jwt = Http::new(HttpAuthScheme::Bearer);
+        jwt.bearer_format = Some("JWT".to_string());
+        jwt.description = Some("A JWT whose `scope` claim grants the route's scope".to_string());
+        components.add_security_scheme("bearer", SecurityScheme::Http(jwt));
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                "X-Api-Key",
+                "A key issued through `POST /admin/api-keys`; `Authorization: ApiKey <key>` works too",
+            ))),
+        );
+        components.add_security_scheme(
+            "session",
+            SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::with_description(
+                "session",
+                "Set by `/auth/login`; unsafe methods also need `X-CSRF-Token`",
+            ))),
+        );
+        let mut admin = Http::new(HttpAuthScheme::Bearer);
+        admin.description = Some("The configured `admin.token`".to_string());
+        components.add_security_scheme("admin_token", SecurityScheme::Http(admin));
+    }
+}
+
+/// Adds the non-JSON [`Format`]s to the bodies of the `/users` routes,
+/// which negotiate them.
+struct MediaTypes;
+
+impl MediaTypes {
+    fn add_formats(operation: &mut Operation) {
+        if let Some(body) = &mut operation.request_body {
+            let json = body
+                .content
+                .iter()
+                .find(|(media_type, _)| media_type.ends_with("json"))
+                .map(|(_, content)| content.clone());
+            if let Some(json) = json {
+                for format in &Format::ALL[1..] {
+                    body.content
+                        .insert(format.media_type().to_string(), json.clone());
+                }
+            }
+        }
+        for response in operation.responses.responses.values_mut() {
+            // Problem responses are references, and always JSON.
+            let RefOr::T(response) = response else {
+                continue;
+            };
+            if let Some(json) = response.content.get(Format::Json.media_type()).cloned() {
+                for format in &Format::ALL[1..] {
+                    response
+                        .content
+                        .insert(format.media_type().to_string(), json.clone());
+                }
+            }
+        }
+    }
+}
+
+impl Modify for MediaTypes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        for (path, item) in openapi.paths.paths.iter_mut() {
+            if !path.starts_with("/users") {
+                continue;
+            }
+            for operation in [
+                &mut item.get,
+                &mut item.put,
+                &mut item.post,
+                &mut item.patch,
+                &mut item.delete,
+            ]
+            .into_iter()
+            .flatten()
+            {
+                MediaTypes::add_formats(operation);
+            }
+        }
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Users API",
+        description = "Create, read, update and delete users. Errors are RFC 7807 problem documents."
+    ),
+    paths(
+        handlers::index,
+        handlers::get_json_data,
+        handlers::post_json,
+        handlers::get_user,
+        handlers::put_user,
+        handlers::patch_user,
+        handlers::delete_user,
+        events::ws::users,
+        events::sse::users,
+        graphql::http::execute,
+        graphql::ws::subscriptions,
+        auth::account::signup,
+        auth::account::login,
+        auth::account::logout,
+        auth::account::me,
+        admin::shutdown,
+        admin::create_api_key,
+        admin::list_api_keys,
+        admin::revoke_api_key,
+        health::healthz,
+        health::readyz,
+        health::details,
+        metrics::export,
+    ),
+    components(responses(
+        Unauthorized,
+        Forbidden,
+        NotFound,
+        BadRequest,
+        ValidationFailed,
+        Conflict,
+        UnsupportedMediaType,
+        NotAcceptable,
+        Unavailable
+    )),
+    modifiers(&SecuritySchemes, &MediaTypes),
+    tags(
+        (name = "users", description = "User records"),
+        (name = "graphql", description = "The same users over GraphQL; the schema is at `/graphiql`"),
+        (name = "auth", description = "Local accounts and login sessions"),
+        (name = "admin", description = "Operator routes, enabled by `admin.token`"),
+        (name = "operations", description = "Probes and metrics"),
+    )
+)]
+pub struct ApiDoc;
+
+/// The spec. utoipa fills `info` from the package metadata, which has no
+/// license.
+fn document() -> utoipa::openapi::OpenApi {
+    let mut openapi = ApiDoc::openapi();
+    openapi.info.license = None;
+    openapi
+}
+
+static SPEC: LazyLock<String> = LazyLock::new(|| document().to_pretty_json().unwrap());
+
+/// The spec as served, pretty-printed.
+pub fn to_json() -> &'static str {
+    &SPEC
+}
+
+/// `GET /openapi.json`.
+pub async fn spec() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(to_json())
+}
+
+/// Swagger UI at `/docs/`, reading the spec from `/openapi.json`. The URL is
+/// relative, so it also works inside a scope.
+pub fn swagger_ui() -> SwaggerUi {
+    SwaggerUi::new("/docs/{_:.*}").config(Config::from("../openapi.json"))
+}
@@ -0,0 +1,481 @@
+//! Token-bucket rate limiting.
+//!
+//! Every rule in `[rate_limit]` gives each client its own bucket of
+//! `capacity` tokens, refilled evenly over `period` seconds. A request takes
+//! a token, or is refused with `429` when the bucket is empty. Clients are
+//! told apart by API key, then by user, then by IP address; the IP comes
+//! from `X-Forwarded-For` only when the direct peer is a trusted proxy.
+//!
+//! Responses to limited routes carry the `RateLimit-*` headers of the IETF
+//! `draft-ietf-httpapi-ratelimit-headers` draft. Buckets are kept in a
+//! [`RateLimitStore`], so a shared store can replace the in-memory one when
+//! running several instances.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpRequest};
+use ipnet::IpNet;
+
+use crate::auth::{self, AuthMethod};
+use crate::config::{RateLimitConfig, RateLimitRule};
+use crate::error::AppError;
+
+const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+const RATELIMIT_POLICY: HeaderName = HeaderName::from_static("ratelimit-policy");
+
+/// How often the in-memory store drops buckets that have refilled.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug)]
+pub struct Quota {
+    pub capacity: u32,
+    pub period: Duration,
+}
+
+impl Quota {
+    /// Tokens added per second.
+    fn rate(&self) -> f64 {
+        f64::from(self.capacity) / self.period.as_secs_f64()
+    }
+}
+
+/// The outcome of taking a token.
+#[derive(Clone, Copy, Debug)]
+pub struct Decision {
+    pub allowed: bool,
+    /// Whole tokens left after this request.
+    pub remaining: u32,
+    /// Time until the bucket is full again.
+    pub reset: Duration,
+    /// Time until the next token, if this request was refused.
+    pub retry_after: Option<Duration>,
+}
+
+/// Where buckets are kept.
+pub trait RateLimitStore: Send + Sync {
+    /// Takes a token from bucket `key`, which starts out full.
+    fn acquire(&self, key: &str, quota: Quota) -> Decision;
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// When the bucket will be full again, after which it can be forgotten.
+    full_at: Instant,
+}
+
+/// Buckets in process memory; every instance limits on its own.
+pub struct MemoryStore {
+    buckets: Mutex<HashMap<String, Bucket>>,
+    last_sweep: Mutex<Instant>,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        MemoryStore {
+            buckets: Mutex::default(),
+            last_sweep: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+impl MemoryStore {
+    /// Drops full buckets, which behave the same as missing ones.
+    fn sweep(&self, buckets: &mut HashMap<String, Bucket>, now: Instant) {
+        let mut last_sweep = self.last_sweep.lock().unwrap();
+        if now.duration_since(*last_sweep) >= SWEEP_INTERVAL {
+            buckets.retain(|_, bucket| bucket.full_at > now);
+            *last_sweep = now;
+        }
+    }
+
+    /// [`RateLimitStore::acquire`] as of `now`.
+    fn acquire_at(&self, key: &str, quota: Quota, now: Instant) -> Decision {
+        let capacity = f64::from(quota.capacity);
+        let rate = quota.rate();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        self.sweep(&mut buckets, now);
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+            full_at: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        let reset = Duration::from_secs_f64((capacity - bucket.tokens) / rate);
+        bucket.full_at = now + reset;
+        Decision {
+            allowed,
+            remaining: bucket.tokens as u32,
+            reset,
+            retry_after: (!allowed).then(|| Duration::from_secs_f64((1.0 - bucket.tokens) / rate)),
+        }
+    }
+}
+
+impl RateLimitStore for MemoryStore {
+    fn acquire(&self, key: &str, quota: Quota) -> Decision {
+        self.acquire_at(key, quota, Instant::now())
+    }
+}
+
+struct Rule {
+    route: String,
+    method: Option<Method>,
+    quota: Quota,
+}
+
+impl Rule {
+    fn matches(&self, method: &Method, pattern: Option<&str>) -> bool {
+        self.method.as_ref().is_none_or(|m| m == method)
+            && (self.route == "*" || pattern == Some(self.route.as_str()))
+    }
+}
+
+/// Parses a trusted proxy entry: an IP address or a CIDR range.
+pub fn parse_proxy(proxy: &str) -> Result<IpNet, String> {
+    proxy
+        .parse::<IpNet>()
+        .or_else(|_| proxy.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("{:?} is not an IP address or CIDR range", proxy))
+}
+
+/// The rule's method, if it is limited to one. Case doesn't matter.
+fn parse_method(method: &str) -> Result<Option<Method>, String> {
+    if method.is_empty() {
+        return Ok(None);
+    }
+    method
+        .to_ascii_uppercase()
+        .parse()
+        .map(Some)
+        .map_err(|_| format!("{:?} is not an HTTP method", method))
+}
+
+pub fn check_rule(rule: &RateLimitRule) -> Result<(), String> {
+    if rule.route != "*" && !rule.route.starts_with('/') {
+        return Err(format!(
+            "route {:?} must be a route pattern or \"*\"",
+            rule.route
+        ));
+    }
+    parse_method(&rule.method)?;
+    if rule.capacity == 0 {
+        return Err("capacity must be greater than 0".to_string());
+    }
+    if rule.period == 0 {
+        return Err("period must be greater than 0".to_string());
+    }
+    Ok(())
+}
+
+pub struct RateLimiter {
+    rules: Vec<Rule>,
+    trusted_proxies: Vec<IpNet>,
+    store: Arc<dyn RateLimitStore>,
+}
+
+impl RateLimiter {
+    /// Builds the limiter, failing with the first problem in `config` as
+    /// `Config::validate` would word it.
+    pub fn new(config: &RateLimitConfig, store: Arc<dyn RateLimitStore>) -> Result<Self, String> {
+        let rules = config
+            .rules
+            .iter()
+            .enumerate()
+            .map(|(i, rule)| {
+                let invalid = |msg| format!("rate_limit.rules[{}]: {}", i, msg);
+                check_rule(rule).map_err(invalid)?;
+                Ok(Rule {
+                    route: rule.route.clone(),
+                    method: parse_method(&rule.method).map_err(invalid)?,
+                    quota: Quota {
+                        capacity: rule.capacity,
+                        period: Duration::from_secs(rule.period),
+                    },
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        let trusted_proxies = config
+            .trusted_proxies
+            .iter()
+            .map(|proxy| {
+                parse_proxy(proxy).map_err(|msg| format!("rate_limit.trusted_proxies: {}", msg))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(RateLimiter {
+            rules,
+            trusted_proxies,
+            store,
+        })
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(&ip))
+    }
+
+    /// The client's address. `X-Forwarded-For` is read right to left,
+    /// skipping trusted proxies, and only if the peer itself is trusted, so
+    /// clients can't pick their own address.
+    fn client_ip(&self, req: &HttpRequest) -> Option<IpAddr> {
+        let peer = req.peer_addr()?.ip();
+        if !self.is_trusted(peer) {
+            return Some(peer);
+        }
+        let forwarded = req
+            .headers()
+            .get_all(HeaderName::from_static("x-forwarded-for"))
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|hop| hop.trim().parse::<IpAddr>().ok())
+            .collect::<Vec<_>>();
+        let mut client = peer;
+        for hop in forwarded.into_iter().rev() {
+            match hop {
+                Some(ip) if self.is_trusted(ip) => client = ip,
+                Some(ip) => return Some(ip),
+                // An unparseable hop was written by someone untrusted.
+                None => break,
+            }
+        }
+        Some(client)
+    }
+
+    /// Who the bucket belongs to. Bad credentials count as none, so
+    /// guessing keys is limited per address.
+    fn client_key(&self, req: &HttpRequest) -> String {
+        match auth::caller(req) {
+            Some(claims) if claims.method == AuthMethod::ApiKey => format!("key:{}", claims.sub),
+            Some(claims) => match claims.user_id {
+                Some(id) => format!("user:{}", id),
+                None => format!("sub:{}", claims.sub),
+            },
+            None => match self.client_ip(req) {
+                Some(ip) => format!("ip:{}", ip),
+                None => "ip:unknown".to_string(),
+            },
+        }
+    }
+}
+
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+fn set_headers(headers: &mut HeaderMap, quota: Quota, decision: &Decision) {
+    let number = |n: u64| HeaderValue::from(n);
+    headers.insert(RATELIMIT_LIMIT, number(quota.capacity.into()));
+    headers.insert(RATELIMIT_REMAINING, number(decision.remaining.into()));
+    headers.insert(RATELIMIT_RESET, number(ceil_secs(decision.reset)));
+    headers.insert(
+        RATELIMIT_POLICY,
+        HeaderValue::from_str(&format!("{};w={}", quota.capacity, quota.period.as_secs())).unwrap(),
+    );
+    if let Some(retry_after) = decision.retry_after {
+        headers.insert(header::RETRY_AFTER, number(ceil_secs(retry_after).max(1)));
+    }
+}
+
+/// Middleware applying the first matching rule to each request.
+pub async fn limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let limiter = req
+        .app_data::<web::Data<RateLimiter>>()
+        .expect("RateLimiter is registered as app data")
+        .clone();
+    let pattern = req.match_pattern();
+    let Some((index, rule)) = (limiter.rules.iter().enumerate())
+        .find(|(_, rule)| rule.matches(req.method(), pattern.as_deref()))
+    else {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body);
+    };
+
+    // Each rule has its own buckets.
+    let key = format!("{}:{}", index, limiter.client_key(req.request()));
+    let decision = limiter.store.acquire(&key, rule.quota);
+    if !decision.allowed {
+        let err = AppError::TooManyRequests(format!(
+            "Rate limit of {} requests per {} seconds exceeded",
+            rule.quota.capacity,
+            rule.quota.period.as_secs()
+        ));
+        let mut res = req.error_response(err);
+        set_headers(res.headers_mut(), rule.quota, &decision);
+        return Ok(res.map_into_right_body());
+    }
+
+    let mut res = next.call(req).await?;
+    set_headers(res.headers_mut(), rule.quota, &decision);
+    Ok(res.map_into_left_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUOTA: Quota = Quota {
+        capacity: 3,
+        period: Duration::from_secs(6),
+    };
+
+    fn rule(route: &str, method: &str) -> RateLimitRule {
+        RateLimitRule {
+            route: route.to_string(),
+            method: method.to_string(),
+            capacity: 1,
+            period: 1,
+        }
+    }
+
+    fn limiter(config: RateLimitConfig) -> Result<RateLimiter, String> {
+        RateLimiter::new(&config, Arc::new(MemoryStore::default()))
+    }
+
+    #[test]
+    fn a_full_bucket_allows_a_burst_of_its_capacity() {
+        let store = MemoryStore::default();
+        let now = Instant::now();
+        for remaining in [2, 1, 0] {
+            let decision = store.acquire_at("client", QUOTA, now);
+            assert!(decision.allowed);
+            assert_eq!(decision.remaining, remaining);
+            assert_eq!(decision.retry_after, None);
+        }
+
+        let refused = store.acquire_at("client", QUOTA, now);
+        assert!(!refused.allowed);
+        assert_eq!(refused.remaining, 0);
+        assert_eq!(refused.reset, Duration::from_secs(6));
+        assert_eq!(refused.retry_after, Some(Duration::from_secs(2)));
+
+        assert!(store.acquire_at("other client", QUOTA, now).allowed);
+    }
+
+    #[test]
+    fn tokens_refill_at_capacity_per_period() {
+        let store = MemoryStore::default();
+        let start = Instant::now();
+        for _ in 0..3 {
+            store.acquire_at("client", QUOTA, start);
+        }
+
+        let early = store.acquire_at("client", QUOTA, start + Duration::from_secs(1));
+        assert!(!early.allowed);
+        assert_eq!(early.retry_after, Some(Duration::from_secs(1)));
+
+        let one_token = store.acquire_at("client", QUOTA, start + Duration::from_secs(2));
+        assert!(one_token.allowed);
+        assert_eq!(one_token.remaining, 0);
+        assert!(
+            !store
+                .acquire_at("client", QUOTA, start + Duration::from_secs(2))
+                .allowed
+        );
+
+        // Never more than a full bucket, however long the client waits.
+        let later = start + Duration::from_secs(600);
+        let full = store.acquire_at("client", QUOTA, later);
+        assert!(full.allowed);
+        assert_eq!(full.remaining, 2);
+        assert_eq!(full.reset, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn headers_describe_the_quota_and_the_decision() {
+        let store = MemoryStore::default();
+        let now = Instant::now();
+        let mut headers = HeaderMap::new();
+        set_headers(&mut headers, QUOTA, &store.acquire_at("client", QUOTA, now));
+        assert_eq!(headers.get(RATELIMIT_LIMIT).unwrap(), "3");
+        assert_eq!(headers.get(RATELIMIT_REMAINING).unwrap(), "2");
+        assert_eq!(headers.get(RATELIMIT_RESET).unwrap(), "2");
+        assert_eq!(headers.get(RATELIMIT_POLICY).unwrap(), "3;w=6");
+        assert!(!headers.contains_key(header::RETRY_AFTER));
+
+        let refused = Decision {
+            allowed: false,
+            remaining: 0,
+            reset: Duration::from_millis(5500),
+            retry_after: Some(Duration::from_millis(1)),
+        };
+        let mut headers = HeaderMap::new();
+        set_headers(&mut headers, QUOTA, &refused);
+        assert_eq!(headers.get(RATELIMIT_REMAINING).unwrap(), "0");
+        assert_eq!(headers.get(RATELIMIT_RESET).unwrap(), "6");
+        assert_eq!(headers.get(header::RETRY_AFTER).unwrap(), "1");
+
+        let soon = Decision {
+            retry_after: Some(Duration::ZERO),
+            ..refused
+        };
+        let mut headers = HeaderMap::new();
+        set_headers(&mut headers, QUOTA, &soon);
+        assert_eq!(headers.get(header::RETRY_AFTER).unwrap(), "1", "never 0");
+    }
+
+    #[test]
+    fn new_accepts_methods_in_any_case() {
+        let limiter = limiter(RateLimitConfig {
+            trusted_proxies: vec!["10.0.0.0/8".to_string(), "::1".to_string()],
+            rules: vec![rule("/users", "post"), rule("*", "")],
+        })
+        .unwrap();
+        assert_eq!(limiter.rules[0].method, Some(Method::POST));
+        assert_eq!(limiter.rules[1].method, None);
+        assert_eq!(limiter.trusted_proxies.len(), 2);
+    }
+
+    #[test]
+    fn new_rejects_what_validation_would() {
+        for (config, problem) in [
+            (
+                RateLimitConfig {
+                    rules: vec![rule("*", ""), rule("/users", "not a method")],
+                    ..RateLimitConfig::default()
+                },
+                r#"rate_limit.rules[1]: "not a method" is not an HTTP method"#,
+            ),
+            (
+                RateLimitConfig {
+                    rules: vec![rule("users", "GET")],
+                    ..RateLimitConfig::default()
+                },
+                r#"rate_limit.rules[0]: route "users" must be a route pattern or "*""#,
+            ),
+            (
+                RateLimitConfig {
+                    trusted_proxies: vec!["proxy.internal".to_string()],
+                    ..RateLimitConfig::default()
+                },
+                "rate_limit.trusted_proxies: ",
+            ),
+        ] {
+            match limiter(config) {
+                Err(err) => assert!(err.starts_with(problem), "{}", err),
+                Ok(_) => panic!("expected {:?}", problem),
+            }
+        }
+    }
+}
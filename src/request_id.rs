@@ -0,0 +1,100 @@
+//! Per-request correlation ids.
+//!
+//! The [`assign`] middleware gives every request a [`RequestId`], taken from
+//! the client's `X-Request-Id` header when it sends a usable one and
+//! generated otherwise, and echoes it back in the response. For as long as
+//! the request is being handled, [`RequestContext::current`] exposes the id
+//! together with the method and route, so code with no access to the request
+//! (error rendering, every log line) can still report them.
+
+use std::fmt;
+use std::sync::Arc;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpMessage};
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest client-supplied id we accept; anything longer is replaced.
+const MAX_LEN: usize = 128;
+
+tokio::task_local! {
+    static CURRENT: RequestContext;
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestId(Arc<str>);
+
+impl RequestId {
+    fn generate() -> Self {
+        RequestId(Uuid::new_v4().to_string().into())
+    }
+
+    /// Accepts ids of printable ASCII without spaces, so they are safe to put
+    /// in headers and log lines verbatim.
+    fn from_client(value: &HeaderValue) -> Option<Self> {
+        let value = value.to_str().ok()?;
+        let usable = !value.is_empty()
+            && value.len() <= MAX_LEN
+            && value.bytes().all(|b| b.is_ascii_graphic());
+        usable.then(|| RequestId(value.into()))
+    }
+
+    /// The id of the request being handled on this task, if any.
+    pub fn current() -> Option<RequestId> {
+        RequestContext::current().map(|context| context.id)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// What is known about the request being handled on this task.
+#[derive(Clone, Debug)]
+pub struct RequestContext {
+    pub id: RequestId,
+    pub method: Arc<str>,
+    /// Route pattern such as `/users/{id}`, if the path matched a route.
+    pub route: Option<Arc<str>>,
+}
+
+impl RequestContext {
+    pub fn current() -> Option<RequestContext> {
+        CURRENT.try_with(RequestContext::clone).ok()
+    }
+}
+
+pub async fn assign(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(RequestId::from_client)
+        .unwrap_or_else(RequestId::generate);
+    let context = RequestContext {
+        id: id.clone(),
+        method: req.method().as_str().into(),
+        route: req.match_pattern().map(Into::into),
+    };
+    req.extensions_mut().insert(id.clone());
+
+    let mut res = CURRENT.scope(context, next.call(req)).await?;
+    res.headers_mut().insert(
+        REQUEST_ID_HEADER,
+        HeaderValue::from_str(id.as_str()).unwrap(),
+    );
+    Ok(res)
+}
@@ -0,0 +1,174 @@
+//! Graceful shutdown.
+//!
+//! [`Shutdown::watch`] waits for SIGINT, SIGTERM or a `POST /admin/shutdown`,
+//! then stops the server gracefully: no new connections are accepted and
+//! in-flight requests get the configured drain timeout to finish. Once the
+//! server has stopped, [`Shutdown::finish`] reports how the drain went and
+//! runs the registered hooks.
+//!
+//! Long-lived streams, which would otherwise hold the server up until the
+//! drain timeout, wait on [`Shutdown::draining`] and close themselves.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServerHandle, ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+use tokio::sync::Notify;
+
+type Hook = Box<dyn FnOnce() -> Result<(), String> + Send>;
+
+#[derive(Default)]
+pub struct Shutdown {
+    draining: AtomicBool,
+    in_flight: AtomicUsize,
+    /// In flight when draining started.
+    draining_from: AtomicUsize,
+    /// Dropped before finishing, i.e. cut off by the drain timeout.
+    aborted: AtomicUsize,
+    requested: Notify,
+    drain_started: Notify,
+    hooks: Mutex<Vec<(String, Hook)>>,
+}
+
+impl Shutdown {
+    /// Registers `hook` to run after the server has stopped. Hooks run in
+    /// registration order; a failing hook is logged and does not stop the
+    /// others.
+    pub fn on_shutdown(
+        &self,
+        name: impl Into<String>,
+        hook: impl FnOnce() -> Result<(), String> + Send + 'static,
+    ) {
+        self.hooks
+            .lock()
+            .unwrap()
+            .push((name.into(), Box::new(hook)));
+    }
+
+    /// Asks [`watch`](Shutdown::watch) to stop the server, as a signal would.
+    pub fn request(&self) {
+        self.requested.notify_one();
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once draining has started, or at once if it already has.
+    pub async fn draining(&self) {
+        let notified = self.drain_started.notified();
+        tokio::pin!(notified);
+        // Registered before checking the flag, so a drain starting in between
+        // still wakes us.
+        notified.as_mut().enable();
+        if self.is_draining() {
+            return;
+        }
+        notified.await;
+    }
+
+    pub async fn watch(&self, server: ServerHandle) {
+        let reason = tokio::select! {
+            _ = tokio::signal::ctrl_c() => "SIGINT",
+            _ = sigterm() => "SIGTERM",
+            _ = self.requested.notified() => "shutdown request",
+        };
+
+        self.draining.store(true, Ordering::SeqCst);
+        self.drain_started.notify_waiters();
+        let in_flight = self.in_flight.load(Ordering::SeqCst);
+        self.draining_from.store(in_flight, Ordering::SeqCst);
+        log::info!(
+            "{} received, draining {} in-flight request(s)",
+            reason,
+            in_flight
+        );
+        server.stop(true).await;
+    }
+
+    /// Logs the drain outcome and runs the shutdown hooks. Call once the
+    /// server future has completed.
+    pub fn finish(&self) {
+        let aborted = self.aborted.load(Ordering::SeqCst);
+        let drained = self
+            .draining_from
+            .load(Ordering::SeqCst)
+            .saturating_sub(aborted);
+        log::info!(
+            "server stopped: {} request(s) drained, {} aborted",
+            drained,
+            aborted
+        );
+
+        for (name, hook) in self.hooks.lock().unwrap().drain(..) {
+            match hook() {
+                Ok(()) => log::info!("shutdown hook {:?} done", name),
+                Err(err) => log::error!("shutdown hook {:?} failed: {}", name, err),
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn sigterm() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut stream) => {
+            stream.recv().await;
+        }
+        Err(err) => {
+            log::warn!("cannot listen for SIGTERM: {}", err);
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn sigterm() {
+    std::future::pending::<()>().await;
+}
+
+/// Counts a request as in flight until its handler finishes. A request whose
+/// future is dropped first while draining was aborted.
+struct InFlight<'a> {
+    shutdown: &'a Shutdown,
+    finished: bool,
+}
+
+impl<'a> InFlight<'a> {
+    fn start(shutdown: &'a Shutdown) -> Self {
+        shutdown.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight {
+            shutdown,
+            finished: false,
+        }
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.shutdown.in_flight.fetch_sub(1, Ordering::SeqCst);
+        if !self.finished && self.shutdown.is_draining() {
+            self.shutdown.aborted.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Middleware that keeps the in-flight count.
+pub async fn track(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let shutdown = req
+        .app_data::<web::Data<Shutdown>>()
+        .expect("Shutdown is registered as app data")
+        .clone();
+    let mut in_flight = InFlight::start(&shutdown);
+    let res = next.call(req).await;
+    in_flight.finished = true;
+    res
+}
@@ -0,0 +1,261 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::{MemoryRepository, StorageError, UserRepository};
+use crate::models::{ApiKey, Info, User, UserUpdate};
+
+/// One line of the log file.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Entry {
+    Put { user: User },
+    Delete { id: u32 },
+    Password { id: u32, hash: String },
+    Account { user: User, hash: String },
+    ApiKey { key: ApiKey, hash: String },
+    ApiKeyDelete { id: String },
+    ApiKeyUsed { id: String, at: DateTime<Utc> },
+}
+
+/// Append-only JSON-lines log, replayed into memory on startup. Meant for
+/// demos: the file is never compacted.
+///
+/// API key uses are only written on [`flush`](UserRepository::flush), which
+/// the server calls on shutdown, so a busy key doesn't add a line a minute.
+/// After a crash keys show when they were last used before the previous
+/// clean shutdown.
+pub struct JsonFileRepository {
+    users: MemoryRepository,
+    /// Held for the whole of each mutation so the log order matches the
+    /// order the changes were applied in. Each change is written to the log
+    /// before it is applied, so a failed write leaves memory as it was.
+    log: Mutex<File>,
+    /// Last use of each key touched since the previous flush.
+    touched: Mutex<BTreeMap<String, DateTime<Utc>>>,
+}
+
+impl JsonFileRepository {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StorageError> {
+        let path = path.as_ref();
+        let mut users = BTreeMap::new();
+        let mut password_hashes = BTreeMap::new();
+        let mut api_keys = BTreeMap::new();
+        let mut last_id = 0;
+
+        if path.exists() {
+            let reader = BufReader::new(File::open(path)?);
+            for (number, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let entry = serde_json::from_str(&line).map_err(|err| {
+                    StorageError::Corrupt(format!("{}:{}: {}", path.display(), number + 1, err))
+                })?;
+                match entry {
+                    Entry::Put { user } => {
+                        last_id = last_id.max(user.id);
+                        users.insert(user.id, user);
+                    }
+                    Entry::Delete { id } => {
+                        users.remove(&id);
+                        password_hashes.remove(&id);
+                    }
+                    Entry::Password { id, hash } => {
+                        password_hashes.insert(id, hash);
+                    }
+                    Entry::Account { user, hash } => {
+                        last_id = last_id.max(user.id);
+                        password_hashes.insert(user.id, hash);
+                        users.insert(user.id, user);
+                    }
+                    Entry::ApiKey { key, hash } => {
+                        api_keys.insert(key.id.clone(), (key, hash));
+                    }
+                    Entry::ApiKeyDelete { id } => {
+                        api_keys.remove(&id);
+                    }
+                    Entry::ApiKeyUsed { id, at } => {
+                        if let Some((key, _)) = api_keys.get_mut(&id) {
+                            key.last_used_at = Some(at);
+                        }
+                    }
+                }
+            }
+        }
+
+        let log = OpenOptions::new().create(true).append(true).open(path)?;
+        let repository = JsonFileRepository {
+            users: MemoryRepository::with_users(users),
+            log: Mutex::new(log),
+            touched: Mutex::default(),
+        };
+        repository.users.reserve_ids(last_id);
+        for (id, hash) in password_hashes {
+            repository
+                .users
+                .set_password_hash(id, hash)
+                .map_err(|err| match err {
+                    StorageError::DuplicateEmail(email) => StorageError::Corrupt(format!(
+                        "{}: more than one account for {}",
+                        path.display(),
+                        email
+                    )),
+                    err => err,
+                })?;
+        }
+        for (key, hash) in api_keys.into_values() {
+            repository.users.create_api_key(key, hash)?;
+        }
+        Ok(repository)
+    }
+}
+
+fn append(log: &mut File, entry: &Entry) -> Result<(), StorageError> {
+    let mut line = serde_json::to_vec(entry).map_err(io::Error::from)?;
+    line.push(b'\n');
+    log.write_all(&line)?;
+    Ok(())
+}
+
+impl UserRepository for JsonFileRepository {
+    fn create(&self, info: Info) -> Result<User, StorageError> {
+        let mut log = self.log.lock().unwrap();
+        let user = User::new(self.users.allocate_id(), info);
+        append(&mut log, &Entry::Put { user: user.clone() })?;
+        self.users.import(user.clone())?;
+        Ok(user)
+    }
+
+    fn list(&self) -> Result<Vec<User>, StorageError> {
+        self.users.list()
+    }
+
+    fn get(&self, id: u32) -> Result<Option<User>, StorageError> {
+        self.users.get(id)
+    }
+
+    fn replace(&self, id: u32, update: UserUpdate) -> Result<Option<User>, StorageError> {
+        let mut log = self.log.lock().unwrap();
+        let Some(mut user) = self.users.get(id)? else {
+            return Ok(None);
+        };
+        user.apply(update);
+        self.users.check_account_email(&user, false)?;
+        append(&mut log, &Entry::Put { user: user.clone() })?;
+        self.users.import(user.clone())?;
+        Ok(Some(user))
+    }
+
+    fn delete(&self, id: u32) -> Result<bool, StorageError> {
+        let mut log = self.log.lock().unwrap();
+        if self.users.get(id)?.is_none() {
+            return Ok(false);
+        }
+        append(&mut log, &Entry::Delete { id })?;
+        self.users.delete(id)
+    }
+
+    fn ping(&self) -> Result<(), StorageError> {
+        // Fails if the file has become unreadable, e.g. its volume went away.
+        self.log.lock().unwrap().metadata()?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        let mut log = self.log.lock().unwrap();
+        let touched = std::mem::take(&mut *self.touched.lock().unwrap());
+        for (id, at) in touched {
+            // Revoked keys need no record of their last use.
+            if self.users.get_api_key(&id)?.is_some() {
+                append(&mut log, &Entry::ApiKeyUsed { id, at })?;
+            }
+        }
+        log.sync_all()?;
+        Ok(())
+    }
+
+    fn import(&self, user: User) -> Result<(), StorageError> {
+        let mut log = self.log.lock().unwrap();
+        self.users.check_account_email(&user, false)?;
+        append(&mut log, &Entry::Put { user: user.clone() })?;
+        self.users.import(user)
+    }
+
+    fn create_account(&self, info: Info, hash: String) -> Result<User, StorageError> {
+        let mut log = self.log.lock().unwrap();
+        let mut user = User::new(0, info);
+        self.users.check_account_email(&user, true)?;
+        user.id = self.users.allocate_id();
+        let entry = Entry::Account {
+            user: user.clone(),
+            hash: hash.clone(),
+        };
+        append(&mut log, &entry)?;
+        self.users.import(user.clone())?;
+        self.users.set_password_hash(user.id, hash)?;
+        Ok(user)
+    }
+
+    fn set_password_hash(&self, id: u32, hash: String) -> Result<bool, StorageError> {
+        let mut log = self.log.lock().unwrap();
+        let Some(user) = self.users.get(id)? else {
+            return Ok(false);
+        };
+        self.users.check_account_email(&user, true)?;
+        append(
+            &mut log,
+            &Entry::Password {
+                id,
+                hash: hash.clone(),
+            },
+        )?;
+        self.users.set_password_hash(id, hash)
+    }
+
+    fn find_account(&self, email: &str) -> Result<Option<(User, String)>, StorageError> {
+        self.users.find_account(email)
+    }
+
+    fn create_api_key(&self, key: ApiKey, hash: String) -> Result<(), StorageError> {
+        let mut log = self.log.lock().unwrap();
+        append(
+            &mut log,
+            &Entry::ApiKey {
+                key: key.clone(),
+                hash: hash.clone(),
+            },
+        )?;
+        self.users.create_api_key(key, hash)
+    }
+
+    fn list_api_keys(&self) -> Result<Vec<ApiKey>, StorageError> {
+        self.users.list_api_keys()
+    }
+
+    fn get_api_key(&self, id: &str) -> Result<Option<(ApiKey, String)>, StorageError> {
+        self.users.get_api_key(id)
+    }
+
+    fn delete_api_key(&self, id: &str) -> Result<bool, StorageError> {
+        let mut log = self.log.lock().unwrap();
+        if self.users.get_api_key(id)?.is_none() {
+            return Ok(false);
+        }
+        let entry = Entry::ApiKeyDelete { id: id.to_string() };
+        append(&mut log, &entry)?;
+        self.users.delete_api_key(id)
+    }
+
+    fn touch_api_key(&self, id: &str, at: DateTime<Utc>) -> Result<(), StorageError> {
+        self.users.touch_api_key(id, at)?;
+        self.touched.lock().unwrap().insert(id.to_string(), at);
+        Ok(())
+    }
+}
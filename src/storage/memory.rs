@@ -0,0 +1,193 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::RwLock;
+
+use chrono::{DateTime, Utc};
+
+use super::{StorageError, UserRepository};
+use crate::models::{ApiKey, Info, User, UserUpdate};
+
+/// Volatile store; everything is lost on restart.
+#[derive(Default)]
+pub struct MemoryRepository {
+    users: RwLock<BTreeMap<u32, User>>,
+    next_id: AtomicU32,
+    password_hashes: RwLock<HashMap<u32, String>>,
+    /// Keyed by id, with the hash of the secret.
+    api_keys: RwLock<BTreeMap<String, (ApiKey, String)>>,
+}
+
+impl MemoryRepository {
+    /// Seeds the store with previously persisted records.
+    pub(super) fn with_users(users: BTreeMap<u32, User>) -> Self {
+        let last_id = users.keys().next_back().copied().unwrap_or(0);
+        MemoryRepository {
+            users: RwLock::new(users),
+            next_id: AtomicU32::new(last_id),
+            password_hashes: RwLock::default(),
+            api_keys: RwLock::default(),
+        }
+    }
+
+    /// Raises the id counter so ids of deleted records are never reused.
+    pub(super) fn reserve_ids(&self, last_id: u32) {
+        self.next_id.fetch_max(last_id, Ordering::SeqCst);
+    }
+
+    /// Takes the next id without storing anything under it.
+    pub(super) fn allocate_id(&self) -> u32 {
+        self.next_id.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Fails if storing `user` would give two accounts the same email:
+    /// `user` is an account already or, with `new_account`, is becoming one.
+    /// For callers that must check before they write elsewhere; the
+    /// mutations here check for themselves.
+    pub(super) fn check_account_email(
+        &self,
+        user: &User,
+        new_account: bool,
+    ) -> Result<(), StorageError> {
+        let users = self.users.read().unwrap();
+        let hashes = self.password_hashes.read().unwrap();
+        if new_account || hashes.contains_key(&user.id) {
+            check_email(&users, &hashes, user)?;
+        }
+        Ok(())
+    }
+}
+
+/// Fails if an account other than `user` has `user`'s email.
+fn check_email(
+    users: &BTreeMap<u32, User>,
+    hashes: &HashMap<u32, String>,
+    user: &User,
+) -> Result<(), StorageError> {
+    let Some(email) = user.email.as_deref() else {
+        return Ok(());
+    };
+    let taken = users.values().any(|other| {
+        other.id != user.id
+            && hashes.contains_key(&other.id)
+            && other
+                .email
+                .as_deref()
+                .is_some_and(|address| address.eq_ignore_ascii_case(email))
+    });
+    if taken {
+        return Err(StorageError::DuplicateEmail(email.to_string()));
+    }
+    Ok(())
+}
+
+impl UserRepository for MemoryRepository {
+    fn create(&self, info: Info) -> Result<User, StorageError> {
+        let user = User::new(self.allocate_id(), info);
+        self.users.write().unwrap().insert(user.id, user.clone());
+        Ok(user)
+    }
+
+    fn list(&self) -> Result<Vec<User>, StorageError> {
+        Ok(self.users.read().unwrap().values().cloned().collect())
+    }
+
+    fn get(&self, id: u32) -> Result<Option<User>, StorageError> {
+        Ok(self.users.read().unwrap().get(&id).cloned())
+    }
+
+    fn replace(&self, id: u32, update: UserUpdate) -> Result<Option<User>, StorageError> {
+        let mut users = self.users.write().unwrap();
+        let Some(mut user) = users.get(&id).cloned() else {
+            return Ok(None);
+        };
+        user.apply(update);
+        let hashes = self.password_hashes.read().unwrap();
+        if hashes.contains_key(&id) {
+            check_email(&users, &hashes, &user)?;
+        }
+        users.insert(id, user.clone());
+        Ok(Some(user))
+    }
+
+    fn delete(&self, id: u32) -> Result<bool, StorageError> {
+        let mut users = self.users.write().unwrap();
+        self.password_hashes.write().unwrap().remove(&id);
+        Ok(users.remove(&id).is_some())
+    }
+
+    fn import(&self, user: User) -> Result<(), StorageError> {
+        let mut users = self.users.write().unwrap();
+        let hashes = self.password_hashes.read().unwrap();
+        if hashes.contains_key(&user.id) {
+            check_email(&users, &hashes, &user)?;
+        }
+        self.reserve_ids(user.id);
+        users.insert(user.id, user);
+        Ok(())
+    }
+
+    fn create_account(&self, info: Info, hash: String) -> Result<User, StorageError> {
+        let mut users = self.users.write().unwrap();
+        let mut hashes = self.password_hashes.write().unwrap();
+        let mut user = User::new(0, info);
+        check_email(&users, &hashes, &user)?;
+        user.id = self.allocate_id();
+        users.insert(user.id, user.clone());
+        hashes.insert(user.id, hash);
+        Ok(user)
+    }
+
+    fn set_password_hash(&self, id: u32, hash: String) -> Result<bool, StorageError> {
+        let users = self.users.read().unwrap();
+        let Some(user) = users.get(&id) else {
+            return Ok(false);
+        };
+        let mut hashes = self.password_hashes.write().unwrap();
+        check_email(&users, &hashes, user)?;
+        hashes.insert(id, hash);
+        Ok(true)
+    }
+
+    fn find_account(&self, email: &str) -> Result<Option<(User, String)>, StorageError> {
+        let users = self.users.read().unwrap();
+        let hashes = self.password_hashes.read().unwrap();
+        Ok(users
+            .values()
+            .filter(|user| {
+                user.email
+                    .as_deref()
+                    .is_some_and(|address| address.eq_ignore_ascii_case(email))
+            })
+            .find_map(|user| Some((user.clone(), hashes.get(&user.id)?.clone()))))
+    }
+
+    fn create_api_key(&self, key: ApiKey, hash: String) -> Result<(), StorageError> {
+        self.api_keys
+            .write()
+            .unwrap()
+            .insert(key.id.clone(), (key, hash));
+        Ok(())
+    }
+
+    fn list_api_keys(&self) -> Result<Vec<ApiKey>, StorageError> {
+        let api_keys = self.api_keys.read().unwrap();
+        let mut keys: Vec<ApiKey> = api_keys.values().map(|(key, _)| key.clone()).collect();
+        keys.sort_by_key(|key| key.created_at);
+        Ok(keys)
+    }
+
+    fn get_api_key(&self, id: &str) -> Result<Option<(ApiKey, String)>, StorageError> {
+        Ok(self.api_keys.read().unwrap().get(id).cloned())
+    }
+
+    fn delete_api_key(&self, id: &str) -> Result<bool, StorageError> {
+        Ok(self.api_keys.write().unwrap().remove(id).is_some())
+    }
+
+    fn touch_api_key(&self, id: &str, at: DateTime<Utc>) -> Result<(), StorageError> {
+        if let Some((key, _)) = self.api_keys.write().unwrap().get_mut(id) {
+            key.last_used_at = Some(at);
+        }
+        Ok(())
+    }
+}
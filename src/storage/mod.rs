@@ -0,0 +1,192 @@
+//! User persistence. Handlers only talk to [`UserRepository`]; the concrete
+//! backend is picked once in `main` from the `storage` setting.
+
+mod json_file;
+mod memory;
+mod publishing;
+mod query;
+mod sqlite;
+mod traced;
+
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+
+use crate::models::{ApiKey, Info, User, UserUpdate};
+
+pub use json_file::JsonFileRepository;
+pub use memory::MemoryRepository;
+pub use publishing::PublishingRepository;
+pub use query::{Cursor, Sort, UserFilter, UserPage, UserQuery};
+pub use sqlite::SqliteRepository;
+pub use traced::TracedRepository;
+
+/// Storage operations behind the `/users` routes.
+pub trait UserRepository: Send + Sync {
+    fn create(&self, info: Info) -> Result<User, StorageError>;
+
+    fn list(&self) -> Result<Vec<User>, StorageError>;
+
+    /// One page of users matching `query`. The default filters and sorts the
+    /// whole of [`list`](UserRepository::list) in memory.
+    fn search(&self, query: &UserQuery) -> Result<UserPage, StorageError> {
+        Ok(query.apply(self.list()?))
+    }
+
+    fn get(&self, id: u32) -> Result<Option<User>, StorageError>;
+
+    /// Overwrites the mutable fields of an existing user. Returns `None` if
+    /// there is no user with that id. Fails with
+    /// [`StorageError::DuplicateEmail`] if the user is an account and
+    /// another account has the new email.
+    fn replace(&self, id: u32, update: UserUpdate) -> Result<Option<User>, StorageError>;
+
+    /// Returns `false` if there was no user with that id.
+    fn delete(&self, id: u32) -> Result<bool, StorageError>;
+
+    /// Stores `user` as-is, keeping its id and timestamps and overwriting any
+    /// user with the same id. Used to restore exports. Fails like
+    /// [`replace`](UserRepository::replace) on a duplicate account email.
+    fn import(&self, user: User) -> Result<(), StorageError>;
+
+    /// Creates a user that is also an account, with this password hash.
+    /// Fails with [`StorageError::DuplicateEmail`] if another account has
+    /// the same email; the check and the insert happen atomically.
+    fn create_account(&self, info: Info, hash: String) -> Result<User, StorageError>;
+
+    /// Sets the password hash of an existing user, turning the record into
+    /// an account that can log in. Returns `false` if there is no such user.
+    /// Fails with [`StorageError::DuplicateEmail`] if another account has
+    /// the user's email.
+    fn set_password_hash(&self, id: u32, hash: String) -> Result<bool, StorageError>;
+
+    /// The account whose email matches `email` (ignoring ASCII case),
+    /// together with its password hash. Users without a password are not
+    /// accounts and are never returned.
+    fn find_account(&self, email: &str) -> Result<Option<(User, String)>, StorageError>;
+
+    /// Stores a new API key with the hash of its secret.
+    fn create_api_key(&self, key: ApiKey, hash: String) -> Result<(), StorageError>;
+
+    /// Every API key, oldest first.
+    fn list_api_keys(&self) -> Result<Vec<ApiKey>, StorageError>;
+
+    /// The API key with this id, together with the hash of its secret.
+    fn get_api_key(&self, id: &str) -> Result<Option<(ApiKey, String)>, StorageError>;
+
+    /// Returns `false` if there was no key with that id.
+    fn delete_api_key(&self, id: &str) -> Result<bool, StorageError>;
+
+    /// Records that the key was used at `at`.
+    fn touch_api_key(&self, id: &str, at: DateTime<Utc>) -> Result<(), StorageError>;
+
+    /// Checks that the backend can currently serve requests.
+    fn ping(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// Makes every accepted write durable. Called on shutdown.
+    fn flush(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// Brings the schema up to date and returns how many migrations were
+    /// applied. Backends without a schema have nothing to do.
+    fn migrate(&self) -> Result<usize, StorageError> {
+        Ok(0)
+    }
+
+    /// Number of migrations [`migrate`](UserRepository::migrate) would apply.
+    fn pending_migrations(&self) -> Result<usize, StorageError> {
+        Ok(0)
+    }
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    Io(std::io::Error),
+    Sqlite(rusqlite::Error),
+    /// The backing file exists but could not be parsed.
+    Corrupt(String),
+    /// The storage setting names a backend we don't know about.
+    UnknownBackend(String),
+    /// Another account already has this email, compared ignoring ASCII
+    /// case. Emails only need to be unique among accounts.
+    DuplicateEmail(String),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Io(err) => write!(f, "storage I/O error: {}", err),
+            StorageError::Sqlite(err) => write!(f, "sqlite error: {}", err),
+            StorageError::Corrupt(msg) => write!(f, "corrupt storage file: {}", msg),
+            StorageError::UnknownBackend(spec) => write!(
+                f,
+                "unknown storage backend {:?} (expected memory, sqlite:<path> or json:<path>)",
+                spec
+            ),
+            StorageError::DuplicateEmail(email) => {
+                write!(f, "an account for {} already exists", email)
+            }
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<std::io::Error> for StorageError {
+    fn from(err: std::io::Error) -> Self {
+        StorageError::Io(err)
+    }
+}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(err: rusqlite::Error) -> Self {
+        StorageError::Sqlite(err)
+    }
+}
+
+/// A parsed storage setting: `memory`, `sqlite:<path>` or `json:<path>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Backend {
+    Memory,
+    Sqlite(PathBuf),
+    JsonFile(PathBuf),
+}
+
+impl FromStr for Backend {
+    type Err = StorageError;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        match spec.split_once(':') {
+            None if spec == "memory" => Ok(Backend::Memory),
+            Some(("sqlite", path)) if !path.is_empty() => Ok(Backend::Sqlite(path.into())),
+            Some(("json", path)) if !path.is_empty() => Ok(Backend::JsonFile(path.into())),
+            _ => Err(StorageError::UnknownBackend(spec.to_string())),
+        }
+    }
+}
+
+/// Connects to the backend described by `spec` without touching its schema.
+pub fn connect(spec: &str) -> Result<Arc<dyn UserRepository>, StorageError> {
+    Ok(match spec.parse()? {
+        Backend::Memory => Arc::new(MemoryRepository::default()),
+        Backend::Sqlite(path) => Arc::new(SqliteRepository::open(path)?),
+        Backend::JsonFile(path) => Arc::new(JsonFileRepository::open(path)?),
+    })
+}
+
+/// Connects to the backend described by `spec` and applies any pending
+/// migrations, as the server does at startup.
+pub fn open(spec: &str) -> Result<Arc<dyn UserRepository>, StorageError> {
+    let repository = connect(spec)?;
+    let applied = repository.migrate()?;
+    if applied > 0 {
+        log::info!("applied {} storage migration(s)", applied);
+    }
+    Ok(repository)
+}
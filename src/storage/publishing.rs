@@ -0,0 +1,252 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+
+use crate::events::Events;
+use crate::models::{ApiKey, Info, User, UserUpdate};
+
+use super::{StorageError, UserPage, UserQuery, UserRepository};
+
+/// Wraps a backend so that every successful change to a user publishes a
+/// change event, whichever route made it.
+///
+/// Changes are made one at a time, each together with its event, so event
+/// ids follow the order the backend stored them in. Otherwise two writes to
+/// one user could be stored in one order and published in the other,
+/// leaving subscribers with the older state.
+pub struct PublishingRepository {
+    inner: Arc<dyn UserRepository>,
+    events: Arc<Events>,
+    writing: Mutex<()>,
+}
+
+impl PublishingRepository {
+    pub fn new(inner: Arc<dyn UserRepository>, events: Arc<Events>) -> Self {
+        PublishingRepository {
+            inner,
+            events,
+            writing: Mutex::new(()),
+        }
+    }
+}
+
+impl UserRepository for PublishingRepository {
+    fn create(&self, info: Info) -> Result<User, StorageError> {
+        let _writing = self.writing.lock().unwrap();
+        let user = self.inner.create(info)?;
+        self.events.created(&user);
+        Ok(user)
+    }
+
+    fn list(&self) -> Result<Vec<User>, StorageError> {
+        self.inner.list()
+    }
+
+    fn search(&self, query: &UserQuery) -> Result<UserPage, StorageError> {
+        self.inner.search(query)
+    }
+
+    fn get(&self, id: u32) -> Result<Option<User>, StorageError> {
+        self.inner.get(id)
+    }
+
+    fn replace(&self, id: u32, update: UserUpdate) -> Result<Option<User>, StorageError> {
+        let _writing = self.writing.lock().unwrap();
+        let user = self.inner.replace(id, update)?;
+        if let Some(user) = &user {
+            self.events.updated(user);
+        }
+        Ok(user)
+    }
+
+    fn delete(&self, id: u32) -> Result<bool, StorageError> {
+        let _writing = self.writing.lock().unwrap();
+        let deleted = self.inner.delete(id)?;
+        if deleted {
+            self.events.deleted(id);
+        }
+        Ok(deleted)
+    }
+
+    /// Not published: only the `import` command restores users, and no
+    /// server is listening then.
+    fn import(&self, user: User) -> Result<(), StorageError> {
+        self.inner.import(user)
+    }
+
+    fn create_account(&self, info: Info, hash: String) -> Result<User, StorageError> {
+        let _writing = self.writing.lock().unwrap();
+        let user = self.inner.create_account(info, hash)?;
+        self.events.created(&user);
+        Ok(user)
+    }
+
+    fn set_password_hash(&self, id: u32, hash: String) -> Result<bool, StorageError> {
+        self.inner.set_password_hash(id, hash)
+    }
+
+    fn find_account(&self, email: &str) -> Result<Option<(User, String)>, StorageError> {
+        self.inner.find_account(email)
+    }
+
+    fn create_api_key(&self, key: ApiKey, hash: String) -> Result<(), StorageError> {
+        self.inner.create_api_key(key, hash)
+    }
+
+    fn list_api_keys(&self) -> Result<Vec<ApiKey>, StorageError> {
+        self.inner.list_api_keys()
+    }
+
+    fn get_api_key(&self, id: &str) -> Result<Option<(ApiKey, String)>, StorageError> {
+        self.inner.get_api_key(id)
+    }
+
+    fn delete_api_key(&self, id: &str) -> Result<bool, StorageError> {
+        self.inner.delete_api_key(id)
+    }
+
+    fn touch_api_key(&self, id: &str, at: DateTime<Utc>) -> Result<(), StorageError> {
+        self.inner.touch_api_key(id, at)
+    }
+
+    fn ping(&self) -> Result<(), StorageError> {
+        self.inner.ping()
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.inner.flush()
+    }
+
+    fn migrate(&self) -> Result<usize, StorageError> {
+        self.inner.migrate()
+    }
+
+    fn pending_migrations(&self) -> Result<usize, StorageError> {
+        self.inner.pending_migrations()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::config::EventsConfig;
+    use crate::storage::MemoryRepository;
+
+    /// A backend that stalls after storing a user named "Stalled", as if
+    /// the thread were preempted between the write and its event.
+    struct Stalling {
+        inner: MemoryRepository,
+        stored: Mutex<mpsc::Sender<()>>,
+    }
+
+    impl UserRepository for Stalling {
+        fn create(&self, info: Info) -> Result<User, StorageError> {
+            self.inner.create(info)
+        }
+
+        fn list(&self) -> Result<Vec<User>, StorageError> {
+            self.inner.list()
+        }
+
+        fn get(&self, id: u32) -> Result<Option<User>, StorageError> {
+            self.inner.get(id)
+        }
+
+        fn replace(&self, id: u32, update: UserUpdate) -> Result<Option<User>, StorageError> {
+            let stall = update.name == "Stalled";
+            let user = self.inner.replace(id, update)?;
+            if stall {
+                self.stored.lock().unwrap().send(()).unwrap();
+                thread::sleep(Duration::from_millis(100));
+            }
+            Ok(user)
+        }
+
+        fn delete(&self, id: u32) -> Result<bool, StorageError> {
+            self.inner.delete(id)
+        }
+
+        fn import(&self, user: User) -> Result<(), StorageError> {
+            self.inner.import(user)
+        }
+
+        fn create_account(&self, info: Info, hash: String) -> Result<User, StorageError> {
+            self.inner.create_account(info, hash)
+        }
+
+        fn set_password_hash(&self, id: u32, hash: String) -> Result<bool, StorageError> {
+            self.inner.set_password_hash(id, hash)
+        }
+
+        fn find_account(&self, email: &str) -> Result<Option<(User, String)>, StorageError> {
+            self.inner.find_account(email)
+        }
+
+        fn create_api_key(&self, key: ApiKey, hash: String) -> Result<(), StorageError> {
+            self.inner.create_api_key(key, hash)
+        }
+
+        fn list_api_keys(&self) -> Result<Vec<ApiKey>, StorageError> {
+            self.inner.list_api_keys()
+        }
+
+        fn get_api_key(&self, id: &str) -> Result<Option<(ApiKey, String)>, StorageError> {
+            self.inner.get_api_key(id)
+        }
+
+        fn delete_api_key(&self, id: &str) -> Result<bool, StorageError> {
+            self.inner.delete_api_key(id)
+        }
+
+        fn touch_api_key(&self, id: &str, at: DateTime<Utc>) -> Result<(), StorageError> {
+            self.inner.touch_api_key(id, at)
+        }
+    }
+
+    fn renamed(name: &str) -> UserUpdate {
+        UserUpdate {
+            id: None,
+            name: name.to_string(),
+            age: None,
+            email: None,
+            role: None,
+        }
+    }
+
+    #[test]
+    fn events_are_published_in_the_order_changes_were_stored() {
+        let (stored, stalled) = mpsc::channel();
+        let backend = Stalling {
+            inner: MemoryRepository::default(),
+            stored: Mutex::new(stored),
+        };
+        let events = Arc::new(Events::new(&EventsConfig::default()));
+        let repository = PublishingRepository::new(Arc::new(backend), events.clone());
+        let id = repository
+            .create(Info {
+                name: "Ada".to_string(),
+                age: None,
+                email: None,
+                role: None,
+            })
+            .unwrap()
+            .id;
+
+        thread::scope(|scope| {
+            scope.spawn(|| repository.replace(id, renamed("Stalled")).unwrap());
+            // Stored second, while the first write has yet to publish.
+            stalled.recv().unwrap();
+            scope.spawn(|| repository.replace(id, renamed("Latest")).unwrap());
+        });
+
+        let published: Vec<_> = (events.resume(Some(0)).missed.unwrap().iter())
+            .map(|event| event.user.as_ref().unwrap().name.clone())
+            .collect();
+        assert_eq!(published, ["Ada", "Stalled", "Latest"]);
+        assert_eq!(repository.get(id).unwrap().unwrap().name, "Latest");
+    }
+}
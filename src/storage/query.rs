@@ -0,0 +1,484 @@
+//! Filtering, sorting and pagination for `GET /users`.
+//!
+//! [`UserQuery::apply`] is the reference implementation over an in-memory
+//! list; backends that can do better (SQLite) translate the same query into
+//! their own language and must produce the same pages.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+use crate::models::User;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortField {
+    Id,
+    Name,
+    Age,
+    Email,
+}
+
+impl SortField {
+    fn name(self) -> &'static str {
+        match self {
+            SortField::Id => "id",
+            SortField::Name => "name",
+            SortField::Age => "age",
+            SortField::Email => "email",
+        }
+    }
+
+    /// The value this field sorts by. Missing ages sort as `-1` and missing
+    /// emails as `""`, so every key is totally ordered.
+    pub fn value_of(self, user: &User) -> SortValue {
+        match self {
+            SortField::Id => SortValue::Int(user.id.into()),
+            SortField::Name => SortValue::Text(user.name.clone()),
+            SortField::Age => SortValue::Int(user.age.map_or(-1, i64::from)),
+            SortField::Email => SortValue::Text(user.email.clone().unwrap_or_default()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SortValue {
+    Int(i64),
+    Text(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SortKey {
+    pub field: SortField,
+    pub descending: bool,
+}
+
+/// A sort order such as `name,-age`. Always ends with `id` so that the order
+/// is total and cursors are unambiguous.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sort(Vec<SortKey>);
+
+impl Sort {
+    pub fn keys(&self) -> &[SortKey] {
+        &self.0
+    }
+
+    fn key_of(&self, user: &User) -> Vec<SortValue> {
+        self.0.iter().map(|key| key.field.value_of(user)).collect()
+    }
+
+    fn compare(&self, a: &[SortValue], b: &[SortValue]) -> Ordering {
+        for (key, (a, b)) in self.0.iter().zip(a.iter().zip(b)) {
+            let ordering = if key.descending { b.cmp(a) } else { a.cmp(b) };
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        Ordering::Equal
+    }
+}
+
+impl Default for Sort {
+    fn default() -> Self {
+        Sort(vec![SortKey {
+            field: SortField::Id,
+            descending: false,
+        }])
+    }
+}
+
+impl FromStr for Sort {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut keys: Vec<SortKey> = Vec::new();
+        for part in spec
+            .split(',')
+            .map(str::trim)
+            .filter(|part| !part.is_empty())
+        {
+            let (descending, name) = match part.strip_prefix('-') {
+                Some(name) => (true, name),
+                None => (false, part.strip_prefix('+').unwrap_or(part)),
+            };
+            let field = match name {
+                "id" => SortField::Id,
+                "name" => SortField::Name,
+                "age" => SortField::Age,
+                "email" => SortField::Email,
+                _ => return Err(format!("cannot sort by {:?}", name)),
+            };
+            if keys.iter().any(|key| key.field == field) {
+                return Err(format!("{:?} appears more than once in sort", name));
+            }
+            keys.push(SortKey { field, descending });
+        }
+        if !keys.iter().any(|key| key.field == SortField::Id) {
+            keys.push(SortKey {
+                field: SortField::Id,
+                descending: false,
+            });
+        }
+        Ok(Sort(keys))
+    }
+}
+
+impl fmt::Display for Sort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, key) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            if key.descending {
+                f.write_str("-")?;
+            }
+            f.write_str(key.field.name())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct UserFilter {
+    /// Case-insensitive (ASCII) substring match.
+    pub name_contains: Option<String>,
+    /// Case-insensitive (ASCII) substring match.
+    pub email_contains: Option<String>,
+    pub min_age: Option<u8>,
+    pub max_age: Option<u8>,
+}
+
+impl UserFilter {
+    pub fn matches(&self, user: &User) -> bool {
+        fn contains(haystack: &str, needle: &str) -> bool {
+            haystack
+                .to_ascii_lowercase()
+                .contains(&needle.to_ascii_lowercase())
+        }
+
+        self.name_contains
+            .as_deref()
+            .is_none_or(|needle| contains(&user.name, needle))
+            && self.email_contains.as_deref().is_none_or(|needle| {
+                user.email
+                    .as_deref()
+                    .is_some_and(|email| contains(email, needle))
+            })
+            && self
+                .min_age
+                .is_none_or(|min| user.age.is_some_and(|age| age >= min))
+            && self
+                .max_age
+                .is_none_or(|max| user.age.is_some_and(|age| age <= max))
+    }
+}
+
+/// Position just after a given user in a given sort order. Clients only ever
+/// see it as the opaque `after` token.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Cursor {
+    pub key: Vec<SortValue>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CursorToken {
+    sort: String,
+    key: Vec<SortValue>,
+}
+
+impl Cursor {
+    pub fn encode(&self, sort: &Sort) -> String {
+        let token = CursorToken {
+            sort: sort.to_string(),
+            key: self.key.clone(),
+        };
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(&token).unwrap())
+    }
+
+    /// Decodes a token produced by [`Cursor::encode`] for the same sort order.
+    pub fn decode(token: &str, sort: &Sort) -> Result<Self, String> {
+        let invalid = || "invalid after cursor".to_string();
+        let bytes = URL_SAFE_NO_PAD.decode(token).map_err(|_| invalid())?;
+        let token: CursorToken = serde_json::from_slice(&bytes).map_err(|_| invalid())?;
+        if token.sort != sort.to_string() {
+            return Err("after cursor was issued for a different sort order".to_string());
+        }
+        if token.key.len() != sort.keys().len() {
+            return Err(invalid());
+        }
+        Ok(Cursor { key: token.key })
+    }
+}
+
+pub struct UserQuery {
+    pub filter: UserFilter,
+    pub sort: Sort,
+    pub after: Option<Cursor>,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+pub struct UserPage {
+    pub items: Vec<User>,
+    /// Number of users matching the filter, ignoring pagination.
+    pub total: u64,
+    /// Set when there are more users after this page.
+    pub next: Option<Cursor>,
+}
+
+impl UserQuery {
+    pub fn apply(&self, users: Vec<User>) -> UserPage {
+        let mut matching: Vec<(Vec<SortValue>, User)> = users
+            .into_iter()
+            .filter(|user| self.filter.matches(user))
+            .map(|user| (self.sort.key_of(&user), user))
+            .collect();
+        let total = matching.len() as u64;
+        matching.sort_by(|(a, _), (b, _)| self.sort.compare(a, b));
+
+        let start = match &self.after {
+            Some(cursor) => matching.partition_point(|(key, _)| {
+                self.sort.compare(key, &cursor.key) != Ordering::Greater
+            }),
+            None => 0,
+        };
+        let page: Vec<_> = matching
+            .into_iter()
+            .skip(start + self.offset)
+            .take(self.limit + 1)
+            .collect();
+        self.page(page.into_iter().map(|(_, user)| user).collect(), total)
+    }
+
+    /// Builds the page from up to `limit + 1` users in sort order; the extra
+    /// one only signals that there is a next page.
+    pub fn page(&self, mut items: Vec<User>, total: u64) -> UserPage {
+        let next = if items.len() > self.limit {
+            items.truncate(self.limit);
+            items.last().map(|last| Cursor {
+                key: self.sort.key_of(last),
+            })
+        } else {
+            None
+        };
+        UserPage { items, total, next }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Info;
+
+    fn key(field: SortField, descending: bool) -> SortKey {
+        SortKey { field, descending }
+    }
+
+    fn user(id: u32, name: &str, age: Option<u8>) -> User {
+        let info = Info {
+            name: name.to_string(),
+            age,
+            email: None,
+            role: None,
+        };
+        User::new(id, info)
+    }
+
+    fn users() -> Vec<User> {
+        vec![
+            user(1, "Ada", Some(36)),
+            user(2, "Grace", Some(45)),
+            user(3, "Alan", None),
+            user(4, "Ada", Some(45)),
+            user(5, "Edsger", Some(36)),
+        ]
+    }
+
+    fn query(sort: &str, after: Option<Cursor>, offset: usize, limit: usize) -> UserQuery {
+        UserQuery {
+            filter: UserFilter::default(),
+            sort: sort.parse().unwrap(),
+            after,
+            offset,
+            limit,
+        }
+    }
+
+    fn ids(page: &UserPage) -> Vec<u32> {
+        page.items.iter().map(|user| user.id).collect()
+    }
+
+    #[test]
+    fn sort_parses_fields_and_directions() {
+        let cases: &[(&str, &[SortKey])] = &[
+            ("", &[key(SortField::Id, false)]),
+            ("id", &[key(SortField::Id, false)]),
+            ("-id", &[key(SortField::Id, true)]),
+            ("+id", &[key(SortField::Id, false)]),
+            (
+                "name,-age",
+                &[
+                    key(SortField::Name, false),
+                    key(SortField::Age, true),
+                    key(SortField::Id, false),
+                ],
+            ),
+            (
+                " +email , -id ,age",
+                &[
+                    key(SortField::Email, false),
+                    key(SortField::Id, true),
+                    key(SortField::Age, false),
+                ],
+            ),
+            (
+                ",name,",
+                &[key(SortField::Name, false), key(SortField::Id, false)],
+            ),
+        ];
+        for (spec, keys) in cases {
+            let sort: Sort = spec.parse().unwrap();
+            assert_eq!(sort.keys(), *keys, "{:?}", spec);
+        }
+    }
+
+    #[test]
+    fn sort_rejects_unknown_and_repeated_fields() {
+        let cases = [
+            ("height", "cannot sort by \"height\""),
+            ("name,-Name", "cannot sort by \"Name\""),
+            ("--name", "cannot sort by \"-name\""),
+            ("name,-name", "\"name\" appears more than once in sort"),
+            ("+age,age", "\"age\" appears more than once in sort"),
+            ("id,-id", "\"id\" appears more than once in sort"),
+        ];
+        for (spec, error) in cases {
+            assert_eq!(spec.parse::<Sort>(), Err(error.to_string()), "{:?}", spec);
+        }
+    }
+
+    #[test]
+    fn sort_displays_in_canonical_form() {
+        for (spec, canonical) in [("", "id"), ("+name", "name,id"), ("-age,+id", "-age,id")] {
+            assert_eq!(spec.parse::<Sort>().unwrap().to_string(), canonical);
+        }
+    }
+
+    #[test]
+    fn cursor_round_trips() {
+        let sort: Sort = "-age,name".parse().unwrap();
+        let cursor = Cursor {
+            key: vec![
+                SortValue::Int(45),
+                SortValue::Text("Ada".to_string()),
+                SortValue::Int(4),
+            ],
+        };
+        assert_eq!(Cursor::decode(&cursor.encode(&sort), &sort), Ok(cursor));
+    }
+
+    #[test]
+    fn cursor_rejects_bad_tokens() {
+        let sort: Sort = "name".parse().unwrap();
+        let token = |sort: &str, key: Vec<SortValue>| {
+            let token = CursorToken {
+                sort: sort.to_string(),
+                key,
+            };
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&token).unwrap())
+        };
+        let name = || SortValue::Text("Ada".to_string());
+        let invalid = "invalid after cursor";
+        let cases = [
+            ("not base64!".to_string(), invalid),
+            (URL_SAFE_NO_PAD.encode("not json"), invalid),
+            (URL_SAFE_NO_PAD.encode(r#"{"key": []}"#), invalid),
+            (
+                token("-name,id", vec![name(), SortValue::Int(1)]),
+                "after cursor was issued for a different sort order",
+            ),
+            (
+                token("id", vec![SortValue::Int(1)]),
+                "after cursor was issued for a different sort order",
+            ),
+            (token("name,id", vec![name()]), invalid),
+            (
+                token(
+                    "name,id",
+                    vec![name(), SortValue::Int(1), SortValue::Int(2)],
+                ),
+                invalid,
+            ),
+        ];
+        for (token, error) in cases {
+            assert_eq!(
+                Cursor::decode(&token, &sort),
+                Err(error.to_string()),
+                "{:?}",
+                token
+            );
+        }
+    }
+
+    #[test]
+    fn apply_sorts_with_the_id_tiebreak_and_missing_values_first() {
+        let cases = [
+            ("id", vec![1, 2, 3, 4, 5]),
+            ("-id", vec![5, 4, 3, 2, 1]),
+            ("name", vec![1, 4, 3, 5, 2]),
+            ("age", vec![3, 1, 5, 2, 4]),
+            ("-age", vec![2, 4, 1, 5, 3]),
+            ("-age,-id", vec![4, 2, 5, 1, 3]),
+        ];
+        for (sort, expected) in cases {
+            let page = query(sort, None, 0, 10).apply(users());
+            assert_eq!(ids(&page), expected, "{}", sort);
+        }
+    }
+
+    #[test]
+    fn apply_sets_next_only_when_the_page_overflows() {
+        let cases = [(4, Some(4)), (5, None), (6, None)];
+        for (limit, last) in cases {
+            let page = query("id", None, 0, limit).apply(users());
+            assert_eq!(page.total, 5);
+            let next = page.next.map(|cursor| cursor.key);
+            assert_eq!(
+                next,
+                last.map(|id| vec![SortValue::Int(id)]),
+                "limit {}",
+                limit
+            );
+        }
+        let page = query("id", None, 5, 10).apply(users());
+        assert!(page.items.is_empty() && page.next.is_none());
+    }
+
+    #[test]
+    fn apply_counts_the_offset_from_the_cursor() {
+        let first = query("name", None, 0, 2).apply(users());
+        assert_eq!(ids(&first), [1, 4]);
+        let after = first.next.unwrap();
+        assert_eq!(
+            after.key,
+            [SortValue::Text("Ada".to_string()), SortValue::Int(4)]
+        );
+
+        let page = query("name", Some(after), 1, 2).apply(users());
+        assert_eq!(ids(&page), [5, 2]);
+        assert_eq!(page.total, 5);
+        assert!(page.next.is_none());
+
+        // A cursor for a user that has since been deleted still marks the
+        // position in the order.
+        let gone = Cursor {
+            key: vec![SortValue::Text("Al".to_string()), SortValue::Int(9)],
+        };
+        let page = query("name", Some(gone), 0, 2).apply(users());
+        assert_eq!(ids(&page), [3, 5]);
+    }
+}
@@ -0,0 +1,484 @@
+use std::path::Path;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, Value, ValueRef};
+use rusqlite::{params, params_from_iter, Connection, ErrorCode, OptionalExtension, Row, ToSql};
+
+use super::query::{SortField, SortValue};
+use super::{StorageError, UserPage, UserQuery, UserRepository};
+use crate::models::{ApiKey, Info, Role, User, UserUpdate};
+
+/// Schema migrations, applied in order. The number of applied migrations is
+/// tracked in `PRAGMA user_version`, so only ever append to this list.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE users (
+        id   INTEGER PRIMARY KEY AUTOINCREMENT,
+        name TEXT NOT NULL,
+        age  TEXT NOT NULL DEFAULT ''
+    );",
+    // Typed age, optional email and timestamps. Ages that were stored as
+    // free text and don't parse as a number are dropped. The id sequence is
+    // carried over so ids of deleted users stay retired.
+    "CREATE TABLE users_v2 (
+        id         INTEGER PRIMARY KEY AUTOINCREMENT,
+        name       TEXT NOT NULL,
+        age        INTEGER,
+        email      TEXT,
+        created_at TEXT NOT NULL,
+        updated_at TEXT NOT NULL
+    );
+    INSERT INTO users_v2 (id, name, age, created_at, updated_at)
+        SELECT id, name,
+               CASE WHEN age GLOB '[0-9]*' AND age NOT GLOB '*[^0-9]*' THEN CAST(age AS INTEGER) END,
+               strftime('%Y-%m-%dT%H:%M:%fZ', 'now'),
+               strftime('%Y-%m-%dT%H:%M:%fZ', 'now')
+        FROM users;
+    DELETE FROM sqlite_sequence WHERE name = 'users_v2';
+    UPDATE sqlite_sequence SET name = 'users_v2' WHERE name = 'users';
+    DROP TABLE users;
+    ALTER TABLE users_v2 RENAME TO users;",
+    // Accounts: users with a password can log in.
+    "ALTER TABLE users ADD COLUMN password_hash TEXT;",
+    // Roles for the access policy; existing users become viewers.
+    "ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'viewer';",
+    // API keys; scopes are stored space-separated.
+    "CREATE TABLE api_keys (
+        id           TEXT PRIMARY KEY,
+        name         TEXT NOT NULL,
+        hash         TEXT NOT NULL,
+        scopes       TEXT NOT NULL,
+        role         TEXT NOT NULL,
+        created_at   TEXT NOT NULL,
+        expires_at   TEXT,
+        last_used_at TEXT
+    );",
+    // One account per email, ignoring ASCII case as `find_account` does.
+    // Fails, and leaves the database as it was, if two accounts already
+    // share an email.
+    "CREATE UNIQUE INDEX users_account_email ON users (lower(email))
+        WHERE password_hash IS NOT NULL;",
+];
+
+const COLUMNS: &str = "id, name, age, email, role, created_at, updated_at";
+
+const API_KEY_COLUMNS: &str = "id, name, scopes, role, created_at, expires_at, last_used_at, hash";
+
+/// Embedded SQLite database; no external server needed.
+pub struct SqliteRepository {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteRepository {
+    /// Opens (or creates) the database at `path`. The schema is left alone
+    /// until [`UserRepository::migrate`] is called.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StorageError> {
+        Ok(SqliteRepository {
+            conn: Mutex::new(Connection::open(path)?),
+        })
+    }
+}
+
+/// Number of migrations already applied to `conn`.
+fn schema_version(conn: &Connection) -> Result<usize, StorageError> {
+    let applied: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if applied > MIGRATIONS.len() {
+        return Err(StorageError::Corrupt(format!(
+            "database schema version {} is newer than this build supports ({})",
+            applied,
+            MIGRATIONS.len()
+        )));
+    }
+    Ok(applied)
+}
+
+/// Reports a violation of the `users_account_email` index, the only unique
+/// constraint user writes can break, as a duplicate `email`.
+fn duplicate_email(err: rusqlite::Error, email: Option<&str>) -> StorageError {
+    match (err.sqlite_error_code(), email) {
+        (Some(ErrorCode::ConstraintViolation), Some(email)) => {
+            StorageError::DuplicateEmail(email.to_string())
+        }
+        _ => err.into(),
+    }
+}
+
+fn user_from_row(row: &Row<'_>) -> rusqlite::Result<User> {
+    Ok(User {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        age: row.get(2)?,
+        email: row.get(3)?,
+        role: row.get(4)?,
+        created_at: row.get(5)?,
+        updated_at: row.get(6)?,
+    })
+}
+
+/// An API key and its hash, from a row of [`API_KEY_COLUMNS`].
+fn api_key_from_row(row: &Row<'_>) -> rusqlite::Result<(ApiKey, String)> {
+    let scopes: String = row.get(2)?;
+    let key = ApiKey {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        scopes: scopes.split_whitespace().map(str::to_string).collect(),
+        role: row.get(3)?,
+        created_at: row.get(4)?,
+        expires_at: row.get(5)?,
+        last_used_at: row.get(6)?,
+    };
+    Ok((key, row.get(7)?))
+}
+
+impl ToSql for Role {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for Role {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|err: String| FromSqlError::Other(err.into()))
+    }
+}
+
+/// Column expression matching [`SortField::value_of`], NULLs included.
+fn sort_expr(field: SortField) -> &'static str {
+    match field {
+        SortField::Id => "id",
+        SortField::Name => "name",
+        SortField::Age => "COALESCE(age, -1)",
+        SortField::Email => "COALESCE(email, '')",
+    }
+}
+
+fn sql_value(value: &SortValue) -> Value {
+    match value {
+        SortValue::Int(n) => Value::Integer(*n),
+        SortValue::Text(s) => Value::Text(s.clone()),
+    }
+}
+
+/// Builds the `WHERE` clause for the filter, and for the cursor when
+/// `with_cursor` is set, pushing bound values onto `params`.
+fn where_clause(query: &UserQuery, with_cursor: bool, params: &mut Vec<Value>) -> String {
+    let mut conditions = Vec::new();
+    let filter = &query.filter;
+    if let Some(needle) = &filter.name_contains {
+        params.push(Value::Text(needle.to_ascii_lowercase()));
+        conditions.push(format!("instr(lower(name), ?{}) > 0", params.len()));
+    }
+    if let Some(needle) = &filter.email_contains {
+        params.push(Value::Text(needle.to_ascii_lowercase()));
+        conditions.push(format!("instr(lower(email), ?{}) > 0", params.len()));
+    }
+    if let Some(min) = filter.min_age {
+        params.push(Value::Integer(min.into()));
+        conditions.push(format!("age >= ?{}", params.len()));
+    }
+    if let Some(max) = filter.max_age {
+        params.push(Value::Integer(max.into()));
+        conditions.push(format!("age <= ?{}", params.len()));
+    }
+
+    // Keyset condition: (k1 > v1) OR (k1 = v1 AND k2 > v2) OR ..., with the
+    // comparison flipped for descending keys.
+    if let (true, Some(cursor)) = (with_cursor, &query.after) {
+        let mut alternatives = Vec::new();
+        let keys = query.sort.keys();
+        for (i, key) in keys.iter().enumerate() {
+            let mut terms = Vec::new();
+            for (prefix, value) in keys[..i].iter().zip(&cursor.key) {
+                params.push(sql_value(value));
+                terms.push(format!("{} = ?{}", sort_expr(prefix.field), params.len()));
+            }
+            params.push(sql_value(&cursor.key[i]));
+            let op = if key.descending { "<" } else { ">" };
+            terms.push(format!("{} {} ?{}", sort_expr(key.field), op, params.len()));
+            alternatives.push(format!("({})", terms.join(" AND ")));
+        }
+        conditions.push(format!("({})", alternatives.join(" OR ")));
+    }
+
+    if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", conditions.join(" AND "))
+    }
+}
+
+impl UserRepository for SqliteRepository {
+    fn create(&self, info: Info) -> Result<User, StorageError> {
+        let conn = self.conn.lock().unwrap();
+        let now = Utc::now();
+        let user = conn.query_row(
+            &format!(
+                "INSERT INTO users (name, age, email, role, created_at, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?5) RETURNING {COLUMNS}"
+            ),
+            params![
+                info.name,
+                info.age,
+                info.email,
+                info.role.unwrap_or_default(),
+                now
+            ],
+            user_from_row,
+        )?;
+        Ok(user)
+    }
+
+    fn list(&self) -> Result<Vec<User>, StorageError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached(&format!("SELECT {COLUMNS} FROM users ORDER BY id"))?;
+        let users = stmt
+            .query_map([], user_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(users)
+    }
+
+    fn search(&self, query: &UserQuery) -> Result<UserPage, StorageError> {
+        let conn = self.conn.lock().unwrap();
+
+        let mut params = Vec::new();
+        let filter = where_clause(query, false, &mut params);
+        let total: u64 = conn.query_row(
+            &format!("SELECT COUNT(*) FROM users {filter}"),
+            params_from_iter(&params),
+            |row| row.get(0),
+        )?;
+
+        let mut params = Vec::new();
+        let filter = where_clause(query, true, &mut params);
+        let order = query
+            .sort
+            .keys()
+            .iter()
+            .map(|key| {
+                let direction = if key.descending { "DESC" } else { "ASC" };
+                format!("{} {}", sort_expr(key.field), direction)
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        params.push(Value::Integer(query.limit as i64 + 1));
+        let limit = params.len();
+        params.push(Value::Integer(query.offset as i64));
+        let offset = params.len();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {COLUMNS} FROM users {filter} ORDER BY {order} LIMIT ?{limit} OFFSET ?{offset}"
+        ))?;
+        let items = stmt
+            .query_map(params_from_iter(&params), user_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(query.page(items, total))
+    }
+
+    fn get(&self, id: u32) -> Result<Option<User>, StorageError> {
+        let conn = self.conn.lock().unwrap();
+        let user = conn
+            .query_row(
+                &format!("SELECT {COLUMNS} FROM users WHERE id = ?1"),
+                params![id],
+                user_from_row,
+            )
+            .optional()?;
+        Ok(user)
+    }
+
+    fn replace(&self, id: u32, update: UserUpdate) -> Result<Option<User>, StorageError> {
+        let conn = self.conn.lock().unwrap();
+        let user = conn
+            .query_row(
+                &format!(
+                    "UPDATE users SET name = ?2, age = ?3, email = ?4,
+                         role = COALESCE(?5, role), updated_at = ?6
+                     WHERE id = ?1 RETURNING {COLUMNS}"
+                ),
+                params![
+                    id,
+                    update.name,
+                    update.age,
+                    update.email,
+                    update.role,
+                    Utc::now()
+                ],
+                user_from_row,
+            )
+            .optional()
+            .map_err(|err| duplicate_email(err, update.email.as_deref()))?;
+        Ok(user)
+    }
+
+    fn delete(&self, id: u32) -> Result<bool, StorageError> {
+        let conn = self.conn.lock().unwrap();
+        let deleted = conn.execute("DELETE FROM users WHERE id = ?1", params![id])?;
+        Ok(deleted > 0)
+    }
+
+    fn import(&self, user: User) -> Result<(), StorageError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            // An upsert rather than INSERT OR REPLACE, which would delete the
+            // row and with it any password hash.
+            &format!(
+                "INSERT INTO users ({COLUMNS}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                 ON CONFLICT (id) DO UPDATE SET name = excluded.name, age = excluded.age,
+                     email = excluded.email, role = excluded.role,
+                     created_at = excluded.created_at,
+                     updated_at = excluded.updated_at"
+            ),
+            params![
+                user.id,
+                user.name,
+                user.age,
+                user.email,
+                user.role,
+                user.created_at,
+                user.updated_at
+            ],
+        )
+        .map_err(|err| duplicate_email(err, user.email.as_deref()))?;
+        Ok(())
+    }
+
+    fn create_account(&self, info: Info, hash: String) -> Result<User, StorageError> {
+        let conn = self.conn.lock().unwrap();
+        let now = Utc::now();
+        let user = conn
+            .query_row(
+                &format!(
+                    "INSERT INTO users (name, age, email, role, created_at, updated_at, password_hash)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?5, ?6) RETURNING {COLUMNS}"
+                ),
+                params![
+                    info.name,
+                    info.age,
+                    info.email,
+                    info.role.unwrap_or_default(),
+                    now,
+                    hash
+                ],
+                user_from_row,
+            )
+            .map_err(|err| duplicate_email(err, info.email.as_deref()))?;
+        Ok(user)
+    }
+
+    fn set_password_hash(&self, id: u32, hash: String) -> Result<bool, StorageError> {
+        let conn = self.conn.lock().unwrap();
+        let updated = conn
+            .execute(
+                "UPDATE users SET password_hash = ?2 WHERE id = ?1",
+                params![id, hash],
+            )
+            .map_err(|err| {
+                let email: Option<String> = conn
+                    .query_row("SELECT email FROM users WHERE id = ?1", [id], |row| {
+                        row.get(0)
+                    })
+                    .unwrap_or_default();
+                duplicate_email(err, email.as_deref())
+            })?;
+        Ok(updated > 0)
+    }
+
+    fn find_account(&self, email: &str) -> Result<Option<(User, String)>, StorageError> {
+        let conn = self.conn.lock().unwrap();
+        let account = conn
+            .query_row(
+                &format!(
+                    "SELECT {COLUMNS}, password_hash FROM users
+                     WHERE lower(email) = lower(?1) AND password_hash IS NOT NULL
+                     ORDER BY id LIMIT 1"
+                ),
+                params![email],
+                |row| Ok((user_from_row(row)?, row.get(7)?)),
+            )
+            .optional()?;
+        Ok(account)
+    }
+
+    fn create_api_key(&self, key: ApiKey, hash: String) -> Result<(), StorageError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            &format!(
+                "INSERT INTO api_keys ({API_KEY_COLUMNS}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)"
+            ),
+            params![
+                key.id,
+                key.name,
+                key.scopes.join(" "),
+                key.role,
+                key.created_at,
+                key.expires_at,
+                key.last_used_at,
+                hash
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn list_api_keys(&self) -> Result<Vec<ApiKey>, StorageError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT {API_KEY_COLUMNS} FROM api_keys ORDER BY created_at"
+        ))?;
+        let keys = stmt
+            .query_map([], |row| Ok(api_key_from_row(row)?.0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(keys)
+    }
+
+    fn get_api_key(&self, id: &str) -> Result<Option<(ApiKey, String)>, StorageError> {
+        let conn = self.conn.lock().unwrap();
+        let key = conn
+            .query_row(
+                &format!("SELECT {API_KEY_COLUMNS} FROM api_keys WHERE id = ?1"),
+                params![id],
+                api_key_from_row,
+            )
+            .optional()?;
+        Ok(key)
+    }
+
+    fn delete_api_key(&self, id: &str) -> Result<bool, StorageError> {
+        let conn = self.conn.lock().unwrap();
+        let deleted = conn.execute("DELETE FROM api_keys WHERE id = ?1", params![id])?;
+        Ok(deleted > 0)
+    }
+
+    fn touch_api_key(&self, id: &str, at: DateTime<Utc>) -> Result<(), StorageError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE api_keys SET last_used_at = ?2 WHERE id = ?1",
+            params![id, at],
+        )?;
+        Ok(())
+    }
+
+    fn ping(&self) -> Result<(), StorageError> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row("SELECT 1", [], |_| Ok(()))?;
+        Ok(())
+    }
+
+    fn migrate(&self) -> Result<usize, StorageError> {
+        let mut conn = self.conn.lock().unwrap();
+        let applied = schema_version(&conn)?;
+        for (version, migration) in MIGRATIONS.iter().enumerate().skip(applied) {
+            let tx = conn.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", version + 1)?;
+            tx.commit()?;
+        }
+        Ok(MIGRATIONS.len() - applied)
+    }
+
+    fn pending_migrations(&self) -> Result<usize, StorageError> {
+        let conn = self.conn.lock().unwrap();
+        Ok(MIGRATIONS.len() - schema_version(&conn)?)
+    }
+}
@@ -0,0 +1,95 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+
+use crate::models::{ApiKey, Info, User, UserUpdate};
+use crate::telemetry::try_in_span;
+
+use super::{StorageError, UserPage, UserQuery, UserRepository};
+
+/// Wraps a backend so that every call runs in its own `storage.*` span.
+pub struct TracedRepository(pub Arc<dyn UserRepository>);
+
+impl UserRepository for TracedRepository {
+    fn create(&self, info: Info) -> Result<User, StorageError> {
+        try_in_span("storage.create", || self.0.create(info))
+    }
+
+    fn list(&self) -> Result<Vec<User>, StorageError> {
+        try_in_span("storage.list", || self.0.list())
+    }
+
+    fn search(&self, query: &UserQuery) -> Result<UserPage, StorageError> {
+        try_in_span("storage.search", || self.0.search(query))
+    }
+
+    fn get(&self, id: u32) -> Result<Option<User>, StorageError> {
+        try_in_span("storage.get", || self.0.get(id))
+    }
+
+    fn replace(&self, id: u32, update: UserUpdate) -> Result<Option<User>, StorageError> {
+        try_in_span("storage.replace", || self.0.replace(id, update))
+    }
+
+    fn delete(&self, id: u32) -> Result<bool, StorageError> {
+        try_in_span("storage.delete", || self.0.delete(id))
+    }
+
+    fn import(&self, user: User) -> Result<(), StorageError> {
+        try_in_span("storage.import", || self.0.import(user))
+    }
+
+    fn create_account(&self, info: Info, hash: String) -> Result<User, StorageError> {
+        try_in_span("storage.create_account", || {
+            self.0.create_account(info, hash)
+        })
+    }
+
+    fn set_password_hash(&self, id: u32, hash: String) -> Result<bool, StorageError> {
+        try_in_span("storage.set_password_hash", || {
+            self.0.set_password_hash(id, hash)
+        })
+    }
+
+    fn find_account(&self, email: &str) -> Result<Option<(User, String)>, StorageError> {
+        try_in_span("storage.find_account", || self.0.find_account(email))
+    }
+
+    fn create_api_key(&self, key: ApiKey, hash: String) -> Result<(), StorageError> {
+        try_in_span("storage.create_api_key", || {
+            self.0.create_api_key(key, hash)
+        })
+    }
+
+    fn list_api_keys(&self) -> Result<Vec<ApiKey>, StorageError> {
+        try_in_span("storage.list_api_keys", || self.0.list_api_keys())
+    }
+
+    fn get_api_key(&self, id: &str) -> Result<Option<(ApiKey, String)>, StorageError> {
+        try_in_span("storage.get_api_key", || self.0.get_api_key(id))
+    }
+
+    fn delete_api_key(&self, id: &str) -> Result<bool, StorageError> {
+        try_in_span("storage.delete_api_key", || self.0.delete_api_key(id))
+    }
+
+    fn touch_api_key(&self, id: &str, at: DateTime<Utc>) -> Result<(), StorageError> {
+        try_in_span("storage.touch_api_key", || self.0.touch_api_key(id, at))
+    }
+
+    fn ping(&self) -> Result<(), StorageError> {
+        try_in_span("storage.ping", || self.0.ping())
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        try_in_span("storage.flush", || self.0.flush())
+    }
+
+    fn migrate(&self) -> Result<usize, StorageError> {
+        self.0.migrate()
+    }
+
+    fn pending_migrations(&self) -> Result<usize, StorageError> {
+        self.0.pending_migrations()
+    }
+}
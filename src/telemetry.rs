@@ -0,0 +1,259 @@
+//! Distributed tracing with OpenTelemetry.
+//!
+//! [`trace`] starts a server span for every request, continuing the trace
+//! named by an incoming W3C `traceparent`/`tracestate` pair or starting a new
+//! one, and returns its trace id in the `X-Trace-Id` response header.
+//! Handlers add a child span with [`in_span`] and storage calls get one from
+//! [`TracedRepository`](crate::storage::TracedRepository). Finished spans go
+//! to the exporter chosen by the `tracing` settings: an OTLP/HTTP collector,
+//! a JSON-lines file, or nowhere.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::Error;
+use chrono::{DateTime, SecondsFormat, Utc};
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::{FutureExt, SpanId, SpanKind, Status, TraceContextExt, TraceId, Tracer};
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry_otlp::{Protocol, WithExportConfig};
+use opentelemetry_sdk::error::{OTelSdkError, OTelSdkResult};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{SdkTracerProvider, SpanData, SpanExporter};
+use opentelemetry_sdk::Resource;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::config::TracingConfig;
+
+pub const TRACE_ID_HEADER: HeaderName = HeaderName::from_static("x-trace-id");
+
+const SCOPE: &str = env!("CARGO_PKG_NAME");
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TraceExporter {
+    /// Spans are created and propagated but not exported.
+    #[default]
+    None,
+    /// OTLP over HTTP (JSON encoding) to `tracing.endpoint`.
+    Otlp,
+    /// One JSON object per span, appended to `tracing.file`.
+    File,
+}
+
+/// Installs the global tracer provider and W3C propagator. The returned
+/// provider must be shut down on exit so buffered spans are exported.
+pub fn init(config: &TracingConfig) -> Result<SdkTracerProvider, String> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let resource = Resource::builder()
+        .with_service_name(config.service_name.clone())
+        .build();
+    let builder = SdkTracerProvider::builder().with_resource(resource);
+    let builder = match config.exporter {
+        TraceExporter::None => builder,
+        TraceExporter::Otlp => {
+            let exporter = opentelemetry_otlp::SpanExporter::builder()
+                .with_http()
+                .with_protocol(Protocol::HttpJson)
+                .with_endpoint(config.endpoint.clone())
+                .build()
+                .map_err(|err| format!("cannot create OTLP exporter: {}", err))?;
+            builder.with_batch_exporter(exporter)
+        }
+        TraceExporter::File => {
+            let exporter = FileExporter::open(&config.file)
+                .map_err(|err| format!("cannot open trace file {}: {}", config.file, err))?;
+            builder.with_batch_exporter(exporter)
+        }
+    };
+    let provider = builder.build();
+    global::set_tracer_provider(provider.clone());
+    Ok(provider)
+}
+
+/// The trace id of the span active on this task, if it is a real one.
+pub fn current_trace_id() -> Option<TraceId> {
+    let context = Context::current();
+    let span_context = context.span().span_context().clone();
+    span_context.is_valid().then(|| span_context.trace_id())
+}
+
+/// Runs `future` inside a child span of the current one.
+pub async fn in_span<F: Future>(name: &'static str, future: F) -> F::Output {
+    let tracer = global::tracer(SCOPE);
+    let span = tracer
+        .span_builder(name)
+        .with_kind(SpanKind::Internal)
+        .start(&tracer);
+    let context = Context::current_with_span(span);
+    future.with_context(context).await
+}
+
+/// Runs `f` inside a child span of the current one, marking the span as
+/// failed if `f` returns an error.
+pub fn try_in_span<T, E: fmt::Display>(
+    name: &'static str,
+    f: impl FnOnce() -> Result<T, E>,
+) -> Result<T, E> {
+    global::tracer(SCOPE).in_span(name, |context| {
+        let result = f();
+        if let Err(err) = &result {
+            context.span().set_status(Status::error(err.to_string()));
+        }
+        result
+    })
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+/// Middleware wrapping each request in a server span.
+pub async fn trace(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(req.headers()))
+    });
+    let route = req.match_pattern();
+    let name = match &route {
+        Some(route) => format!("{} {}", req.method(), route),
+        None => req.method().to_string(),
+    };
+    let mut attributes = vec![
+        KeyValue::new("http.request.method", req.method().to_string()),
+        KeyValue::new("url.path", req.path().to_string()),
+    ];
+    if let Some(route) = route {
+        attributes.push(KeyValue::new("http.route", route));
+    }
+
+    let tracer = global::tracer(SCOPE);
+    let span = tracer
+        .span_builder(name)
+        .with_kind(SpanKind::Server)
+        .with_attributes(attributes)
+        .start_with_context(&tracer, &parent);
+    let context = parent.with_span(span);
+    let trace_id = context.span().span_context().trace_id();
+
+    let res = next.call(req).with_context(context.clone()).await;
+
+    let span = context.span();
+    let status = match &res {
+        Ok(res) => res.status(),
+        Err(err) => err.as_response_error().status_code(),
+    };
+    span.set_attribute(KeyValue::new(
+        "http.response.status_code",
+        i64::from(status.as_u16()),
+    ));
+    if status.is_server_error() {
+        span.set_status(Status::error(status.to_string()));
+    }
+    span.end();
+
+    let mut res = res?;
+    if trace_id != TraceId::INVALID {
+        res.headers_mut().insert(
+            TRACE_ID_HEADER,
+            HeaderValue::from_str(&trace_id.to_string()).unwrap(),
+        );
+    }
+    Ok(res)
+}
+
+/// Appends finished spans to a file as JSON lines, for offline use.
+#[derive(Debug)]
+struct FileExporter {
+    file: Mutex<File>,
+}
+
+impl FileExporter {
+    fn open(path: &str) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(FileExporter {
+            file: Mutex::new(file),
+        })
+    }
+
+    fn write(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        let mut out = Vec::new();
+        for span in batch {
+            serde_json::to_writer(&mut out, &span_json(&span)).unwrap();
+            out.push(b'\n');
+        }
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&out)
+            .and_then(|()| file.flush())
+            .map_err(|err| OTelSdkError::InternalFailure(err.to_string()))
+    }
+}
+
+impl SpanExporter for FileExporter {
+    fn export(&self, batch: Vec<SpanData>) -> impl Future<Output = OTelSdkResult> + Send {
+        std::future::ready(self.write(batch))
+    }
+}
+
+fn span_json(span: &SpanData) -> Value {
+    fn timestamp(time: SystemTime) -> String {
+        DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Micros, true)
+    }
+
+    let attributes: Map<String, Value> = span
+        .attributes
+        .iter()
+        .map(|kv| {
+            let value = match &kv.value {
+                opentelemetry::Value::Bool(b) => Value::from(*b),
+                opentelemetry::Value::I64(n) => Value::from(*n),
+                opentelemetry::Value::F64(n) => Value::from(*n),
+                other => Value::from(other.to_string()),
+            };
+            (kv.key.to_string(), value)
+        })
+        .collect();
+    let status: Cow<str> = match &span.status {
+        Status::Unset => "unset".into(),
+        Status::Ok => "ok".into(),
+        Status::Error { description } => format!("error: {}", description).into(),
+    };
+    let duration = span
+        .end_time
+        .duration_since(span.start_time)
+        .unwrap_or_default();
+    json!({
+        "trace_id": span.span_context.trace_id().to_string(),
+        "span_id": span.span_context.span_id().to_string(),
+        "parent_span_id": (span.parent_span_id != SpanId::INVALID)
+            .then(|| span.parent_span_id.to_string()),
+        "name": span.name,
+        "kind": format!("{:?}", span.span_kind).to_lowercase(),
+        "start": timestamp(span.start_time),
+        "end": timestamp(span.end_time),
+        "duration_ms": duration.as_secs_f64() * 1000.0,
+        "status": status,
+        "attributes": attributes,
+    })
+}
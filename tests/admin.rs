@@ -0,0 +1,127 @@
+//! Operator routes under `/admin`.
+
+mod common;
+
+use actix_web::http::{header, StatusCode};
+use serde_json::json;
+
+use common::{test_config, TestApp};
+
+#[actix_web::test]
+async fn api_keys_can_be_issued_listed_and_revoked() {
+    let app = TestApp::spawn().await;
+
+    let res = app
+        .call(app.post("/admin/api-keys").operator().json(json!({
+            "name": "reporting",
+            "scopes": ["users:read"],
+            "role": "editor",
+        })))
+        .await;
+    res.assert_status(StatusCode::CREATED);
+    let issued = res.assert_json_includes(json!({
+        "name": "reporting",
+        "scopes": ["users:read"],
+        "role": "editor",
+        "expires_at": null,
+        "last_used_at": null,
+    }));
+    let id = issued["id"].as_str().unwrap().to_string();
+    let key = issued["key"].as_str().unwrap().to_string();
+    assert!(key.starts_with(&format!("{}.", id)), "key: {}", key);
+    assert_eq!(
+        res.header(header::LOCATION),
+        Some(format!("/admin/api-keys/{}", id).as_str())
+    );
+
+    let res = app.call(app.get("/users").header("X-Api-Key", &key)).await;
+    res.assert_status(StatusCode::OK);
+
+    let res = app.call(app.get("/admin/api-keys").operator()).await;
+    res.assert_status(StatusCode::OK);
+    let list = res.assert_json_includes(json!({ "items": [{ "id": id }] }));
+    assert!(list["items"][0].get("key").is_none());
+
+    let uri = format!("/admin/api-keys/{}", id);
+    let res = app.call(app.delete(&uri).operator()).await;
+    res.assert_status(StatusCode::NO_CONTENT);
+    let res = app.call(app.delete(&uri).operator()).await;
+    res.assert_problem(StatusCode::NOT_FOUND, "/problems/not-found");
+
+    let res = app.call(app.get("/users").header("X-Api-Key", &key)).await;
+    res.assert_problem(StatusCode::UNAUTHORIZED, "/problems/unauthorized");
+}
+
+#[actix_web::test]
+async fn api_key_requests_are_validated() {
+    let app = TestApp::spawn().await;
+
+    let res = app
+        .call(
+            app.post("/admin/api-keys")
+                .operator()
+                .json(json!({ "name": "" })),
+        )
+        .await;
+    res.assert_problem(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "/problems/validation-error",
+    );
+
+    for body in [
+        json!({ "name": "old", "expires_at": "2000-01-01T00:00:00Z" }),
+        json!({ "name": "spaced", "scopes": ["users read"] }),
+        json!({ "name": "typo", "role": "owner" }),
+    ] {
+        let res = app
+            .call(app.post("/admin/api-keys").operator().json(body))
+            .await;
+        res.assert_problem(StatusCode::BAD_REQUEST, "/problems/invalid-body");
+    }
+}
+
+#[actix_web::test]
+async fn admin_routes_need_the_admin_token() {
+    let app = TestApp::spawn().await;
+
+    for req in [
+        app.get("/admin/api-keys"),
+        app.post("/admin/api-keys").json(json!({ "name": "x" })),
+        app.delete("/admin/api-keys/ak_000000000000"),
+        app.post("/admin/shutdown"),
+    ] {
+        let res = app.call(req).await;
+        let problem = res.assert_problem(StatusCode::UNAUTHORIZED, "/problems/unauthorized");
+        assert_eq!(problem["detail"], "Admin bearer token required");
+        assert_eq!(res.header(header::WWW_AUTHENTICATE), Some("Bearer"));
+    }
+
+    let res = app.call(app.get("/admin/api-keys").admin()).await;
+    let problem = res.assert_problem(StatusCode::UNAUTHORIZED, "/problems/unauthorized");
+    assert_eq!(problem["detail"], "Invalid admin token");
+    assert_eq!(
+        res.header(header::WWW_AUTHENTICATE),
+        Some(r#"Bearer error="invalid_token""#)
+    );
+}
+
+#[actix_web::test]
+async fn admin_routes_are_hidden_without_an_admin_token() {
+    let mut config = test_config();
+    config.admin.token.clear();
+    let app = TestApp::with_config(config).await;
+
+    let res = app.call(app.get("/admin/api-keys").operator()).await;
+    res.assert_problem(StatusCode::NOT_FOUND, "/problems/not-found");
+    let res = app.call(app.post("/admin/shutdown").operator()).await;
+    res.assert_problem(StatusCode::NOT_FOUND, "/problems/not-found");
+}
+
+#[actix_web::test]
+async fn shutdown_is_accepted() {
+    let app = TestApp::spawn().await;
+
+    let res = app.call(app.post("/admin/shutdown").operator()).await;
+    res.assert_status(StatusCode::ACCEPTED);
+    res.assert_json_includes(json!({ "status": "shutting down" }));
+}
@@ -0,0 +1,375 @@
+//! Accounts, sessions and API keys: `/auth/*` and the credentials the
+//! `/users` routes accept.
+
+mod common;
+
+use actix_web::http::{header, StatusCode};
+use futures_util::future::join_all;
+use serde_json::json;
+
+use actix_web_app::auth;
+use actix_web_app::auth::api_key::{self, NewApiKey};
+use actix_web_app::models::{Role, UserUpdate};
+use actix_web_app::AppState;
+use common::{test_config, token, TestApp};
+
+#[actix_web::test]
+async fn signup_creates_an_account_and_logs_in() {
+    let app = TestApp::spawn().await;
+
+    let res = app
+        .call(app.post("/auth/signup").json(json!({
+            "name": "Ada",
+            "email": "ada@example.com",
+            "password": "correct horse",
+        })))
+        .await;
+    res.assert_status(StatusCode::CREATED);
+    let body = res.assert_json_includes(json!({
+        "user": { "id": 1, "name": "Ada", "email": "ada@example.com", "role": "viewer" },
+    }));
+    assert!(body["csrf_token"].is_string());
+    assert!(body["user"].get("password").is_none());
+    assert_eq!(res.header(header::LOCATION), Some("/users/1"));
+    assert!(res.header(header::SET_COOKIE).is_some());
+}
+
+#[actix_web::test]
+async fn signup_reports_errors() {
+    let app = TestApp::spawn().await;
+    app.sign_up("ada@example.com", "correct horse").await;
+
+    let res = app
+        .call(app.post("/auth/signup").json(json!({
+            "name": "Ada again",
+            "email": "ada@example.com",
+            "password": "correct horse",
+        })))
+        .await;
+    res.assert_problem(StatusCode::CONFLICT, "/problems/conflict");
+
+    let res = app
+        .call(app.post("/auth/signup").json(json!({
+            "name": "Short",
+            "email": "short@example.com",
+            "password": "short",
+        })))
+        .await;
+    let problem = res.assert_problem(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "/problems/validation-error",
+    );
+    assert!(problem["errors"]["password"].is_array());
+
+    let res = app
+        .call(app.post("/auth/signup").raw("application/json", "{"))
+        .await;
+    res.assert_problem(StatusCode::BAD_REQUEST, "/problems/invalid-body");
+}
+
+#[actix_web::test]
+async fn concurrent_signups_for_one_email_make_one_account() {
+    let app = TestApp::spawn().await;
+
+    let signups = ["ada@example.com", "ADA@example.com", "ada@example.com"].map(|email| {
+        app.call(app.post("/auth/signup").json(json!({
+            "name": "Ada",
+            "email": email,
+            "password": "correct horse",
+        })))
+    });
+    let statuses: Vec<_> = join_all(signups)
+        .await
+        .iter()
+        .map(|res| res.status)
+        .collect();
+    assert_eq!(
+        statuses
+            .iter()
+            .filter(|&&s| s == StatusCode::CREATED)
+            .count(),
+        1,
+        "{:?}",
+        statuses
+    );
+    assert_eq!(
+        statuses
+            .iter()
+            .filter(|&&s| s == StatusCode::CONFLICT)
+            .count(),
+        2,
+        "{:?}",
+        statuses
+    );
+}
+
+#[actix_web::test]
+async fn accounts_cannot_take_each_others_email() {
+    let app = TestApp::spawn().await;
+    app.sign_up("ada@example.com", "correct horse").await;
+    app.sign_up("grace@example.com", "correct horse").await;
+
+    let res = app
+        .call(app.put("/users/2").admin().json(json!({
+            "name": "Grace",
+            "email": "Ada@example.com",
+        })))
+        .await;
+    let problem = res.assert_problem(StatusCode::CONFLICT, "/problems/conflict");
+    assert_eq!(
+        problem["detail"],
+        "An account for Ada@example.com already exists"
+    );
+
+    // Users that aren't accounts may share an address.
+    app.call(app.post("/users").admin().json(json!({
+        "name": "Ada's twin",
+        "email": "ada@example.com",
+    })))
+    .await
+    .assert_status(StatusCode::CREATED);
+}
+
+#[test]
+fn a_malformed_session_key_is_an_error_not_a_random_key() {
+    let mut config = test_config();
+    config.auth.session.key = "not base64!".to_string();
+    let repository = actix_web_app::storage::open(&config.storage).unwrap();
+    match AppState::new(&config, repository) {
+        Err(err) => assert!(
+            err.starts_with("auth.session.key: not valid base64"),
+            "{}",
+            err
+        ),
+        Ok(_) => panic!("a malformed auth.session.key was accepted"),
+    }
+}
+
+#[actix_web::test]
+async fn login_checks_the_password() {
+    let app = TestApp::spawn().await;
+    app.sign_up("ada@example.com", "correct horse").await;
+
+    let res = app
+        .call(app.post("/auth/login").json(json!({
+            "email": "ada@example.com",
+            "password": "correct horse",
+        })))
+        .await;
+    res.assert_status(StatusCode::OK);
+    res.assert_json_includes(json!({ "user": { "email": "ada@example.com" } }));
+
+    for (email, password) in [
+        ("ada@example.com", "battery staple"),
+        ("nobody@example.com", "correct horse"),
+    ] {
+        let res = app
+            .call(
+                app.post("/auth/login")
+                    .json(json!({ "email": email, "password": password })),
+            )
+            .await;
+        let problem = res.assert_problem(StatusCode::UNAUTHORIZED, "/problems/unauthorized");
+        assert_eq!(problem["detail"], "Invalid email or password");
+        // A login form is not HTTP authentication; there is no challenge.
+        assert_eq!(res.header(header::WWW_AUTHENTICATE), None);
+    }
+}
+
+#[actix_web::test]
+async fn session_reaches_me_and_the_users_routes() {
+    let app = TestApp::spawn().await;
+    let session = app.sign_up("ada@example.com", "correct horse").await;
+
+    let res = app.call(app.get("/auth/me").session(&session)).await;
+    res.assert_status(StatusCode::OK);
+    res.assert_json_includes(json!({
+        "user": { "id": 1 },
+        "csrf_token": session.csrf_token,
+    }));
+
+    let res = app.call(app.get("/users/1").session(&session)).await;
+    res.assert_status(StatusCode::OK);
+
+    let res = app
+        .call(
+            app.patch("/users/1")
+                .session(&session)
+                .json(json!({ "age": 36 })),
+        )
+        .await;
+    // Viewers may read but not update themselves.
+    res.assert_problem(StatusCode::FORBIDDEN, "/problems/forbidden");
+}
+
+#[actix_web::test]
+async fn session_writes_need_the_csrf_token() {
+    let app = TestApp::spawn().await;
+    let session = app.sign_up("ada@example.com", "correct horse").await;
+    app.state
+        .store
+        .replace(
+            1,
+            UserUpdate {
+                id: None,
+                name: "Ada".to_string(),
+                age: None,
+                email: Some("ada@example.com".to_string()),
+                role: Some(Role::Admin),
+            },
+        )
+        .unwrap();
+
+    let res = app
+        .call(
+            app.post("/users")
+                .header(header::COOKIE, session.cookie.to_string())
+                .json(json!({ "name": "Grace" })),
+        )
+        .await;
+    let problem = res.assert_problem(StatusCode::FORBIDDEN, "/problems/forbidden");
+    assert_eq!(problem["detail"], "Missing or invalid X-CSRF-Token header");
+
+    let res = app
+        .call(
+            app.post("/users")
+                .session(&session)
+                .json(json!({ "name": "Grace" })),
+        )
+        .await;
+    res.assert_status(StatusCode::CREATED);
+}
+
+#[actix_web::test]
+async fn me_needs_a_session() {
+    let app = TestApp::spawn().await;
+
+    let res = app.call(app.get("/auth/me")).await;
+    res.assert_problem(StatusCode::UNAUTHORIZED, "/problems/unauthorized");
+
+    let res = app.call(app.get("/auth/me").admin()).await;
+    let problem = res.assert_problem(StatusCode::UNAUTHORIZED, "/problems/unauthorized");
+    assert_eq!(problem["detail"], "This route requires a login session");
+}
+
+#[actix_web::test]
+async fn logout_ends_the_session() {
+    let app = TestApp::spawn().await;
+    let session = app.sign_up("ada@example.com", "correct horse").await;
+
+    let res = app.call(app.post("/auth/logout").session(&session)).await;
+    res.assert_status(StatusCode::NO_CONTENT);
+
+    let res = app.call(app.get("/auth/me").session(&session)).await;
+    res.assert_problem(StatusCode::UNAUTHORIZED, "/problems/unauthorized");
+
+    let res = app.call(app.post("/auth/logout")).await;
+    res.assert_problem(StatusCode::UNAUTHORIZED, "/problems/unauthorized");
+}
+
+#[actix_web::test]
+async fn deleting_the_account_ends_its_session() {
+    let app = TestApp::spawn().await;
+    let session = app.sign_up("ada@example.com", "correct horse").await;
+
+    let res = app.call(app.delete("/users/1").admin()).await;
+    res.assert_status(StatusCode::NO_CONTENT);
+
+    let res = app.call(app.get("/users/1").session(&session)).await;
+    res.assert_problem(StatusCode::UNAUTHORIZED, "/problems/unauthorized");
+}
+
+#[actix_web::test]
+async fn api_keys_authenticate_with_their_scopes_and_role() {
+    let app = TestApp::spawn().await;
+    app.create_user("Ada", None).await;
+    let issued = api_key::issue(
+        &**app.state.store,
+        NewApiKey {
+            name: "reporting".to_string(),
+            scopes: vec!["users:read".to_string()],
+            role: Some(Role::Editor),
+            expires_at: None,
+        },
+    )
+    .unwrap();
+
+    let res = app
+        .call(app.get("/users").header("X-Api-Key", &issued.key))
+        .await;
+    res.assert_status(StatusCode::OK);
+
+    let res = app
+        .call(
+            app.get("/users/1")
+                .header(header::AUTHORIZATION, format!("ApiKey {}", issued.key)),
+        )
+        .await;
+    res.assert_status(StatusCode::OK);
+
+    let res = app
+        .call(
+            app.post("/users")
+                .header("X-Api-Key", &issued.key)
+                .json(json!({ "name": "Grace" })),
+        )
+        .await;
+    res.assert_problem(StatusCode::FORBIDDEN, "/problems/forbidden");
+
+    let (stored, _) = app
+        .state
+        .store
+        .get_api_key(&issued.api_key.id)
+        .unwrap()
+        .unwrap();
+    assert!(stored.last_used_at.is_some());
+}
+
+#[actix_web::test]
+async fn invalid_api_keys_are_rejected() {
+    let app = TestApp::spawn().await;
+
+    for key in ["nonsense", "ak_000000000000.secret"] {
+        let res = app.call(app.get("/users").header("X-Api-Key", key)).await;
+        let problem = res.assert_problem(StatusCode::UNAUTHORIZED, "/problems/unauthorized");
+        assert_eq!(problem["detail"], "Invalid API key");
+        assert_eq!(res.header(header::WWW_AUTHENTICATE), Some("ApiKey"));
+    }
+}
+
+#[actix_web::test]
+async fn bearer_tokens_must_be_valid() {
+    let app = TestApp::spawn().await;
+
+    let mut other_key = common::test_config().auth.jwt;
+    other_key.secret = "another-secret-that-is-long-enough-too".to_string();
+    let forged = auth::issue(
+        &other_key,
+        "admin",
+        &["users:read".to_string()],
+        Role::Admin,
+        60,
+    )
+    .unwrap();
+    let res = app.call(app.get("/users").bearer(&forged)).await;
+    res.assert_problem(StatusCode::UNAUTHORIZED, "/problems/unauthorized");
+    assert_eq!(
+        res.header(header::WWW_AUTHENTICATE),
+        Some(r#"Bearer error="invalid_token""#)
+    );
+
+    let res = app
+        .call(
+            app.get("/users")
+                .header(header::AUTHORIZATION, "Basic YWRhOnB3"),
+        )
+        .await;
+    res.assert_problem(StatusCode::UNAUTHORIZED, "/problems/unauthorized");
+    // An unsupported scheme is answered with the ones that are.
+    assert_eq!(res.header(header::WWW_AUTHENTICATE), Some("Bearer, ApiKey"));
+
+    let res = app
+        .call(app.get("/users").bearer(&token("admin", Role::Admin, &[])))
+        .await;
+    res.assert_problem(StatusCode::FORBIDDEN, "/problems/forbidden");
+}
@@ -0,0 +1,452 @@
+//! Test harness: the app `main` serves, built against a fresh in-memory
+//! store for every test, plus helpers for credentials, fixtures and
+//! assertions on responses.
+//!
+//! ```ignore
+//! let app = TestApp::spawn().await;
+//! let user = app.create_user("Ada", Some(36)).await;
+//! let res = app.call(app.get(&format!("/users/{}", user.id)).admin()).await;
+//! res.assert_status(StatusCode::OK);
+//! ```
+
+// Each test binary uses a different subset of the helpers.
+#![allow(dead_code)]
+
+use std::future::poll_fn;
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use std::{fs, process};
+
+use actix_http::Request;
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServerHandle, Service, ServiceResponse};
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::http::{Method, StatusCode};
+use actix_web::rt::time::timeout;
+use actix_web::test::{self, TestRequest};
+use actix_web::web::Bytes;
+use actix_web::{Error, HttpServer};
+use serde_json::Value;
+
+use actix_web_app::auth::{self, USERS_READ, USERS_WRITE};
+use actix_web_app::config::Config;
+use actix_web_app::models::{Info, Role, User};
+use actix_web_app::{build_app, configure, storage, AppState};
+
+pub const JWT_SECRET: &str = "test-secret-that-is-long-enough-for-hs256";
+pub const ADMIN_TOKEN: &str = "test-admin-token-0123456789";
+
+/// The configuration every test starts from: defaults, including the
+/// in-memory store, plus the secrets the authenticated routes need.
+pub fn test_config() -> Config {
+    let mut config = Config::default();
+    config.auth.jwt.secret = JWT_SECRET.to_string();
+    config.admin.token = ADMIN_TOKEN.to_string();
+    config
+}
+
+/// A directory for database files, removed with everything in it when
+/// dropped.
+pub struct Scratch {
+    pub dir: PathBuf,
+}
+
+impl Scratch {
+    pub fn new() -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "actix-web-app-test-{}-{}",
+            process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        Scratch { dir }
+    }
+
+    /// `storage` settings for every backend, with their files in this
+    /// directory.
+    pub fn backends(&self) -> [String; 3] {
+        [
+            "memory".to_string(),
+            format!("json:{}", self.dir.join("users.jsonl").display()),
+            format!("sqlite:{}", self.dir.join("users.db").display()),
+        ]
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// A bearer token for `sub` with `role` and `scopes`.
+pub fn token(sub: &str, role: Role, scopes: &[&str]) -> String {
+    let scopes: Vec<String> = scopes.iter().map(|scope| scope.to_string()).collect();
+    auth::issue(&test_config().auth.jwt, sub, &scopes, role, 300).unwrap()
+}
+
+pub struct TestApp<S> {
+    service: S,
+    pub state: AppState,
+}
+
+impl TestApp<()> {
+    pub async fn spawn(
+    ) -> TestApp<impl Service<Request, Response = ServiceResponse<impl MessageBody>, Error = Error>>
+    {
+        TestApp::with_config(test_config()).await
+    }
+
+    pub async fn with_config(
+        config: Config,
+    ) -> TestApp<impl Service<Request, Response = ServiceResponse<impl MessageBody>, Error = Error>>
+    {
+        let repository = storage::open(&config.storage).unwrap();
+        let state = AppState::new(&config, repository).unwrap();
+        let service = test::init_service(build_app(&state).configure(configure)).await;
+        TestApp { service, state }
+    }
+}
+
+impl<S, B> TestApp<S>
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody,
+{
+    pub fn request(&self, method: Method, uri: &str) -> Req {
+        Req(TestRequest::default().method(method).uri(uri))
+    }
+
+    pub fn get(&self, uri: &str) -> Req {
+        self.request(Method::GET, uri)
+    }
+
+    pub fn post(&self, uri: &str) -> Req {
+        self.request(Method::POST, uri)
+    }
+
+    pub fn put(&self, uri: &str) -> Req {
+        self.request(Method::PUT, uri)
+    }
+
+    pub fn patch(&self, uri: &str) -> Req {
+        self.request(Method::PATCH, uri)
+    }
+
+    pub fn delete(&self, uri: &str) -> Req {
+        self.request(Method::DELETE, uri)
+    }
+
+    pub async fn call(&self, req: Req) -> Res {
+        let res = test::call_service(&self.service, req.0.to_request()).await;
+        let status = res.status();
+        let headers = res.headers().clone();
+        let body = test::read_body(res).await;
+        Res {
+            status,
+            headers,
+            body,
+        }
+    }
+
+    /// Sends `req` and returns the response without reading its body, for
+    /// streams that don't end by themselves.
+    pub async fn open(&self, req: Req) -> EventStream
+    where
+        B: 'static,
+    {
+        let res = test::call_service(&self.service, req.0.to_request()).await;
+        EventStream {
+            status: res.status(),
+            headers: res.headers().clone(),
+            body: res.into_body().boxed(),
+            buffer: String::new(),
+        }
+    }
+
+    /// Serves the app on a free local port and shares its state, for clients
+    /// that need a real connection, such as WebSockets.
+    pub fn serve(&self) -> Server {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = self.state.clone();
+        let server = HttpServer::new(move || build_app(&state).configure(configure))
+            .workers(1)
+            .disable_signals()
+            .listen(listener)
+            .unwrap()
+            .run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+        Server { addr, handle }
+    }
+
+    /// Stores a user directly, bypassing the routes.
+    pub async fn create_user(&self, name: &str, age: Option<u8>) -> User {
+        self.create_user_with(Info {
+            name: name.to_string(),
+            age,
+            email: None,
+            role: None,
+        })
+        .await
+    }
+
+    pub async fn create_user_with(&self, info: Info) -> User {
+        self.state.store.create(info).unwrap()
+    }
+
+    /// Signs up through `/auth/signup` and returns the session cookie and
+    /// CSRF token.
+    pub async fn sign_up(&self, email: &str, password: &str) -> Session {
+        let res = self
+            .call(self.post("/auth/signup").json(serde_json::json!({
+                "name": "Session User",
+                "email": email,
+                "password": password,
+            })))
+            .await;
+        res.assert_status(StatusCode::CREATED);
+        res.session(&res.json()["csrf_token"])
+    }
+}
+
+/// A running server from [`TestApp::serve`].
+pub struct Server {
+    pub addr: SocketAddr,
+    pub handle: ServerHandle,
+}
+
+impl Server {
+    pub fn ws_url(&self, path: &str) -> String {
+        format!("ws://{}{}", self.addr, path)
+    }
+}
+
+/// A Server-Sent Events response from [`TestApp::open`], read a frame at a
+/// time.
+pub struct EventStream {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    body: BoxBody,
+    buffer: String,
+}
+
+/// One event: its `id` and `event` fields, and `data` parsed as JSON.
+#[derive(Debug)]
+pub struct Event {
+    pub id: Option<u64>,
+    pub event: Option<String>,
+    pub data: Value,
+}
+
+impl EventStream {
+    /// The next frame, without its blank line; `None` once the stream ends.
+    pub async fn next_frame(&mut self) -> Option<String> {
+        loop {
+            if let Some(end) = self.buffer.find("\n\n") {
+                let frame = self.buffer[..end].to_string();
+                self.buffer.drain(..end + 2);
+                return Some(frame);
+            }
+            let chunk = timeout(
+                Duration::from_secs(5),
+                poll_fn(|cx| Pin::new(&mut self.body).poll_next(cx)),
+            )
+            .await
+            .expect("a frame within 5 seconds")?
+            .unwrap();
+            self.buffer.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+    }
+
+    /// The next frame with data, skipping comments and `retry` frames.
+    pub async fn next_event(&mut self) -> Event {
+        loop {
+            let frame = self.next_frame().await.expect("the stream is open");
+            let mut event = Event {
+                id: None,
+                event: None,
+                data: Value::Null,
+            };
+            for line in frame.lines() {
+                match line.split_once(": ") {
+                    Some(("id", id)) => event.id = Some(id.parse().unwrap()),
+                    Some(("event", name)) => event.event = Some(name.to_string()),
+                    Some(("data", data)) => event.data = serde_json::from_str(data).unwrap(),
+                    _ => {}
+                }
+            }
+            if !event.data.is_null() {
+                return event;
+            }
+        }
+    }
+
+    /// Asserts that the stream ends, skipping whatever comes before.
+    pub async fn assert_ends(&mut self) {
+        while self.next_frame().await.is_some() {}
+    }
+}
+
+/// A request being built; wraps `TestRequest` with credential helpers.
+pub struct Req(pub TestRequest);
+
+impl Req {
+    pub fn header(self, name: impl AsRef<str>, value: impl AsRef<str>) -> Self {
+        Req(self
+            .0
+            .insert_header((name.as_ref().to_string(), value.as_ref().to_string())))
+    }
+
+    pub fn bearer(self, token: &str) -> Self {
+        self.header(header::AUTHORIZATION, format!("Bearer {}", token))
+    }
+
+    /// A bearer token for an admin with both user scopes.
+    pub fn admin(self) -> Self {
+        self.bearer(&token("admin", Role::Admin, &[USERS_READ, USERS_WRITE]))
+    }
+
+    /// A bearer token for user `id` with `role` and both user scopes.
+    pub fn user(self, id: u32, role: Role) -> Self {
+        self.bearer(&token(&id.to_string(), role, &[USERS_READ, USERS_WRITE]))
+    }
+
+    /// The `admin.token` of [`test_config`].
+    pub fn operator(self) -> Self {
+        self.bearer(ADMIN_TOKEN)
+    }
+
+    pub fn session(self, session: &Session) -> Self {
+        Req(self.0.cookie(session.cookie.clone())).header(auth::CSRF_HEADER, &session.csrf_token)
+    }
+
+    pub fn json(self, body: Value) -> Self {
+        Req(self.0.set_json(body))
+    }
+
+    /// A body sent as-is, e.g. malformed JSON.
+    pub fn raw(self, content_type: &str, body: &'static str) -> Self {
+        self.bytes(content_type, body.as_bytes().to_vec())
+    }
+
+    /// A body in any format, e.g. one from `Format::encode`.
+    pub fn bytes(self, content_type: &str, body: Vec<u8>) -> Self {
+        Req(self
+            .0
+            .insert_header((header::CONTENT_TYPE, content_type))
+            .set_payload(body))
+    }
+}
+
+/// A logged-in session.
+pub struct Session {
+    pub cookie: actix_web::cookie::Cookie<'static>,
+    pub csrf_token: String,
+}
+
+/// A response with its body read.
+pub struct Res {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl Res {
+    #[track_caller]
+    pub fn assert_status(&self, status: StatusCode) -> &Self {
+        assert_eq!(
+            self.status,
+            status,
+            "unexpected status; body: {}",
+            String::from_utf8_lossy(&self.body)
+        );
+        self
+    }
+
+    #[track_caller]
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap_or_else(|err| {
+            panic!(
+                "body is not JSON ({}): {}",
+                err,
+                String::from_utf8_lossy(&self.body)
+            )
+        })
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8(self.body.to_vec()).unwrap()
+    }
+
+    pub fn header(&self, name: impl header::AsHeaderName) -> Option<&str> {
+        self.headers.get(name).map(|value| value.to_str().unwrap())
+    }
+
+    /// Asserts that the response is a problem document with this status
+    /// and `type`, and returns the document.
+    #[track_caller]
+    pub fn assert_problem(&self, status: StatusCode, kind: &str) -> Value {
+        self.assert_status(status);
+        assert_eq!(
+            self.header(header::CONTENT_TYPE),
+            Some("application/problem+json")
+        );
+        let problem = self.json();
+        assert_eq!(problem["type"], kind, "problem: {}", problem);
+        assert_eq!(problem["status"], status.as_u16());
+        assert!(problem["request_id"].is_string(), "problem: {}", problem);
+        problem
+    }
+
+    /// Asserts that `expected` is a subset of the JSON body: every member
+    /// it names has the same value, and other members are ignored. Arrays
+    /// must have the same length and are compared element by element.
+    #[track_caller]
+    pub fn assert_json_includes(&self, expected: Value) -> Value {
+        let actual = self.json();
+        assert_includes(&actual, &expected, "$");
+        actual
+    }
+
+    fn session(&self, csrf_token: &Value) -> Session {
+        let cookie = self
+            .headers
+            .get_all(header::SET_COOKIE)
+            .filter_map(|value| value.to_str().ok())
+            .filter_map(|value| actix_web::cookie::Cookie::parse_encoded(value.to_string()).ok())
+            .find(|cookie| cookie.name() == "session")
+            .expect("response sets the session cookie");
+        Session {
+            cookie,
+            csrf_token: csrf_token.as_str().unwrap().to_string(),
+        }
+    }
+}
+
+#[track_caller]
+fn assert_includes(actual: &Value, expected: &Value, path: &str) {
+    match (actual, expected) {
+        (Value::Object(actual), Value::Object(expected)) => {
+            for (key, value) in expected {
+                let path = format!("{}.{}", path, key);
+                match actual.get(key) {
+                    Some(actual) => assert_includes(actual, value, &path),
+                    None => panic!("{} is missing", path),
+                }
+            }
+        }
+        (Value::Array(actual), Value::Array(expected)) => {
+            assert_eq!(actual.len(), expected.len(), "length of {}", path);
+            for (index, (actual, expected)) in actual.iter().zip(expected).enumerate() {
+                assert_includes(actual, expected, &format!("{}[{}]", path, index));
+            }
+        }
+        _ => assert_eq!(actual, expected, "at {}", path),
+    }
+}
@@ -0,0 +1,238 @@
+//! Configuration layering: defaults, then the TOML file, then `APP_*`
+//! variables, then flags.
+
+mod common;
+
+use std::fs;
+
+use actix_web_app::config::{Config, ConfigArgs, ConfigError, MAX_EVENTS, MAX_SESSION_TTL};
+use actix_web_app::logging::LogFormat;
+use common::Scratch;
+
+fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+    vars.iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+#[track_caller]
+fn invalid(result: Result<Config, ConfigError>) -> String {
+    match result {
+        Err(err) => err.to_string(),
+        Ok(config) => panic!("expected an error, got {:?}", config),
+    }
+}
+
+#[test]
+fn defaults_apply_without_any_layer() {
+    let config = Config::load_from(&ConfigArgs::default(), env(&[])).unwrap();
+    assert_eq!(config.host, "127.0.0.1");
+    assert_eq!(config.port, 3000);
+    assert_eq!(config.log_level, "info");
+    assert_eq!(config.storage, "memory");
+    assert_eq!(config.limits.json, 2 * 1024 * 1024);
+}
+
+#[test]
+fn each_layer_overrides_the_one_before() {
+    let scratch = Scratch::new();
+    let path = scratch.dir.join("app.toml");
+    fs::write(
+        &path,
+        r#"
+        host = "0.0.0.0"
+        port = 4000
+        log_level = "warn"
+        workers = 3
+
+        [limits]
+        json = 1000
+        payload = 2000
+        "#,
+    )
+    .unwrap();
+
+    let file_only = ConfigArgs {
+        config: Some(path.clone()),
+        ..ConfigArgs::default()
+    };
+    let config = Config::load_from(&file_only, env(&[])).unwrap();
+    assert_eq!(config.host, "0.0.0.0");
+    assert_eq!(config.port, 4000);
+    assert_eq!(config.limits.json, 1000);
+    assert_eq!(config.log_format, LogFormat::Json, "default kept");
+
+    let vars = env(&[
+        ("APP_PORT", "5000"),
+        ("APP_LOG_LEVEL", "debug"),
+        ("APP_LIMITS__PAYLOAD", "3000"),
+        ("APP_LOG_FORMAT", "text"),
+    ]);
+    let config = Config::load_from(&file_only, vars.clone()).unwrap();
+    assert_eq!(config.host, "0.0.0.0", "file kept");
+    assert_eq!(config.port, 5000);
+    assert_eq!(config.log_level, "debug");
+    assert_eq!(config.limits.json, 1000, "file kept");
+    assert_eq!(config.limits.payload, 3000);
+    assert_eq!(config.log_format, LogFormat::Text);
+
+    let flags = ConfigArgs {
+        config: Some(path),
+        port: Some(6000),
+        payload_limit: Some(4000),
+        ..ConfigArgs::default()
+    };
+    let config = Config::load_from(&flags, vars).unwrap();
+    assert_eq!(config.port, 6000);
+    assert_eq!(config.limits.payload, 4000);
+    assert_eq!(config.log_level, "debug", "environment kept");
+    assert_eq!(config.workers, 3, "file kept");
+}
+
+#[test]
+fn unrelated_environment_variables_are_ignored() {
+    let vars = env(&[
+        ("APP_ENV", "production"),
+        ("APP_FOO", "1"),
+        ("APP_FOO__BAR", "x"),
+        ("APP_", "x"),
+        ("APP_CONFIG", "/does/not/matter"),
+        ("PORT", "1"),
+        ("APP_PORT", "8080"),
+    ]);
+    let config = Config::load_from(&ConfigArgs::default(), vars).unwrap();
+    assert_eq!(config.port, 8080);
+}
+
+#[test]
+fn bad_environment_variables_for_known_settings_are_errors() {
+    let err = invalid(Config::load_from(
+        &ConfigArgs::default(),
+        env(&[("APP_PORT", "eighty")]),
+    ));
+    assert!(err.starts_with("APP_PORT: expected an integer"), "{}", err);
+
+    let err = invalid(Config::load_from(
+        &ConfigArgs::default(),
+        env(&[("APP_LIMITS__JSNO", "1")]),
+    ));
+    assert!(err.contains("jsno"), "{}", err);
+}
+
+#[test]
+fn log_filters_use_the_env_logger_syntax() {
+    for filter in [
+        "info",
+        "",
+        "actix_web",
+        "info,actix_web",
+        "warn,actix_web=debug",
+        "actix_web_app::storage=trace,off",
+        "actix_web=",
+        "info/starting",
+        "4",
+    ] {
+        let vars = env(&[("APP_LOG_LEVEL", filter)]);
+        let config = Config::load_from(&ConfigArgs::default(), vars);
+        assert!(config.is_ok(), "{:?}: {}", filter, invalid(config));
+    }
+
+    for (filter, problem) in [
+        (
+            "actix_web=loud",
+            r#""loud" is not one of off, error, warn, info, debug, trace"#,
+        ),
+        ("=info", r#""=info" is not a target=level directive"#),
+        ("a=b=info", r#""a=b=info" is not a target=level directive"#),
+        ("info/a/b", r#""info/a/b" has more than one '/'"#),
+    ] {
+        let vars = env(&[("APP_LOG_LEVEL", filter)]);
+        let err = invalid(Config::load_from(&ConfigArgs::default(), vars));
+        assert!(
+            err.contains(&format!("log_level: {}", problem)),
+            "{:?}: {}",
+            filter,
+            err
+        );
+    }
+}
+
+#[test]
+fn session_ttl_is_capped_at_a_year() {
+    let year = MAX_SESSION_TTL.to_string();
+    let config = Config::load_from(
+        &ConfigArgs::default(),
+        env(&[("APP_AUTH__SESSION__TTL", &year)]),
+    )
+    .unwrap();
+    assert_eq!(config.auth.session.ttl, MAX_SESSION_TTL);
+
+    for (ttl, problem) in [
+        ("0", "auth.session.ttl must be greater than 0"),
+        (
+            "31536001",
+            "auth.session.ttl must be at most 31536000 seconds",
+        ),
+        // Too large for a TOML integer, so it never reaches validation.
+        ("18446744073709551615", "expected an integer"),
+    ] {
+        let err = invalid(Config::load_from(
+            &ConfigArgs::default(),
+            env(&[("APP_AUTH__SESSION__TTL", ttl)]),
+        ));
+        assert!(err.contains(problem), "{}: {}", ttl, err);
+    }
+}
+
+#[test]
+fn allowed_origins_must_be_origins() {
+    let scratch = Scratch::new();
+    let path = scratch.dir.join("app.toml");
+    let load = |origins: &str| {
+        fs::write(
+            &path,
+            format!("[auth.session]\nallowed_origins = {}\n", origins),
+        )
+        .unwrap();
+        let args = ConfigArgs {
+            config: Some(path.clone()),
+            ..ConfigArgs::default()
+        };
+        Config::load_from(&args, env(&[]))
+    };
+
+    let config = load(r#"["https://app.example.com", "http://localhost:5173"]"#).unwrap();
+    assert_eq!(config.auth.session.allowed_origins.len(), 2);
+
+    for origin in [
+        "app.example.com",
+        "https://app.example.com/",
+        "ftp://x",
+        "https://",
+    ] {
+        let err = invalid(load(&format!("[{:?}]", origin)));
+        let problem = format!(
+            "auth.session.allowed_origins: {:?} is not an origin",
+            origin
+        );
+        assert!(err.contains(&problem), "{}", err);
+    }
+}
+
+#[test]
+fn event_buffers_are_capped() {
+    let max = MAX_EVENTS.to_string();
+    let vars = env(&[("APP_EVENTS__BUFFER", &max), ("APP_EVENTS__HISTORY", &max)]);
+    Config::load_from(&ConfigArgs::default(), vars).unwrap();
+
+    let over = (MAX_EVENTS + 1).to_string();
+    for setting in ["buffer", "history"] {
+        let key = format!("APP_EVENTS__{}", setting.to_ascii_uppercase());
+        let err = invalid(Config::load_from(
+            &ConfigArgs::default(),
+            env(&[(&key, &over)]),
+        ));
+        let problem = format!("events.{} must be at most {}", setting, MAX_EVENTS);
+        assert!(err.contains(&problem), "{}", err);
+    }
+}
@@ -0,0 +1,215 @@
+//! Content negotiation on the `/users` routes: bodies and responses in
+//! JSON, MessagePack, CBOR and YAML.
+
+mod common;
+
+use actix_web::http::{header, StatusCode};
+use serde_json::{json, Value};
+
+use actix_web_app::content::Format;
+use common::TestApp;
+
+#[actix_web::test]
+async fn bodies_are_read_in_every_format() {
+    let app = TestApp::spawn().await;
+    let info = json!({ "name": "Ada", "age": 36, "role": "editor" });
+
+    for (id, content_type, format) in [
+        (1, "application/json", Format::Json),
+        (2, "application/msgpack", Format::MessagePack),
+        (3, "application/x-msgpack", Format::MessagePack),
+        (4, "application/cbor", Format::Cbor),
+        (5, "application/yaml", Format::Yaml),
+        (6, "text/yaml; charset=utf-8", Format::Yaml),
+    ] {
+        let res = app
+            .call(
+                app.post("/users")
+                    .admin()
+                    .bytes(content_type, format.encode(&info)),
+            )
+            .await;
+        res.assert_status(StatusCode::CREATED);
+        res.assert_json_includes(json!({ "id": id, "name": "Ada", "age": 36, "role": "editor" }));
+    }
+}
+
+#[actix_web::test]
+async fn responses_follow_accept() {
+    let app = TestApp::spawn().await;
+    app.create_user("Ada", Some(36)).await;
+
+    for format in Format::ALL {
+        let res = app
+            .call(
+                app.get("/users/1")
+                    .admin()
+                    .header(header::ACCEPT, format.media_type()),
+            )
+            .await;
+        res.assert_status(StatusCode::OK);
+        assert_eq!(res.header(header::CONTENT_TYPE), Some(format.media_type()));
+        assert_eq!(res.header(header::VARY), Some("Accept"));
+        let user: Value = format.decode(&res.body).unwrap();
+        assert_eq!(user["name"], "Ada");
+        assert_eq!(user["age"], 36);
+    }
+
+    let res = app
+        .call(
+            app.get("/users")
+                .admin()
+                .header(header::ACCEPT, "application/cbor"),
+        )
+        .await;
+    res.assert_status(StatusCode::OK);
+    assert!(res.header(header::LINK).is_some());
+    let list: Value = Format::Cbor.decode(&res.body).unwrap();
+    assert_eq!(list["total"], 1);
+    assert_eq!(list["items"][0]["name"], "Ada");
+}
+
+#[actix_web::test]
+async fn accept_q_values_pick_the_format() {
+    let app = TestApp::spawn().await;
+    app.create_user("Ada", None).await;
+
+    for (accept, expected) in [
+        ("*/*", "application/json"),
+        (
+            "application/json;q=0.5, application/cbor",
+            "application/cbor",
+        ),
+        ("application/*;q=0.1, application/yaml", "application/yaml"),
+        ("*/*, application/json;q=0", "application/msgpack"),
+        ("text/*", "application/yaml"),
+        (
+            "application/vnd.msgpack;q=0.9, application/json;q=0.8",
+            "application/msgpack",
+        ),
+        ("text/html, application/json;q=0.1", "application/json"),
+    ] {
+        let res = app
+            .call(app.get("/users/1").admin().header(header::ACCEPT, accept))
+            .await;
+        res.assert_status(StatusCode::OK);
+        assert_eq!(
+            res.header(header::CONTENT_TYPE),
+            Some(expected),
+            "Accept: {}",
+            accept
+        );
+    }
+}
+
+#[actix_web::test]
+async fn unacceptable_requests_fail_before_the_handler() {
+    let app = TestApp::spawn().await;
+
+    let res = app
+        .call(
+            app.post("/users")
+                .admin()
+                .header(header::ACCEPT, "text/html")
+                .json(json!({ "name": "Ada" })),
+        )
+        .await;
+    let problem = res.assert_problem(StatusCode::NOT_ACCEPTABLE, "/problems/not-acceptable");
+    assert!(problem["detail"]
+        .as_str()
+        .unwrap()
+        .contains("application/msgpack"));
+
+    let res = app
+        .call(
+            app.get("/users")
+                .admin()
+                .header(header::ACCEPT, "application/*;q=0"),
+        )
+        .await;
+    res.assert_problem(StatusCode::NOT_ACCEPTABLE, "/problems/not-acceptable");
+
+    let res = app.call(app.get("/users").admin()).await;
+    res.assert_json_includes(json!({ "total": 0 }));
+}
+
+#[actix_web::test]
+async fn unreadable_bodies_are_rejected() {
+    let app = TestApp::spawn().await;
+
+    let res = app
+        .call(app.post("/users").admin().raw("text/plain", "Ada"))
+        .await;
+    let problem = res.assert_problem(
+        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        "/problems/unsupported-media-type",
+    );
+    assert!(problem["detail"]
+        .as_str()
+        .unwrap()
+        .contains("application/cbor"));
+
+    let res = app
+        .call(
+            app.post("/users")
+                .admin()
+                .bytes("application/msgpack", vec![0xc1]),
+        )
+        .await;
+    let problem = res.assert_problem(StatusCode::BAD_REQUEST, "/problems/invalid-body");
+    assert!(problem["detail"]
+        .as_str()
+        .unwrap()
+        .starts_with("Invalid MessagePack body"));
+
+    let res = app
+        .call(
+            app.post("/users")
+                .admin()
+                .raw("application/yaml", "name: [unclosed"),
+        )
+        .await;
+    res.assert_problem(StatusCode::BAD_REQUEST, "/problems/invalid-body");
+
+    let res = app
+        .call(app.post("/users").admin().bytes(
+            "application/cbor",
+            Format::Cbor.encode(&json!({ "name": "" })),
+        ))
+        .await;
+    res.assert_problem(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "/problems/validation-error",
+    );
+}
+
+#[actix_web::test]
+async fn put_and_patch_take_any_format() {
+    let app = TestApp::spawn().await;
+    app.create_user("Ada", Some(36)).await;
+
+    let res = app
+        .call(
+            app.put("/users/1")
+                .admin()
+                .header(header::ACCEPT, "application/yaml")
+                .bytes(
+                    "application/msgpack",
+                    Format::MessagePack.encode(&json!({ "name": "Grace", "age": 45 })),
+                ),
+        )
+        .await;
+    res.assert_status(StatusCode::OK);
+    let user: Value = Format::Yaml.decode(&res.body).unwrap();
+    assert_eq!(user["name"], "Grace");
+
+    let res = app
+        .call(app.patch("/users/1").admin().bytes(
+            "application/cbor",
+            Format::Cbor.encode(&json!({ "age": null })),
+        ))
+        .await;
+    res.assert_status(StatusCode::OK);
+    let user = res.assert_json_includes(json!({ "name": "Grace" }));
+    assert!(user["age"].is_null());
+}
@@ -0,0 +1,561 @@
+//! User change events on `/ws/users` and `/users/events`.
+
+mod common;
+
+use std::collections::BTreeMap;
+use std::thread;
+use std::time::Duration;
+
+use actix_web::http::{header, StatusCode};
+use actix_web::rt::time::timeout;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::{Error, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use actix_web_app::auth::{USERS_READ, USERS_WRITE};
+use actix_web_app::models::{Role, UserUpdate};
+use common::{test_config, token, Scratch, Server, TestApp};
+
+type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+fn admin_token() -> String {
+    token("admin", Role::Admin, &[USERS_READ, USERS_WRITE])
+}
+
+async fn connect(server: &Server, path: &str, token: &str) -> Result<Client, Error> {
+    connect_with(
+        server,
+        path,
+        &[("Authorization", format!("Bearer {}", token))],
+    )
+    .await
+}
+
+async fn connect_with(
+    server: &Server,
+    path: &str,
+    headers: &[(&'static str, String)],
+) -> Result<Client, Error> {
+    let mut request = server.ws_url(path).into_client_request().unwrap();
+    for (name, value) in headers {
+        request.headers_mut().insert(*name, value.parse().unwrap());
+    }
+    let (client, _) = tokio_tungstenite::connect_async(request).await?;
+    Ok(client)
+}
+
+#[track_caller]
+fn assert_refused(result: Result<Client, Error>, status: StatusCode) {
+    match result {
+        Err(Error::Http(res)) => assert_eq!(res.status().as_u16(), status.as_u16()),
+        Err(err) => panic!("expected a {} answer, got {}", status, err),
+        Ok(_) => panic!("expected a {} answer, got an open connection", status),
+    }
+}
+
+/// The next frame that isn't a ping or pong.
+async fn next_frame(client: &mut Client) -> Message {
+    loop {
+        let frame = timeout(Duration::from_secs(5), client.next())
+            .await
+            .expect("a frame within 5 seconds")
+            .expect("the connection is open")
+            .unwrap();
+        if !matches!(frame, Message::Ping(_) | Message::Pong(_)) {
+            return frame;
+        }
+    }
+}
+
+async fn next_json(client: &mut Client) -> Value {
+    match next_frame(client).await {
+        Message::Text(text) => serde_json::from_str(text.as_str()).unwrap(),
+        other => panic!("expected a text frame, got {:?}", other),
+    }
+}
+
+async fn next_close(client: &mut Client) -> CloseFrame {
+    loop {
+        match next_frame(client).await {
+            Message::Close(Some(frame)) => return frame,
+            Message::Text(_) => continue,
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+    }
+}
+
+#[actix_web::test]
+async fn changes_are_streamed_as_they_happen() {
+    let app = TestApp::spawn().await;
+    let server = app.serve();
+    let mut client = connect(&server, "/ws/users", &admin_token()).await.unwrap();
+
+    app.call(app.post("/users").admin().json(json!({ "name": "Ada" })))
+        .await
+        .assert_status(StatusCode::CREATED);
+    let created = next_json(&mut client).await;
+    assert_eq!(created["type"], "created");
+    assert_eq!(created["user_id"], 1);
+    assert_eq!(created["user"]["name"], "Ada");
+    assert!(created["at"].is_string());
+
+    app.call(app.patch("/users/1").admin().json(json!({ "age": 36 })))
+        .await
+        .assert_status(StatusCode::OK);
+    let updated = next_json(&mut client).await;
+    assert_eq!(updated["type"], "updated");
+    assert_eq!(updated["user"]["age"], 36);
+    assert_eq!(updated["id"], created["id"].as_u64().unwrap() + 1);
+
+    app.call(
+        app.put("/users/1")
+            .admin()
+            .json(json!({ "name": "Ada Lovelace" })),
+    )
+    .await
+    .assert_status(StatusCode::OK);
+    assert_eq!(next_json(&mut client).await["user"]["name"], "Ada Lovelace");
+
+    app.call(app.delete("/users/1").admin())
+        .await
+        .assert_status(StatusCode::NO_CONTENT);
+    let deleted = next_json(&mut client).await;
+    assert_eq!(deleted["type"], "deleted");
+    assert_eq!(deleted["user_id"], 1);
+    assert!(deleted.get("user").is_none());
+
+    app.sign_up("grace@example.com", "correct horse").await;
+    let signed_up = next_json(&mut client).await;
+    assert_eq!(signed_up["type"], "created");
+    assert_eq!(signed_up["user"]["email"], "grace@example.com");
+
+    server.handle.stop(false).await;
+}
+
+#[actix_web::test]
+async fn clients_can_filter_by_user() {
+    let app = TestApp::spawn().await;
+    app.create_user("Ada", None).await;
+    app.create_user("Grace", None).await;
+    let server = app.serve();
+    let mut client = connect(&server, "/ws/users?user_id=2", &admin_token())
+        .await
+        .unwrap();
+
+    for (id, age) in [(1, 36), (2, 45)] {
+        app.call(
+            app.patch(&format!("/users/{}", id))
+                .admin()
+                .json(json!({ "age": age })),
+        )
+        .await
+        .assert_status(StatusCode::OK);
+    }
+    assert_eq!(next_json(&mut client).await["user_id"], 2);
+
+    client
+        .send(Message::text(r#"{"user_id": 1}"#))
+        .await
+        .unwrap();
+    assert_eq!(
+        next_json(&mut client).await,
+        json!({ "type": "subscribed", "user_id": 1 })
+    );
+    for id in [2, 1] {
+        app.call(app.delete(&format!("/users/{}", id)).admin())
+            .await
+            .assert_status(StatusCode::NO_CONTENT);
+    }
+    assert_eq!(next_json(&mut client).await["user_id"], 1);
+
+    client.send(Message::text("nonsense")).await.unwrap();
+    assert_eq!(next_json(&mut client).await["type"], "error");
+
+    server.handle.stop(false).await;
+}
+
+#[actix_web::test]
+async fn viewers_only_see_their_own_changes() {
+    let app = TestApp::spawn().await;
+    app.create_user("Ada", None).await;
+    let server = app.serve();
+    let viewer = token("1", Role::Viewer, &[USERS_READ]);
+
+    match connect(&server, "/ws/users", &viewer).await {
+        Err(Error::Http(response)) => assert_eq!(response.status(), 403),
+        other => panic!("expected 403, got {:?}", other.map(|_| ())),
+    }
+    let mut client = connect(&server, "/ws/users?user_id=1", &viewer)
+        .await
+        .unwrap();
+
+    client
+        .send(Message::text(r#"{"user_id": null}"#))
+        .await
+        .unwrap();
+    let error = next_json(&mut client).await;
+    assert_eq!(error["type"], "error");
+
+    app.call(app.patch("/users/1").admin().json(json!({ "age": 36 })))
+        .await
+        .assert_status(StatusCode::OK);
+    assert_eq!(next_json(&mut client).await["user_id"], 1);
+
+    server.handle.stop(false).await;
+}
+
+#[actix_web::test]
+async fn the_upgrade_is_authenticated() {
+    let app = TestApp::spawn().await;
+
+    let res = app.call(app.get("/ws/users")).await;
+    res.assert_problem(StatusCode::UNAUTHORIZED, "/problems/unauthorized");
+
+    let res = app
+        .call(
+            app.get("/ws/users")
+                .bearer(&token("admin", Role::Admin, &[])),
+        )
+        .await;
+    res.assert_problem(StatusCode::FORBIDDEN, "/problems/forbidden");
+
+    // Authenticated, but not a WebSocket handshake.
+    let res = app.call(app.get("/ws/users").admin()).await;
+    res.assert_problem(StatusCode::BAD_REQUEST, "about:blank");
+}
+
+#[actix_web::test]
+async fn session_upgrades_need_an_allowed_origin() {
+    let mut config = test_config();
+    config.auth.session.allowed_origins = vec!["https://App.Example.com".to_string()];
+    let app = TestApp::with_config(config).await;
+    let server = app.serve();
+    let session = app.sign_up("ws@example.com", "correct horse battery").await;
+    let cookie = ("Cookie", session.cookie.stripped().to_string());
+    // The account is user 1, and sessions may only watch their own user.
+    let path = "/ws/users?user_id=1";
+
+    assert_refused(
+        connect_with(&server, path, std::slice::from_ref(&cookie)).await,
+        StatusCode::FORBIDDEN,
+    );
+    for origin in [
+        "https://evil.example.com",
+        "null",
+        "https://app.example.com.evil",
+    ] {
+        let headers = [cookie.clone(), ("Origin", origin.to_string())];
+        assert_refused(
+            connect_with(&server, path, &headers).await,
+            StatusCode::FORBIDDEN,
+        );
+    }
+
+    for origin in [
+        format!("http://{}", server.addr),
+        "https://app.example.com".to_string(),
+    ] {
+        let headers = [cookie.clone(), ("Origin", origin.clone())];
+        let result = connect_with(&server, path, &headers).await;
+        assert!(result.is_ok(), "{}: {}", origin, result.err().unwrap());
+    }
+
+    // Tokens aren't sent by the browser on its own, so any origin may use them.
+    let headers = [
+        ("Authorization", format!("Bearer {}", admin_token())),
+        ("Origin", "https://evil.example.com".to_string()),
+    ];
+    assert!(connect_with(&server, "/ws/users", &headers).await.is_ok());
+    server.handle.stop(false).await;
+}
+
+#[actix_web::test]
+async fn the_server_pings_clients() {
+    let mut config = test_config();
+    config.events.heartbeat = 1;
+    config.events.client_timeout = 5;
+    let app = TestApp::with_config(config).await;
+    let server = app.serve();
+    let mut client = connect(&server, "/ws/users", &admin_token()).await.unwrap();
+
+    let frame = timeout(Duration::from_secs(5), client.next())
+        .await
+        .expect("a ping within 5 seconds")
+        .unwrap()
+        .unwrap();
+    assert!(matches!(frame, Message::Ping(_)), "got {:?}", frame);
+
+    server.handle.stop(false).await;
+}
+
+#[actix_web::test]
+async fn slow_clients_are_disconnected() {
+    let mut config = test_config();
+    config.events.buffer = 1;
+    let app = TestApp::with_config(config).await;
+    let user = app.create_user("Ada", None).await;
+    let server = app.serve();
+    let mut client = connect(&server, "/ws/users", &admin_token()).await.unwrap();
+
+    // Far faster than one connection's task can forward them.
+    for _ in 0..10_000 {
+        app.state.events.updated(&user);
+    }
+    let close = next_close(&mut client).await;
+    assert_eq!(close.code, CloseCode::Again);
+    assert!(close.reason.contains("events behind"), "{}", close.reason);
+
+    server.handle.stop(false).await;
+}
+
+#[actix_web::test]
+async fn draining_closes_the_streams() {
+    let app = TestApp::spawn().await;
+    let server = app.serve();
+    let mut client = connect(&server, "/ws/users", &admin_token()).await.unwrap();
+
+    let shutdown = app.state.shutdown.clone();
+    let handle = server.handle.clone();
+    actix_web::rt::spawn(async move { shutdown.watch(handle).await });
+    app.state.shutdown.request();
+
+    let close = next_close(&mut client).await;
+    assert_eq!(close.code, CloseCode::Away);
+}
+
+#[actix_web::test]
+async fn changes_are_sent_as_server_sent_events() {
+    let app = TestApp::spawn().await;
+    let mut stream = app.open(app.get("/users/events").admin()).await;
+    assert_eq!(stream.status, StatusCode::OK);
+    assert_eq!(
+        stream.headers.get(header::CONTENT_TYPE).unwrap(),
+        "text/event-stream"
+    );
+    assert_eq!(
+        stream.headers.get(header::CACHE_CONTROL).unwrap(),
+        "no-cache"
+    );
+    assert_eq!(stream.next_frame().await.unwrap(), "retry: 3000");
+
+    app.call(app.post("/users").admin().json(json!({ "name": "Ada" })))
+        .await
+        .assert_status(StatusCode::CREATED);
+    app.call(app.patch("/users/1").admin().json(json!({ "age": 36 })))
+        .await
+        .assert_status(StatusCode::OK);
+    app.call(app.delete("/users/1").admin())
+        .await
+        .assert_status(StatusCode::NO_CONTENT);
+
+    for (id, kind) in [(1, "created"), (2, "updated"), (3, "deleted")] {
+        let event = stream.next_event().await;
+        assert_eq!(event.id, Some(id));
+        assert_eq!(event.event.as_deref(), Some(kind));
+        assert_eq!(event.data["id"], id);
+        assert_eq!(event.data["type"], kind);
+        assert_eq!(event.data["user_id"], 1);
+    }
+}
+
+#[actix_web::test]
+async fn reconnecting_clients_resume_after_the_last_event_id() {
+    let app = TestApp::spawn().await;
+    for name in ["Ada", "Grace", "Edsger"] {
+        app.create_user(name, None).await;
+    }
+
+    let mut stream = app
+        .open(
+            app.get("/users/events")
+                .admin()
+                .header("Last-Event-ID", "1"),
+        )
+        .await;
+    assert_eq!(stream.next_event().await.data["user"]["name"], "Grace");
+    assert_eq!(stream.next_event().await.data["user"]["name"], "Edsger");
+
+    app.create_user("Barbara", None).await;
+    let live = stream.next_event().await;
+    assert_eq!(live.id, Some(4));
+    assert_eq!(live.data["user"]["name"], "Barbara");
+}
+
+#[actix_web::test]
+async fn events_that_are_gone_are_replaced_by_a_reset() {
+    let mut config = test_config();
+    config.events.history = 2;
+    let app = TestApp::with_config(config).await;
+    for name in ["Ada", "Grace", "Edsger", "Barbara"] {
+        app.create_user(name, None).await;
+    }
+
+    // Event 2 was evicted; from before a restart; not an id at all.
+    for last_event_id in ["1", "99", "latest"] {
+        let mut stream = app
+            .open(
+                app.get("/users/events")
+                    .admin()
+                    .header("Last-Event-ID", last_event_id),
+            )
+            .await;
+        let reset = stream.next_event().await;
+        assert_eq!(reset.event.as_deref(), Some("reset"), "{}", last_event_id);
+        assert_eq!(reset.id, Some(4));
+        assert!(reset.data["detail"].is_string());
+    }
+
+    let mut stream = app
+        .open(
+            app.get("/users/events")
+                .admin()
+                .header("Last-Event-ID", "2"),
+        )
+        .await;
+    assert_eq!(stream.next_event().await.id, Some(3));
+    assert_eq!(stream.next_event().await.id, Some(4));
+}
+
+#[actix_web::test]
+async fn idle_streams_get_keep_alive_comments() {
+    let mut config = test_config();
+    config.events.heartbeat = 1;
+    let app = TestApp::with_config(config).await;
+    let mut stream = app.open(app.get("/users/events").admin()).await;
+
+    assert_eq!(stream.next_frame().await.unwrap(), "retry: 3000");
+    assert_eq!(stream.next_frame().await.unwrap(), ": keep-alive");
+}
+
+#[actix_web::test]
+async fn lagging_streams_catch_up_from_the_history() {
+    let mut config = test_config();
+    config.events.buffer = 1;
+    let app = TestApp::with_config(config).await;
+    let user = app.create_user("Ada", None).await;
+    let mut stream = app
+        .open(
+            app.get("/users/events")
+                .admin()
+                .header("Last-Event-ID", "1"),
+        )
+        .await;
+
+    // Published before the stream is read, so it falls behind.
+    for _ in 0..200 {
+        app.state.events.updated(&user);
+    }
+    for id in 2..=201 {
+        assert_eq!(stream.next_event().await.id, Some(id));
+    }
+}
+
+#[actix_web::test]
+async fn event_streams_are_authorized() {
+    let app = TestApp::spawn().await;
+    app.create_user("Ada", None).await;
+    app.create_user("Grace", None).await;
+
+    let res = app.call(app.get("/users/events")).await;
+    res.assert_problem(StatusCode::UNAUTHORIZED, "/problems/unauthorized");
+    let res = app
+        .call(
+            app.get("/users/events")
+                .bearer(&token("admin", Role::Admin, &[])),
+        )
+        .await;
+    res.assert_problem(StatusCode::FORBIDDEN, "/problems/forbidden");
+    let res = app
+        .call(app.get("/users/events").user(1, Role::Viewer))
+        .await;
+    res.assert_problem(StatusCode::FORBIDDEN, "/problems/forbidden");
+
+    let mut stream = app
+        .open(app.get("/users/events?user_id=1").user(1, Role::Viewer))
+        .await;
+    assert_eq!(stream.status, StatusCode::OK);
+    for id in [2, 1] {
+        app.call(app.delete(&format!("/users/{}", id)).admin())
+            .await
+            .assert_status(StatusCode::NO_CONTENT);
+    }
+    let event = stream.next_event().await;
+    assert_eq!(event.data["user_id"], 1);
+    assert_eq!(event.id, Some(4));
+}
+
+#[actix_web::test]
+async fn draining_ends_the_event_streams() {
+    let app = TestApp::spawn().await;
+    let server = app.serve();
+    let mut stream = app.open(app.get("/users/events").admin()).await;
+
+    let shutdown = app.state.shutdown.clone();
+    let handle = server.handle.clone();
+    actix_web::rt::spawn(async move { shutdown.watch(handle).await });
+    app.state.shutdown.request();
+
+    stream.assert_ends().await;
+}
+
+#[actix_web::test]
+async fn events_follow_the_order_changes_were_stored() {
+    const WRITERS: u8 = 8;
+    const ROUNDS: u8 = 50;
+    let scratch = Scratch::new();
+    for spec in scratch.backends() {
+        let mut config = test_config();
+        config.storage = spec.clone();
+        config.events.history = 10_000;
+        let app = TestApp::with_config(config).await;
+        let mut ids = Vec::new();
+        for name in ["Ada", "Grace"] {
+            ids.push(app.create_user(name, None).await.id);
+        }
+
+        // Writers race on every user; the last event for each must carry
+        // what the store ended up with.
+        let store = &app.state.store;
+        thread::scope(|scope| {
+            for writer in 0..WRITERS {
+                let ids = &ids;
+                scope.spawn(move || {
+                    for round in 0..ROUNDS {
+                        for &id in ids {
+                            let update = UserUpdate {
+                                id: None,
+                                name: format!("Writer {} round {}", writer, round),
+                                age: Some(writer * 5 + round % 5),
+                                email: None,
+                                role: None,
+                            };
+                            store.replace(id, update).unwrap();
+                        }
+                    }
+                });
+            }
+        });
+
+        let events = app.state.events.resume(Some(0)).missed.unwrap();
+        let writes = usize::from(WRITERS) * usize::from(ROUNDS) * ids.len();
+        assert_eq!(events.len(), ids.len() + writes, "{}", spec);
+        let mut last = BTreeMap::new();
+        for event in &events {
+            last.insert(event.user_id, event.user.clone());
+        }
+        for id in ids {
+            assert_eq!(
+                last[&id],
+                app.state.store.get(id).unwrap(),
+                "{}: user {}",
+                spec,
+                id
+            );
+        }
+    }
+}
@@ -0,0 +1,404 @@
+//! The GraphQL API on `/graphql`.
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use actix_web::http::{header, StatusCode};
+use actix_web::rt::time::timeout;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::{Error, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use actix_web_app::auth::{USERS_READ, USERS_WRITE};
+use actix_web_app::graphql;
+use actix_web_app::models::{Role, UserUpdate};
+use common::{token, Req, Server, TestApp};
+
+type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+fn graphql(req: Req, query: &str, variables: Value) -> Req {
+    req.json(json!({ "query": query, "variables": variables }))
+}
+
+/// The first error's problem document, from its `extensions`.
+#[track_caller]
+fn problem(response: &Value) -> &Value {
+    let errors = response["errors"]
+        .as_array()
+        .unwrap_or_else(|| panic!("expected errors: {}", response));
+    &errors[0]["extensions"]
+}
+
+/// The next text frame, as JSON.
+async fn next_text(client: &mut Client) -> Value {
+    loop {
+        let frame = timeout(Duration::from_secs(5), client.next())
+            .await
+            .expect("a frame within 5 seconds")
+            .expect("the connection is open")
+            .unwrap();
+        if let Message::Text(text) = frame {
+            return serde_json::from_str(text.as_str()).unwrap();
+        }
+    }
+}
+
+#[test]
+fn schema_matches_snapshot() {
+    let sdl = graphql::sdl();
+    let snapshot = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/snapshots/schema.graphql");
+    if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+        fs::write(&snapshot, &sdl).expect("write snapshot");
+        return;
+    }
+    let expected = fs::read_to_string(&snapshot).expect("read snapshot");
+    assert!(
+        sdl == expected,
+        "the GraphQL schema differs from {}; if the change is intended, run \
+         `UPDATE_SNAPSHOTS=1 cargo test --test graphql` and commit the result",
+        snapshot.display()
+    );
+}
+
+#[actix_web::test]
+async fn queries_read_users() {
+    let app = TestApp::spawn().await;
+    let ada = app.create_user("Ada", Some(36)).await;
+    app.create_user("Grace", Some(45)).await;
+    app.create_user("Alan", Some(41)).await;
+
+    let res = app
+        .call(graphql(
+            app.post("/graphql").admin(),
+            "query($id: Int!) { user(id: $id) { id name age role createdAt } }",
+            json!({ "id": ada.id }),
+        ))
+        .await;
+    res.assert_json_includes(json!({
+        "data": { "user": { "id": ada.id, "name": "Ada", "age": 36, "role": "VIEWER" } }
+    }));
+
+    let res = app
+        .call(graphql(
+            app.post("/graphql").admin(),
+            "{ user(id: 99) { id } }",
+            json!({}),
+        ))
+        .await;
+    res.assert_json_includes(json!({ "data": { "user": null } }));
+
+    let query = "query($after: String) {
+        users(limit: 1, sort: \"-age\", after: $after, filter: { minAge: 40 }) {
+            total next items { name }
+        }
+    }";
+    let first = app
+        .call(graphql(app.post("/graphql").admin(), query, json!({})))
+        .await
+        .json();
+    assert_eq!(first["data"]["users"]["total"], 2);
+    assert_eq!(
+        first["data"]["users"]["items"],
+        json!([{ "name": "Grace" }])
+    );
+    let next = &first["data"]["users"]["next"];
+    assert!(next.is_string(), "{}", first);
+
+    let second = app
+        .call(graphql(
+            app.post("/graphql").admin(),
+            query,
+            json!({ "after": next }),
+        ))
+        .await
+        .json();
+    assert_eq!(
+        second["data"]["users"]["items"],
+        json!([{ "name": "Alan" }])
+    );
+    assert_eq!(second["data"]["users"]["next"], Value::Null);
+}
+
+#[actix_web::test]
+async fn mutations_change_users() {
+    let app = TestApp::spawn().await;
+
+    let res = app
+        .call(graphql(
+            app.post("/graphql").admin(),
+            "mutation($input: CreateUserInput!) { createUser(input: $input) { id name email } }",
+            json!({ "input": { "name": "Ada", "age": 36, "email": "ada@example.com" } }),
+        ))
+        .await;
+    let created = res.json();
+    let id = created["data"]["createUser"]["id"].as_u64().unwrap();
+    app.call(app.get(&format!("/users/{}", id)).admin())
+        .await
+        .assert_json_includes(json!({ "name": "Ada", "age": 36 }));
+
+    // Omitted fields are kept and null clears.
+    let res = app
+        .call(graphql(
+            app.post("/graphql").admin(),
+            "mutation($id: Int!) { updateUser(id: $id, input: { email: null, role: EDITOR }) { name age email role } }",
+            json!({ "id": id }),
+        ))
+        .await;
+    res.assert_json_includes(json!({
+        "data": { "updateUser": { "name": "Ada", "age": 36, "email": null, "role": "EDITOR" } }
+    }));
+
+    let delete = "mutation($id: Int!) { deleteUser(id: $id) }";
+    let res = app
+        .call(graphql(
+            app.post("/graphql").admin(),
+            delete,
+            json!({ "id": id }),
+        ))
+        .await;
+    res.assert_json_includes(json!({ "data": { "deleteUser": id } }));
+    let again = app
+        .call(graphql(
+            app.post("/graphql").admin(),
+            delete,
+            json!({ "id": id }),
+        ))
+        .await
+        .json();
+    assert_eq!(problem(&again)["type"], "/problems/not-found");
+    assert_eq!(problem(&again)["status"], 404);
+    assert_eq!(
+        again["errors"][0]["message"],
+        format!("User {} not found", id)
+    );
+}
+
+#[actix_web::test]
+async fn mutations_are_validated_like_the_rest_routes() {
+    let app = TestApp::spawn().await;
+
+    let res = app
+        .call(graphql(
+            app.post("/graphql").admin(),
+            "mutation { createUser(input: { name: \"\", age: 200 }) { id } }",
+            json!({}),
+        ))
+        .await;
+    res.assert_status(StatusCode::OK);
+    let response = res.json();
+    let failed = problem(&response);
+    assert_eq!(failed["type"], "/problems/validation-error");
+    assert_eq!(failed["status"], 422);
+    assert!(failed["errors"]["name"].is_array(), "{}", failed);
+    assert!(failed["errors"]["age"].is_array(), "{}", failed);
+    assert!(failed["request_id"].is_string(), "{}", failed);
+
+    let page = app
+        .call(graphql(
+            app.post("/graphql").admin(),
+            "{ users(limit: 500) { total } }",
+            json!({}),
+        ))
+        .await
+        .json();
+    assert_eq!(problem(&page)["type"], "/problems/invalid-query");
+}
+
+#[actix_web::test]
+async fn operations_are_authorized() {
+    let app = TestApp::spawn().await;
+    let ada = app.create_user("Ada", None).await;
+    app.create_user("Grace", None).await;
+
+    let res = app
+        .call(graphql(
+            app.post("/graphql"),
+            "{ users { total } }",
+            json!({}),
+        ))
+        .await;
+    res.assert_problem(StatusCode::UNAUTHORIZED, "/problems/unauthorized");
+
+    // Viewers may read themselves, but not list or read others.
+    let viewer = || app.post("/graphql").user(ada.id, Role::Viewer);
+    let res = app
+        .call(graphql(
+            viewer(),
+            "{ me: user(id: 1) { name } other: user(id: 2) { name } }",
+            json!({}),
+        ))
+        .await
+        .json();
+    assert_eq!(res["data"]["me"]["name"], "Ada");
+    assert_eq!(res["data"]["other"], Value::Null);
+    assert_eq!(problem(&res)["type"], "/problems/forbidden");
+
+    let read_only = token("admin", Role::Admin, &[USERS_READ]);
+    let res = app
+        .call(graphql(
+            app.post("/graphql").bearer(&read_only),
+            "mutation { deleteUser(id: 2) }",
+            json!({}),
+        ))
+        .await
+        .json();
+    assert_eq!(problem(&res)["type"], "/problems/forbidden");
+    assert_eq!(res["errors"][0]["message"], "Missing scope \"users:write\"");
+    app.call(app.get("/users/2").admin())
+        .await
+        .assert_status(StatusCode::OK);
+}
+
+/// Opens a `graphql-transport-ws` connection with `headers` and waits for
+/// the server to acknowledge it.
+async fn connect(server: &Server, headers: &[(&'static str, String)]) -> Result<Client, Error> {
+    let mut request = server.ws_url("/graphql").into_client_request().unwrap();
+    for (name, value) in headers {
+        request.headers_mut().insert(*name, value.parse().unwrap());
+    }
+    request.headers_mut().insert(
+        "Sec-WebSocket-Protocol",
+        "graphql-transport-ws".parse().unwrap(),
+    );
+    let (mut client, response) = tokio_tungstenite::connect_async(request).await?;
+    assert_eq!(
+        response.headers()["Sec-WebSocket-Protocol"],
+        "graphql-transport-ws"
+    );
+
+    client
+        .send(Message::text(r#"{"type": "connection_init"}"#))
+        .await
+        .unwrap();
+    assert_eq!(next_text(&mut client).await["type"], "connection_ack");
+    Ok(client)
+}
+
+#[actix_web::test]
+async fn subscriptions_stream_changes() {
+    let app = TestApp::spawn().await;
+    let server = app.serve();
+    let admin = token("admin", Role::Admin, &[USERS_READ, USERS_WRITE]);
+    let mut client = connect(&server, &[("Authorization", format!("Bearer {}", admin))])
+        .await
+        .unwrap();
+    client
+        .send(Message::text(
+            json!({
+                "id": "1",
+                "type": "subscribe",
+                "payload": { "query": "subscription { userChanged { type userId user { name } } }" },
+            })
+            .to_string(),
+        ))
+        .await
+        .unwrap();
+    // Subscribing happens after the message is handled; give it a moment.
+    actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+
+    app.call(app.post("/users").admin().json(json!({ "name": "Ada" })))
+        .await
+        .assert_status(StatusCode::CREATED);
+    let next = next_text(&mut client).await;
+    assert_eq!(next["type"], "next");
+    assert_eq!(next["id"], "1");
+    assert_eq!(
+        next["payload"]["data"]["userChanged"],
+        json!({ "type": "CREATED", "userId": 1, "user": { "name": "Ada" } })
+    );
+
+    server.handle.stop(false).await;
+}
+
+#[actix_web::test]
+async fn websockets_only_run_subscriptions() {
+    let app = TestApp::spawn().await;
+    let server = app.serve();
+    let session = app.sign_up("ws@example.com", "correct horse battery").await;
+    let cookie = ("Cookie", session.cookie.stripped().to_string());
+    // An admin, so only the transport can stop the mutations below.
+    let promote = UserUpdate {
+        id: None,
+        name: "Session User".to_string(),
+        age: None,
+        email: Some("ws@example.com".to_string()),
+        role: Some(Role::Admin),
+    };
+    app.state.store.replace(1, promote).unwrap();
+
+    // The upgrade needs no CSRF token, so other origins can't use the cookie.
+    let headers = [
+        cookie.clone(),
+        ("Origin", "https://evil.example.com".to_string()),
+    ];
+    match connect(&server, &headers).await {
+        Err(Error::Http(res)) => assert_eq!(res.status().as_u16(), 403),
+        Err(err) => panic!("expected 403, got {}", err),
+        Ok(_) => panic!("expected 403, got an open connection"),
+    }
+
+    // Even from the server's own origin, the socket can't change users.
+    let headers = [cookie, ("Origin", format!("http://{}", server.addr))];
+    let mut client = connect(&server, &headers).await.unwrap();
+    for (id, query) in [
+        (
+            "1",
+            r#"mutation { createUser(input: { name: "Mallory" }) { id } }"#,
+        ),
+        ("2", "query { users { total } }"),
+        (
+            "3",
+            "subscription S { userChanged { type } } mutation M { deleteUser(id: 1) }",
+        ),
+    ] {
+        let mut payload = json!({ "query": query });
+        if id == "3" {
+            payload["operationName"] = json!("M");
+        }
+        client
+            .send(Message::text(
+                json!({ "id": id, "type": "subscribe", "payload": payload }).to_string(),
+            ))
+            .await
+            .unwrap();
+        let reply = next_text(&mut client).await;
+        assert_eq!(reply["id"], id, "{}", reply);
+        assert!(
+            reply
+                .to_string()
+                .contains("Only subscriptions may be sent over a WebSocket"),
+            "{}",
+            reply
+        );
+        if reply["type"] == "next" {
+            let complete = next_text(&mut client).await;
+            assert_eq!(complete, json!({ "id": id, "type": "complete" }));
+        }
+    }
+
+    let users = app.state.store.list().unwrap();
+    assert_eq!(users.len(), 1, "only the signed-up account");
+    server.handle.stop(false).await;
+}
+
+#[actix_web::test]
+async fn the_explorer_page_is_self_contained() {
+    let app = TestApp::spawn().await;
+
+    let res = app.call(app.get("/graphiql")).await;
+    res.assert_status(StatusCode::OK);
+    assert_eq!(
+        res.header(header::CONTENT_TYPE),
+        Some("text/html; charset=utf-8")
+    );
+    let page = res.text();
+    assert!(page.contains(r#"const endpoint = "graphql";"#));
+    assert!(!page.contains("<script src"), "loads external scripts");
+    assert!(!page.contains("<link"), "loads external styles");
+}
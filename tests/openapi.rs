@@ -0,0 +1,35 @@
+//! Fails when the generated OpenAPI spec no longer matches
+//! `tests/snapshots/openapi.json`. After an intended API change, refresh the
+//! snapshot with `UPDATE_SNAPSHOTS=1 cargo test --test openapi` and commit it.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn spec_matches_snapshot() {
+    let output = Command::new(env!("CARGO_BIN_EXE_actix-web-app"))
+        .arg("openapi")
+        .env_clear()
+        .output()
+        .expect("run the openapi subcommand");
+    assert!(
+        output.status.success(),
+        "openapi subcommand failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let spec = String::from_utf8(output.stdout).expect("spec is UTF-8");
+
+    let snapshot = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/snapshots/openapi.json");
+    if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+        fs::write(&snapshot, &spec).expect("write snapshot");
+        return;
+    }
+    let expected = fs::read_to_string(&snapshot).expect("read snapshot");
+    assert!(
+        spec == expected,
+        "the OpenAPI spec differs from {}; if the change is intended, run \
+         `UPDATE_SNAPSHOTS=1 cargo test --test openapi` and commit the result",
+        snapshot.display()
+    );
+}
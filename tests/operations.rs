@@ -0,0 +1,199 @@
+//! `/`, the probes, metrics, rate limits, the API docs and what happens on
+//! routes that don't exist.
+
+mod common;
+
+use actix_web::error::ErrorInternalServerError;
+use actix_web::http::{header, StatusCode};
+use actix_web::middleware::ErrorHandlers;
+use actix_web::{test, web, App, HttpResponse};
+use serde_json::json;
+
+use actix_web_app::config::RateLimitRule;
+use actix_web_app::error;
+use common::{test_config, TestApp};
+
+#[actix_web::test]
+async fn root_greets_anonymous_callers_by_default() {
+    let app = TestApp::spawn().await;
+
+    let res = app.call(app.get("/")).await;
+    res.assert_status(StatusCode::OK);
+    assert_eq!(res.text(), "Hello World!");
+    assert!(res.header("x-request-id").is_some());
+}
+
+#[actix_web::test]
+async fn root_can_require_credentials() {
+    let mut config = test_config();
+    config.auth.anonymous_root = false;
+    let app = TestApp::with_config(config).await;
+
+    let res = app.call(app.get("/")).await;
+    res.assert_problem(StatusCode::UNAUTHORIZED, "/problems/unauthorized");
+
+    let res = app.call(app.get("/").bearer("not-a-jwt")).await;
+    res.assert_problem(StatusCode::UNAUTHORIZED, "/problems/unauthorized");
+
+    let res = app.call(app.get("/").admin()).await;
+    res.assert_status(StatusCode::OK);
+    assert_eq!(res.text(), "Hello World!");
+}
+
+#[actix_web::test]
+async fn probes_pass_on_a_healthy_instance() {
+    let app = TestApp::spawn().await;
+
+    let res = app.call(app.get("/healthz")).await;
+    res.assert_status(StatusCode::OK);
+    res.assert_json_includes(json!({ "status": "pass" }));
+
+    let res = app.call(app.get("/readyz")).await;
+    res.assert_status(StatusCode::OK);
+    res.assert_json_includes(json!({ "status": "pass" }));
+
+    let res = app.call(app.get("/health/details")).await;
+    res.assert_status(StatusCode::OK);
+    let details = res.assert_json_includes(json!({
+        "status": "pass",
+        "version": env!("CARGO_PKG_VERSION"),
+    }));
+    let checks: Vec<_> = details["checks"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|check| check["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(checks, ["storage", "migrations", "shutdown"]);
+}
+
+#[actix_web::test]
+async fn rate_limits_apply_per_route() {
+    let mut config = test_config();
+    config.rate_limit.rules.push(RateLimitRule {
+        route: "/healthz".to_string(),
+        method: "GET".to_string(),
+        capacity: 2,
+        period: 60,
+    });
+    let app = TestApp::with_config(config).await;
+
+    for remaining in ["1", "0"] {
+        let res = app.call(app.get("/healthz")).await;
+        res.assert_status(StatusCode::OK);
+        assert_eq!(res.header("ratelimit-remaining"), Some(remaining));
+        assert_eq!(res.header("ratelimit-policy"), Some("2;w=60"));
+    }
+    let res = app.call(app.get("/healthz")).await;
+    res.assert_problem(StatusCode::TOO_MANY_REQUESTS, "/problems/rate-limited");
+    assert!(res.header(header::RETRY_AFTER).is_some());
+
+    let res = app.call(app.get("/readyz")).await;
+    res.assert_status(StatusCode::OK);
+    assert!(res.header("ratelimit-limit").is_none());
+}
+
+#[actix_web::test]
+async fn metrics_count_requests_by_route() {
+    let app = TestApp::spawn().await;
+    app.call(app.get("/users/1").admin()).await;
+    app.call(app.post("/users").admin().json(json!({ "name": "Ada" })))
+        .await;
+
+    let res = app.call(app.get("/metrics")).await;
+    res.assert_status(StatusCode::OK);
+    assert!(res
+        .header(header::CONTENT_TYPE)
+        .unwrap()
+        .starts_with("text/plain"));
+    let text = res.text();
+    assert!(
+        text.contains(r#"http_requests_total{method="GET",route="/users/{id}",status="404"} 1"#),
+        "{}",
+        text
+    );
+    assert!(text.contains("users_created_total 1"), "{}", text);
+}
+
+#[actix_web::test]
+async fn the_api_describes_itself() {
+    let app = TestApp::spawn().await;
+
+    let res = app.call(app.get("/openapi.json")).await;
+    res.assert_status(StatusCode::OK);
+    let spec = res.assert_json_includes(json!({
+        "openapi": "3.1.0",
+        "info": { "title": "Users API" },
+    }));
+    assert!(spec["paths"]["/users/{id}"]["patch"].is_object());
+
+    let res = app.call(app.get("/docs")).await;
+    res.assert_status(StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(res.header(header::LOCATION), Some("docs/"));
+
+    let res = app.call(app.get("/docs/")).await;
+    res.assert_status(StatusCode::OK);
+    assert!(res.text().contains("swagger-ui"));
+}
+
+#[actix_web::test]
+async fn unknown_routes_are_problems() {
+    let app = TestApp::spawn().await;
+
+    let res = app.call(app.get("/nope")).await;
+    let problem = res.assert_problem(StatusCode::NOT_FOUND, "/problems/not-found");
+    assert_eq!(problem["detail"], "No route for GET /nope");
+
+    let res = app.call(app.get("/users/1/friends").admin()).await;
+    res.assert_problem(StatusCode::NOT_FOUND, "/problems/not-found");
+}
+
+#[actix_web::test]
+async fn unsupported_methods_are_problems() {
+    let app = TestApp::spawn().await;
+
+    let res = app.call(app.delete("/users").admin()).await;
+    res.assert_problem(StatusCode::METHOD_NOT_ALLOWED, "about:blank");
+    let allow = res.header(header::ALLOW).expect("405 keeps Allow");
+    assert!(allow.contains("GET") && allow.contains("POST"), "{}", allow);
+}
+
+#[actix_web::test]
+async fn other_server_errors_do_not_leak_their_text() {
+    let app = test::init_service(
+        App::new()
+            .wrap(ErrorHandlers::new().default_handler(error::render_problem))
+            .route(
+                "/fails",
+                web::get().to(|| async {
+                    Err::<HttpResponse, _>(ErrorInternalServerError("/var/lib/app/secret.db"))
+                }),
+            ),
+    )
+    .await;
+
+    let res = test::call_service(&app, test::TestRequest::get().uri("/fails").to_request()).await;
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    let problem: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(
+        problem["detail"], "The server could not complete the request",
+        "{}",
+        problem
+    );
+}
+
+#[actix_web::test]
+async fn request_ids_are_echoed_into_problems() {
+    let app = TestApp::spawn().await;
+
+    let res = app
+        .call(
+            app.get("/users/abc")
+                .admin()
+                .header("X-Request-Id", "test-123"),
+        )
+        .await;
+    let problem = res.assert_problem(StatusCode::BAD_REQUEST, "/problems/invalid-path");
+    assert_eq!(problem["request_id"], "test-123");
+    assert_eq!(res.header("x-request-id"), Some("test-123"));
+}
@@ -0,0 +1,463 @@
+//! The storage backends, checked against each other: every backend must
+//! behave like the in-memory one and return the pages [`UserQuery::apply`]
+//! computes.
+
+mod common;
+
+use std::{fs, thread};
+
+use actix_web::http::StatusCode;
+use chrono::{TimeDelta, Utc};
+use rusqlite::Connection;
+use serde_json::json;
+
+use actix_web_app::models::{ApiKey, Info, Role, User, UserUpdate};
+use actix_web_app::storage::{self, Cursor, StorageError, UserFilter, UserQuery, UserRepository};
+use common::{test_config, Scratch, TestApp};
+
+fn info(name: &str, age: Option<u8>, email: Option<&str>) -> Info {
+    Info {
+        name: name.to_string(),
+        age,
+        email: email.map(str::to_string),
+        role: None,
+    }
+}
+
+/// Users with repeated names and ages and missing fields, so that sorting
+/// needs the tiebreaks and the `NULL` handling.
+fn seed(store: &dyn UserRepository) {
+    let users = [
+        ("Ada", Some(36), Some("ada@example.com")),
+        ("Grace", Some(45), None),
+        ("alan", None, Some("alan@example.org")),
+        ("Ada", Some(45), Some("ada.b@example.org")),
+        ("Edsger", Some(36), None),
+        ("Barbara", None, None),
+        ("Ken", Some(0), Some("ken@example.com")),
+        ("grace", Some(45), Some("GRACE@example.com")),
+    ];
+    for (name, age, email) in users {
+        store.create(info(name, age, email)).unwrap();
+    }
+}
+
+fn names(users: &[User]) -> Vec<&str> {
+    users.iter().map(|user| user.name.as_str()).collect()
+}
+
+fn filters() -> Vec<UserFilter> {
+    vec![
+        UserFilter::default(),
+        UserFilter {
+            name_contains: Some("A".to_string()),
+            ..UserFilter::default()
+        },
+        UserFilter {
+            email_contains: Some("EXAMPLE.COM".to_string()),
+            ..UserFilter::default()
+        },
+        UserFilter {
+            min_age: Some(36),
+            max_age: Some(45),
+            ..UserFilter::default()
+        },
+    ]
+}
+
+#[test]
+fn crud_behaves_the_same_on_every_backend() {
+    let scratch = Scratch::new();
+    for spec in scratch.backends() {
+        let store = storage::open(&spec).unwrap();
+
+        let ada = store
+            .create(info("Ada", Some(36), Some("ada@example.com")))
+            .unwrap();
+        let grace = store.create(info("Grace", None, None)).unwrap();
+        assert_eq!((ada.id, grace.id), (1, 2), "{}", spec);
+        assert_eq!(ada.role, Role::Viewer, "{}", spec);
+        assert_eq!(store.get(ada.id).unwrap(), Some(ada.clone()), "{}", spec);
+        assert_eq!(store.get(99).unwrap(), None, "{}", spec);
+
+        let update = UserUpdate {
+            id: None,
+            name: "Ada Lovelace".to_string(),
+            age: None,
+            email: Some("ada@example.com".to_string()),
+            role: Some(Role::Editor),
+        };
+        let updated = store.replace(ada.id, update.clone()).unwrap().unwrap();
+        assert_eq!(updated.name, "Ada Lovelace", "{}", spec);
+        assert_eq!(updated.age, None, "{}", spec);
+        assert_eq!(updated.role, Role::Editor, "{}", spec);
+        assert_eq!(updated.created_at, ada.created_at, "{}", spec);
+        assert_eq!(store.replace(99, update).unwrap(), None, "{}", spec);
+
+        assert!(store.set_password_hash(ada.id, "hash".to_string()).unwrap());
+        assert!(!store.set_password_hash(99, "hash".to_string()).unwrap());
+        let (account, hash) = store.find_account("ADA@example.com").unwrap().unwrap();
+        assert_eq!((account.id, hash.as_str()), (ada.id, "hash"), "{}", spec);
+        assert!(store.find_account("grace@example.com").unwrap().is_none());
+
+        assert!(store.delete(grace.id).unwrap(), "{}", spec);
+        assert!(!store.delete(grace.id).unwrap(), "{}", spec);
+        assert_eq!(store.list().unwrap(), vec![updated], "{}", spec);
+
+        // Ids of deleted users are not handed out again.
+        let alan = store.create(info("Alan", None, None)).unwrap();
+        assert_eq!(alan.id, 3, "{}", spec);
+    }
+}
+
+#[test]
+fn search_returns_the_pages_of_the_reference_implementation() {
+    let scratch = Scratch::new();
+    for spec in scratch.backends() {
+        let store = storage::open(&spec).unwrap();
+        seed(&*store);
+        let all = store.list().unwrap();
+
+        for sort in [
+            "id",
+            "-id",
+            "name",
+            "-age",
+            "age,-name",
+            "email",
+            "-email,+age",
+        ] {
+            for limit in [1, 3, 20] {
+                for filter in filters() {
+                    let mut query = UserQuery {
+                        filter,
+                        sort: sort.parse().unwrap(),
+                        after: None,
+                        offset: 0,
+                        limit,
+                    };
+                    let context = format!("{} sort={} limit={}", spec, sort, limit);
+
+                    // Walk every page by cursor, as a client would.
+                    let mut seen = Vec::new();
+                    loop {
+                        let expected = query.apply(all.clone());
+                        let page = store.search(&query).unwrap();
+                        assert_eq!(names(&page.items), names(&expected.items), "{}", context);
+                        assert_eq!(page.items, expected.items, "{}", context);
+                        assert_eq!(page.total, expected.total, "{}", context);
+                        assert_eq!(page.next, expected.next, "{}", context);
+                        seen.extend(page.items);
+                        let Some(next) = page.next else { break };
+                        // Round-trip the cursor as clients see it.
+                        let token = next.encode(&query.sort);
+                        query.after = Some(Cursor::decode(&token, &query.sort).unwrap());
+                    }
+                    assert_eq!(seen.len() as u64, query.apply(all.clone()).total);
+
+                    // An offset counts from the cursor.
+                    query.after = None;
+                    let first = query.apply(all.clone());
+                    if let Some(next) = first.next {
+                        query.after = Some(next);
+                        query.offset = 1;
+                        let expected = query.apply(all.clone());
+                        let page = store.search(&query).unwrap();
+                        assert_eq!(page.items, expected.items, "{} offset", context);
+                        assert_eq!(page.next, expected.next, "{} offset", context);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn persistent_backends_keep_their_data_across_reopens() {
+    let scratch = Scratch::new();
+    for spec in &scratch.backends()[1..] {
+        let (ada, ken, key) = {
+            let store = storage::open(spec).unwrap();
+            let ada = store
+                .create(info("Ada", Some(36), Some("ada@example.com")))
+                .unwrap();
+            let grace = store.create(info("Grace", None, None)).unwrap();
+            let alan = store.create(info("Alan", None, None)).unwrap();
+            store.delete(grace.id).unwrap();
+            // The highest id is gone too; it must not be reused either.
+            store.delete(alan.id).unwrap();
+            store.set_password_hash(ada.id, "hash".to_string()).unwrap();
+            let update = UserUpdate {
+                id: None,
+                name: "Ada Lovelace".to_string(),
+                age: Some(37),
+                email: ada.email.clone(),
+                role: None,
+            };
+            let ada = store.replace(ada.id, update).unwrap().unwrap();
+
+            let key = ApiKey {
+                id: "k1".to_string(),
+                name: "ci".to_string(),
+                scopes: vec!["users:read".to_string()],
+                role: Role::Viewer,
+                created_at: Utc::now(),
+                expires_at: None,
+                last_used_at: None,
+            };
+            store
+                .create_api_key(key.clone(), "secret".to_string())
+                .unwrap();
+            store
+                .create_api_key(
+                    ApiKey {
+                        id: "k2".to_string(),
+                        ..key.clone()
+                    },
+                    "secret".to_string(),
+                )
+                .unwrap();
+            store.delete_api_key("k2").unwrap();
+            let ken = store
+                .create_account(
+                    info("Ken", None, Some("ken@example.com")),
+                    "ken".to_string(),
+                )
+                .unwrap();
+            store.flush().unwrap();
+            (ada, ken, key)
+        };
+
+        let store = storage::open(spec).unwrap();
+        assert_eq!(store.pending_migrations().unwrap(), 0, "{}", spec);
+        assert_eq!(
+            store.list().unwrap(),
+            vec![ada.clone(), ken.clone()],
+            "{}",
+            spec
+        );
+        let (account, hash) = store.find_account("ken@example.com").unwrap().unwrap();
+        assert_eq!((account, hash.as_str()), (ken, "ken"), "{}", spec);
+        let (account, hash) = store.find_account("ada@example.com").unwrap().unwrap();
+        assert_eq!((account, hash.as_str()), (ada, "hash"), "{}", spec);
+        assert_eq!(
+            store.get_api_key("k1").unwrap(),
+            Some((key, "secret".to_string())),
+            "{}",
+            spec
+        );
+        assert_eq!(store.get_api_key("k2").unwrap(), None, "{}", spec);
+
+        let next = store.create(info("Barbara", None, None)).unwrap();
+        assert_eq!(next.id, 5, "{}", spec);
+    }
+}
+
+#[test]
+fn sqlite_upgrades_a_version_1_database() {
+    let scratch = Scratch::new();
+    let path = scratch.dir.join("v1.db");
+    {
+        // The schema as the first migration left it, ages as free text.
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE users (
+                id   INTEGER PRIMARY KEY AUTOINCREMENT,
+                name TEXT NOT NULL,
+                age  TEXT NOT NULL DEFAULT ''
+            );
+            INSERT INTO users (name, age) VALUES ('Ada', '36'), ('Grace', 'unknown'), ('Alan', '');
+            DELETE FROM users WHERE name = 'Alan';
+            PRAGMA user_version = 1;",
+        )
+        .unwrap();
+    }
+
+    let store = storage::connect(&format!("sqlite:{}", path.display())).unwrap();
+    let pending = store.pending_migrations().unwrap();
+    assert!(pending > 0);
+    assert_eq!(store.migrate().unwrap(), pending);
+    assert_eq!(store.pending_migrations().unwrap(), 0);
+    assert_eq!(store.migrate().unwrap(), 0);
+
+    let users = store.list().unwrap();
+    let summary: Vec<_> = users
+        .iter()
+        .map(|user| (user.id, user.name.as_str(), user.age, user.role))
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            (1, "Ada", Some(36), Role::Viewer),
+            (2, "Grace", None, Role::Viewer)
+        ]
+    );
+    assert!(users.iter().all(|user| user.email.is_none()));
+
+    // The upgraded schema takes the newer columns, and keeps the ids of
+    // deleted rows reserved.
+    let created = store
+        .create(info("Barbara", Some(50), Some("barbara@example.com")))
+        .unwrap();
+    assert_eq!(created.id, 4);
+    assert!(store
+        .set_password_hash(created.id, "hash".to_string())
+        .unwrap());
+    assert!(store.find_account("barbara@example.com").unwrap().is_some());
+}
+
+#[actix_web::test]
+async fn the_users_routes_work_on_every_backend() {
+    let scratch = Scratch::new();
+    for spec in scratch.backends() {
+        let mut config = test_config();
+        config.storage = spec.clone();
+        let app = TestApp::with_config(config).await;
+
+        for name in ["Ada", "Grace", "Alan"] {
+            app.call(app.post("/users").admin().json(json!({ "name": name })))
+                .await
+                .assert_status(StatusCode::CREATED);
+        }
+        let res = app.call(app.get("/users?sort=-name&limit=2").admin()).await;
+        res.assert_status(StatusCode::OK);
+        let page = res.json();
+        assert_eq!(page["total"], 3, "{}", spec);
+        assert_eq!(page["items"][0]["name"], "Grace", "{}", spec);
+        assert_eq!(page["items"][1]["name"], "Alan", "{}", spec);
+    }
+}
+
+/// Whether `result` failed because Ada's address already has an account.
+fn duplicate<T>(result: Result<T, StorageError>) -> bool {
+    matches!(
+        result,
+        Err(StorageError::DuplicateEmail(email)) if email.eq_ignore_ascii_case("ada@example.com")
+    )
+}
+
+#[test]
+fn account_emails_are_unique_on_every_backend() {
+    let scratch = Scratch::new();
+    for spec in scratch.backends() {
+        let store = storage::open(&spec).unwrap();
+        let ada = store
+            .create_account(info("Ada", None, Some("ada@example.com")), "h1".to_string())
+            .unwrap();
+        assert!(
+            duplicate(store.create_account(
+                info("Ada again", None, Some("ADA@example.com")),
+                "h2".to_string()
+            )),
+            "{}",
+            spec
+        );
+
+        // Plain users may share the address, but can't become accounts.
+        let plain = store
+            .create(info("Ada's twin", None, Some("ada@example.com")))
+            .unwrap();
+        assert!(
+            duplicate(store.set_password_hash(plain.id, "h3".to_string())),
+            "{}",
+            spec
+        );
+
+        // Nor can another account move to it.
+        let grace = store
+            .create_account(
+                info("Grace", None, Some("grace@example.com")),
+                "h4".to_string(),
+            )
+            .unwrap();
+        let update = UserUpdate {
+            id: None,
+            name: "Grace".to_string(),
+            age: None,
+            email: Some("Ada@Example.com".to_string()),
+            role: None,
+        };
+        assert!(duplicate(store.replace(grace.id, update)), "{}", spec);
+        let mut moved = grace.clone();
+        moved.email = Some("ada@example.com".to_string());
+        assert!(duplicate(store.import(moved)), "{}", spec);
+
+        let (account, hash) = store.find_account("ada@example.com").unwrap().unwrap();
+        assert_eq!((account.id, hash.as_str()), (ada.id, "h1"), "{}", spec);
+        assert_eq!(store.get(grace.id).unwrap(), Some(grace), "{}", spec);
+        assert_eq!(store.list().unwrap().len(), 3, "{}", spec);
+    }
+}
+
+#[test]
+fn concurrent_account_creation_lets_one_through() {
+    let scratch = Scratch::new();
+    for spec in scratch.backends() {
+        let store = storage::open(&spec).unwrap();
+        let results: Vec<_> = thread::scope(|scope| {
+            let attempts: Vec<_> = (0..8)
+                .map(|i| {
+                    let store = &store;
+                    let email = if i % 2 == 0 {
+                        "ada@example.com"
+                    } else {
+                        "ADA@example.com"
+                    };
+                    scope.spawn(move || {
+                        store.create_account(info("Ada", None, Some(email)), format!("h{}", i))
+                    })
+                })
+                .collect();
+            attempts
+                .into_iter()
+                .map(|attempt| attempt.join().unwrap())
+                .collect()
+        });
+        let created = results.iter().filter(|result| result.is_ok()).count();
+        assert!(
+            results
+                .iter()
+                .all(|result| matches!(result, Ok(_) | Err(StorageError::DuplicateEmail(_)))),
+            "{}",
+            spec
+        );
+        assert_eq!(created, 1, "{}", spec);
+    }
+}
+
+#[test]
+fn api_key_uses_survive_a_clean_reopen_without_growing_the_json_log() {
+    let scratch = Scratch::new();
+    for spec in &scratch.backends()[1..] {
+        let used_at = Utc::now();
+        {
+            let store = storage::open(spec).unwrap();
+            let key = ApiKey {
+                id: "k1".to_string(),
+                name: "ci".to_string(),
+                scopes: vec!["users:read".to_string()],
+                role: Role::Viewer,
+                created_at: Utc::now(),
+                expires_at: None,
+                last_used_at: None,
+            };
+            store.create_api_key(key, "secret".to_string()).unwrap();
+
+            // Only the JSON-lines backend has a log that could grow.
+            let log = spec.strip_prefix("json:");
+            let size = log.map(|log| fs::metadata(log).unwrap().len());
+            for minutes in (0..100).rev() {
+                let at = used_at - TimeDelta::minutes(minutes);
+                store.touch_api_key("k1", at).unwrap();
+            }
+            let (key, _) = store.get_api_key("k1").unwrap().unwrap();
+            assert_eq!(key.last_used_at, Some(used_at), "{}", spec);
+            assert_eq!(log.map(|log| fs::metadata(log).unwrap().len()), size);
+            store.flush().unwrap();
+        }
+
+        let store = storage::open(spec).unwrap();
+        let (key, _) = store.get_api_key("k1").unwrap().unwrap();
+        assert_eq!(key.last_used_at, Some(used_at), "{}", spec);
+    }
+}
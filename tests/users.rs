@@ -0,0 +1,403 @@
+//! The `/users` routes.
+
+mod common;
+
+use actix_web::http::{header, StatusCode};
+use serde_json::json;
+
+use actix_web_app::auth::USERS_READ;
+use actix_web_app::models::{Info, Role};
+use common::{token, TestApp};
+
+#[actix_web::test]
+async fn list_returns_a_page_of_users() {
+    let app = TestApp::spawn().await;
+    app.create_user("Ada", Some(36)).await;
+    app.create_user("Grace", Some(45)).await;
+
+    let res = app.call(app.get("/users").admin()).await;
+    res.assert_status(StatusCode::OK);
+    let body = res.assert_json_includes(json!({ "total": 2 }));
+    let names: Vec<_> = body["items"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|user| user["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, ["Ada", "Grace"]);
+    assert!(body.get("next").is_none_or(|next| next.is_null()));
+    assert_eq!(res.header(header::LINK), Some("</users>; rel=\"first\""));
+}
+
+#[actix_web::test]
+async fn list_filters_sorts_and_paginates() {
+    let app = TestApp::spawn().await;
+    for (name, age) in [("Ada", 36), ("Bob", 17), ("Cy", 52), ("Di", 29)] {
+        app.create_user(name, Some(age)).await;
+    }
+
+    let res = app
+        .call(app.get("/users?min_age=18&sort=-age&limit=2").admin())
+        .await;
+    res.assert_status(StatusCode::OK);
+    let body = res.assert_json_includes(json!({
+        "total": 3,
+        "items": [{ "name": "Cy" }, { "name": "Ada" }],
+    }));
+    let next = body["next"].as_str().unwrap().to_string();
+    assert!(next.starts_with("/users?"), "next: {}", next);
+    let link = res.header(header::LINK).unwrap();
+    assert!(link.contains("rel=\"next\""), "Link: {}", link);
+
+    let res = app.call(app.get(&next).admin()).await;
+    res.assert_status(StatusCode::OK);
+    let body = res.assert_json_includes(json!({ "total": 3, "items": [{ "name": "Di" }] }));
+    assert!(body["next"].is_null());
+}
+
+#[actix_web::test]
+async fn list_rejects_invalid_query_strings() {
+    let app = TestApp::spawn().await;
+    for uri in [
+        "/users?limit=0",
+        "/users?limit=abc",
+        "/users?sort=height",
+        "/users?after=nonsense",
+        "/users?after=x&offset=1",
+    ] {
+        let res = app.call(app.get(uri).admin()).await;
+        res.assert_problem(StatusCode::BAD_REQUEST, "/problems/invalid-query");
+    }
+}
+
+#[actix_web::test]
+async fn list_requires_credentials_scope_and_role() {
+    let app = TestApp::spawn().await;
+
+    let res = app.call(app.get("/users")).await;
+    res.assert_problem(StatusCode::UNAUTHORIZED, "/problems/unauthorized");
+    assert_eq!(res.header(header::WWW_AUTHENTICATE), Some("Bearer, ApiKey"));
+
+    let res = app.call(app.get("/users").bearer("not-a-jwt")).await;
+    res.assert_problem(StatusCode::UNAUTHORIZED, "/problems/unauthorized");
+    assert_eq!(
+        res.header(header::WWW_AUTHENTICATE),
+        Some(r#"Bearer error="invalid_token""#)
+    );
+
+    let write_only = token("admin", Role::Admin, &["users:write"]);
+    let res = app.call(app.get("/users").bearer(&write_only)).await;
+    res.assert_problem(StatusCode::FORBIDDEN, "/problems/forbidden");
+
+    let res = app.call(app.get("/users").user(1, Role::Viewer)).await;
+    res.assert_problem(StatusCode::FORBIDDEN, "/problems/forbidden");
+}
+
+#[actix_web::test]
+async fn create_stores_the_user() {
+    let app = TestApp::spawn().await;
+
+    let res = app
+        .call(app.post("/users").admin().json(json!({
+            "name": "Ada",
+            "age": 36,
+            "email": "ada@example.com",
+        })))
+        .await;
+    res.assert_status(StatusCode::CREATED);
+    let user = res.assert_json_includes(json!({
+        "id": 1,
+        "name": "Ada",
+        "age": 36,
+        "email": "ada@example.com",
+        "role": "viewer",
+    }));
+    assert_eq!(user["created_at"], user["updated_at"]);
+    assert_eq!(res.header(header::LOCATION), Some("/users/1"));
+
+    let stored = app.state.store.get(1).unwrap().unwrap();
+    assert_eq!(stored.name, "Ada");
+}
+
+#[actix_web::test]
+async fn create_rejects_malformed_bodies() {
+    let app = TestApp::spawn().await;
+
+    let res = app
+        .call(
+            app.post("/users")
+                .admin()
+                .raw("application/json", "{\"name\": "),
+        )
+        .await;
+    res.assert_problem(StatusCode::BAD_REQUEST, "/problems/invalid-body");
+
+    let res = app
+        .call(app.post("/users").admin().json(json!({ "age": 3 })))
+        .await;
+    res.assert_problem(StatusCode::BAD_REQUEST, "/problems/invalid-body");
+
+    let res = app
+        .call(app.post("/users").admin().raw("text/plain", "name=Ada"))
+        .await;
+    res.assert_problem(
+        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        "/problems/unsupported-media-type",
+    );
+}
+
+#[actix_web::test]
+async fn create_reports_each_invalid_field() {
+    let app = TestApp::spawn().await;
+
+    let res = app
+        .call(app.post("/users").admin().json(json!({
+            "name": "",
+            "age": 200,
+            "email": "not-an-email",
+        })))
+        .await;
+    let problem = res.assert_problem(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "/problems/validation-error",
+    );
+    for field in ["name", "age", "email"] {
+        assert!(problem["errors"][field].is_array(), "problem: {}", problem);
+    }
+    assert!(app.state.store.list().unwrap().is_empty());
+}
+
+#[actix_web::test]
+async fn create_checks_the_policy() {
+    let app = TestApp::spawn().await;
+
+    let res = app
+        .call(
+            app.post("/users")
+                .user(1, Role::Viewer)
+                .json(json!({ "name": "Ada" })),
+        )
+        .await;
+    res.assert_problem(StatusCode::FORBIDDEN, "/problems/forbidden");
+
+    let res = app
+        .call(
+            app.post("/users")
+                .user(1, Role::Editor)
+                .json(json!({ "name": "Ada", "role": "admin" })),
+        )
+        .await;
+    res.assert_problem(StatusCode::FORBIDDEN, "/problems/forbidden");
+
+    let res = app
+        .call(
+            app.post("/users")
+                .user(1, Role::Editor)
+                .json(json!({ "name": "Ada" })),
+        )
+        .await;
+    res.assert_status(StatusCode::CREATED);
+}
+
+#[actix_web::test]
+async fn get_returns_one_user() {
+    let app = TestApp::spawn().await;
+    let user = app.create_user("Ada", Some(36)).await;
+
+    let res = app
+        .call(app.get(&format!("/users/{}", user.id)).admin())
+        .await;
+    res.assert_status(StatusCode::OK);
+    res.assert_json_includes(json!({ "id": user.id, "name": "Ada", "age": 36 }));
+}
+
+#[actix_web::test]
+async fn get_reports_missing_users_and_invalid_ids() {
+    let app = TestApp::spawn().await;
+
+    let res = app.call(app.get("/users/42").admin()).await;
+    let problem = res.assert_problem(StatusCode::NOT_FOUND, "/problems/not-found");
+    assert_eq!(problem["detail"], "User 42 not found");
+
+    for id in ["abc", "-1", "4294967296"] {
+        let res = app.call(app.get(&format!("/users/{}", id)).admin()).await;
+        res.assert_problem(StatusCode::BAD_REQUEST, "/problems/invalid-path");
+    }
+}
+
+#[actix_web::test]
+async fn viewers_may_only_read_themselves() {
+    let app = TestApp::spawn().await;
+    let own = app.create_user("Ada", None).await;
+    let other = app.create_user("Grace", None).await;
+
+    let res = app
+        .call(
+            app.get(&format!("/users/{}", own.id))
+                .user(own.id, Role::Viewer),
+        )
+        .await;
+    res.assert_status(StatusCode::OK);
+
+    let res = app
+        .call(
+            app.get(&format!("/users/{}", other.id))
+                .user(own.id, Role::Viewer),
+        )
+        .await;
+    res.assert_problem(StatusCode::FORBIDDEN, "/problems/forbidden");
+}
+
+#[actix_web::test]
+async fn put_replaces_the_user() {
+    let app = TestApp::spawn().await;
+    let user = app
+        .create_user_with(Info {
+            name: "Ada".to_string(),
+            age: Some(36),
+            email: Some("ada@example.com".to_string()),
+            role: None,
+        })
+        .await;
+    let uri = format!("/users/{}", user.id);
+
+    let res = app
+        .call(
+            app.put(&uri)
+                .admin()
+                .json(json!({ "id": user.id, "name": "Ada L." })),
+        )
+        .await;
+    res.assert_status(StatusCode::OK);
+    res.assert_json_includes(json!({ "name": "Ada L.", "age": null, "email": null }));
+}
+
+#[actix_web::test]
+async fn put_reports_errors() {
+    let app = TestApp::spawn().await;
+    let user = app.create_user("Ada", None).await;
+    let uri = format!("/users/{}", user.id);
+
+    let res = app
+        .call(
+            app.put(&uri)
+                .admin()
+                .json(json!({ "id": 99, "name": "Ada" })),
+        )
+        .await;
+    res.assert_problem(StatusCode::CONFLICT, "/problems/conflict");
+
+    let res = app
+        .call(app.put(&uri).admin().json(json!({ "name": "" })))
+        .await;
+    res.assert_problem(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "/problems/validation-error",
+    );
+
+    let res = app
+        .call(app.put("/users/99").admin().json(json!({ "name": "Ada" })))
+        .await;
+    res.assert_problem(StatusCode::NOT_FOUND, "/problems/not-found");
+
+    let res = app
+        .call(
+            app.put(&uri)
+                .user(7, Role::Viewer)
+                .json(json!({ "name": "Ada" })),
+        )
+        .await;
+    res.assert_problem(StatusCode::FORBIDDEN, "/problems/forbidden");
+}
+
+#[actix_web::test]
+async fn patch_merges_into_the_user() {
+    let app = TestApp::spawn().await;
+    let user = app
+        .create_user_with(Info {
+            name: "Ada".to_string(),
+            age: Some(36),
+            email: Some("ada@example.com".to_string()),
+            role: None,
+        })
+        .await;
+    let uri = format!("/users/{}", user.id);
+
+    let res = app
+        .call(
+            app.patch(&uri)
+                .admin()
+                .json(json!({ "age": 37, "email": null })),
+        )
+        .await;
+    res.assert_status(StatusCode::OK);
+    res.assert_json_includes(json!({ "name": "Ada", "age": 37, "email": null }));
+}
+
+#[actix_web::test]
+async fn patch_reports_errors() {
+    let app = TestApp::spawn().await;
+    let user = app.create_user("Ada", None).await;
+    let uri = format!("/users/{}", user.id);
+
+    let res = app
+        .call(app.patch(&uri).admin().json(json!({ "name": null })))
+        .await;
+    res.assert_problem(StatusCode::BAD_REQUEST, "/problems/invalid-body");
+
+    let res = app
+        .call(app.patch(&uri).admin().json(json!({ "age": 151 })))
+        .await;
+    res.assert_problem(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "/problems/validation-error",
+    );
+
+    let res = app
+        .call(app.patch(&uri).admin().json(json!({ "role": "admin" })))
+        .await;
+    res.assert_status(StatusCode::OK);
+    let res = app
+        .call(
+            app.patch(&uri)
+                .user(2, Role::Editor)
+                .json(json!({ "role": "viewer" })),
+        )
+        .await;
+    res.assert_problem(StatusCode::FORBIDDEN, "/problems/forbidden");
+
+    let res = app
+        .call(app.patch("/users/99").admin().json(json!({ "age": 1 })))
+        .await;
+    res.assert_problem(StatusCode::NOT_FOUND, "/problems/not-found");
+}
+
+#[actix_web::test]
+async fn delete_removes_the_user() {
+    let app = TestApp::spawn().await;
+    let user = app.create_user("Ada", None).await;
+    let uri = format!("/users/{}", user.id);
+
+    let res = app.call(app.delete(&uri).admin()).await;
+    res.assert_status(StatusCode::NO_CONTENT);
+    assert!(res.body.is_empty());
+    assert!(app.state.store.get(user.id).unwrap().is_none());
+
+    let res = app.call(app.delete(&uri).admin()).await;
+    res.assert_problem(StatusCode::NOT_FOUND, "/problems/not-found");
+}
+
+#[actix_web::test]
+async fn delete_needs_the_write_scope() {
+    let app = TestApp::spawn().await;
+    let user = app.create_user("Ada", None).await;
+
+    let read_only = token("admin", Role::Admin, &[USERS_READ]);
+    let res = app
+        .call(
+            app.delete(&format!("/users/{}", user.id))
+                .bearer(&read_only),
+        )
+        .await;
+    res.assert_problem(StatusCode::FORBIDDEN, "/problems/forbidden");
+}